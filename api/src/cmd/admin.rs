@@ -2,14 +2,20 @@ use chrono::{DateTime, Utc};
 use clap::{Args, Subcommand};
 
 use pic_store_api::auth::API_KEY_PREFIX;
-use pic_store_db::object_id;
+use pic_store_db::{object_id, secrets::MasterKeys};
 use uuid::Uuid;
 
-use self::make_api_key::MakeApiKeyArgs;
+use self::{
+    collect_orphans::CollectOrphansArgs, make_api_key::MakeApiKeyArgs,
+    migrate_storage::MigrateStorageArgs, rotate_storage_keys::RotateStorageKeysArgs,
+};
 
 #[cfg(feature = "bootstrap")]
 mod bootstrap;
+mod collect_orphans;
 mod make_api_key;
+mod migrate_storage;
+mod rotate_storage_keys;
 
 #[derive(Debug, Args)]
 pub struct AdminArgs {
@@ -33,6 +39,17 @@ pub enum Commands {
     HashPassword(HashPassword),
     /// Add an API key for a particular user and insert it into the database.
     AddApiKey(MakeApiKeyArgs),
+    /// Find objects in a storage location that no image refers to, and optionally delete them.
+    CollectOrphans(CollectOrphansArgs),
+    /// Move images from one storage location to another.
+    MigrateStorage(MigrateStorageArgs),
+    /// Generate a random key for encrypting storage credentials.
+    MakeStorageKey,
+    /// Encrypt all storage credentials with the current storage key.
+    ///
+    /// To change keys, add the old key to the previous storage keys, set the new one as the
+    /// storage key, and run this command.
+    RotateStorageKeys(RotateStorageKeysArgs),
 }
 
 #[derive(Debug, Args)]
@@ -61,13 +78,17 @@ pub struct HashPassword {
     password: String,
 }
 
-pub fn admin_commands(cmd: AdminArgs) -> Result<(), eyre::Report> {
+pub async fn admin_commands(cmd: AdminArgs) -> Result<(), eyre::Report> {
     match cmd.commands {
         #[cfg(feature = "bootstrap")]
         Commands::Bootstrap(args) => bootstrap::bootstrap(args)?,
         Commands::MakeId(MakeId { command }) => make_id(command),
         Commands::AddApiKey(args) => make_api_key::main(args)?,
         Commands::HashPassword(HashPassword { password }) => hash_password(password)?,
+        Commands::CollectOrphans(args) => collect_orphans::main(args).await?,
+        Commands::MigrateStorage(args) => migrate_storage::main(args).await?,
+        Commands::MakeStorageKey => println!("{}", MasterKeys::generate_key()),
+        Commands::RotateStorageKeys(args) => rotate_storage_keys::main(args)?,
     }
 
     Ok(())
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use clap::Args;
+use eyre::Result;
+use pic_store_api::{
+    config::{QueueConfig, StorageKeyConfig},
+    jobs::{self, CollectOrphansJobPayload},
+};
+use pic_store_db::object_id::StorageLocationId;
+
+#[derive(Debug, Args)]
+pub struct CollectOrphansArgs {
+    #[clap(long, help = "The storage location to check")]
+    storage_location: StorageLocationId,
+    #[clap(short, long, help = "Database connection string", env = "DATABASE_URL")]
+    database: String,
+    #[clap(
+        long,
+        help = "Delete the orphaned objects instead of only listing them"
+    )]
+    delete: bool,
+    #[clap(
+        long,
+        help = "Ignore objects modified more recently than this many hours ago",
+        default_value_t = 24
+    )]
+    min_age_hours: u64,
+    #[clap(
+        long,
+        help = "Add a background job to do the work instead of running it now"
+    )]
+    background: bool,
+    #[clap(flatten)]
+    queue: QueueConfig,
+    #[clap(flatten)]
+    storage_keys: StorageKeyConfig,
+}
+
+pub async fn main(args: CollectOrphansArgs) -> Result<()> {
+    let pool = pic_store_db::connect(args.database.as_str(), 2)?;
+    let min_age = Duration::from_secs(args.min_age_hours * 60 * 60);
+
+    if args.background {
+        let queue = jobs::JobQueue::new(&args.queue, &pool).await?;
+        let job_id = queue
+            .add_json(
+                jobs::COLLECT_ORPHANS,
+                &CollectOrphansJobPayload {
+                    storage_location_id: args.storage_location,
+                    dry_run: !args.delete,
+                    min_age_secs: min_age.as_secs(),
+                },
+            )
+            .await?;
+        queue.close(Duration::from_secs(10)).await?;
+
+        println!("Added job {job_id}");
+        return Ok(());
+    }
+
+    let keys = args.storage_keys.master_keys()?;
+    let report =
+        jobs::collect_orphans(&pool, &keys, args.storage_location, min_age, !args.delete).await?;
+
+    for orphan in &report.orphans {
+        println!(
+            "{}\t{}\t{}",
+            orphan.location, orphan.size, orphan.last_modified
+        );
+    }
+
+    if report.dry_run {
+        println!(
+            "Found {} orphaned objects out of {}. Run again with --delete to remove them.",
+            report.orphans.len(),
+            report.scanned
+        );
+    } else {
+        println!(
+            "Deleted {} of {} orphaned objects out of {}",
+            report.deleted,
+            report.orphans.len(),
+            report.scanned
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use clap::{Args, Subcommand};
+use eyre::{eyre, Result};
+use pic_store_api::{
+    config::QueueConfig,
+    jobs::{self, JobOptions, MigrateStorageJobPayload},
+};
+use pic_store_db::object_id::StorageLocationId;
+use uuid::Uuid;
+
+#[derive(Debug, Args)]
+pub struct MigrateStorageArgs {
+    #[clap(subcommand)]
+    command: MigrateStorageCommand,
+    #[clap(short, long, help = "Database connection string", env = "DATABASE_URL")]
+    database: String,
+    #[clap(flatten)]
+    queue: QueueConfig,
+}
+
+#[derive(Debug, Subcommand)]
+enum MigrateStorageCommand {
+    /// Add a job to copy all images from one storage location to another, and then switch the
+    /// upload profiles to the new location.
+    Start {
+        #[clap(long, help = "The storage location to copy from")]
+        from: StorageLocationId,
+        #[clap(long, help = "The storage location to copy to")]
+        to: StorageLocationId,
+    },
+    /// Show the progress of a migration job.
+    Status {
+        #[clap(help = "The ID of the migration job")]
+        job_id: Uuid,
+    },
+}
+
+pub async fn main(args: MigrateStorageArgs) -> Result<()> {
+    let pool = pic_store_db::connect(args.database.as_str(), 2)?;
+    let queue = jobs::JobQueue::new(&args.queue, &pool).await?;
+
+    let result = run_command(&queue, args.command).await;
+    queue.close(Duration::from_secs(10)).await?;
+    result
+}
+
+async fn run_command(queue: &jobs::JobQueue, command: MigrateStorageCommand) -> Result<()> {
+    match command {
+        MigrateStorageCommand::Start { from, to } => {
+            // Each checkpoint restarts the timeout, but a batch of large images can still take a
+            // while to copy.
+            let options = JobOptions {
+                max_retries: 10,
+                timeout: Duration::from_secs(30 * 60),
+                ..Default::default()
+            };
+
+            let job_id = queue
+                .add_json_with_options(
+                    jobs::MIGRATE_STORAGE,
+                    &MigrateStorageJobPayload {
+                        from,
+                        to,
+                        progress: Default::default(),
+                    },
+                    &options,
+                )
+                .await?;
+
+            println!("Added migration job {job_id}");
+        }
+        MigrateStorageCommand::Status { job_id } => {
+            let job = queue
+                .job_status(job_id)
+                .await?
+                .ok_or_else(|| eyre!("Job {job_id} not found"))?;
+            if job.job_type != jobs::MIGRATE_STORAGE {
+                return Err(eyre!("Job {job_id} is not a storage migration"));
+            }
+
+            let payload = serde_json::from_value::<MigrateStorageJobPayload>(job.payload)?;
+            let progress = payload.progress;
+            println!("Job state: {:?}", job.state);
+            println!("Phase: {:?}", progress.phase);
+            match progress.total {
+                Some(total) => println!("Copied: {} of {total}", progress.copied),
+                None => println!("Copied: {}", progress.copied),
+            }
+            println!("Copied bytes: {}", progress.copied_bytes);
+            println!("Missing: {}", progress.missing);
+            if let Some(error) = job.last_error {
+                println!("Last error: {error}");
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use clap::Args;
+use diesel::{prelude::*, Connection, PgConnection};
+use eyre::{eyre, Result};
+use pic_store_api::config::StorageKeyConfig;
+use pic_store_db::{
+    object_id::StorageLocationId,
+    storage_locations::{self, Provider},
+};
+
+#[derive(Debug, Args)]
+pub struct RotateStorageKeysArgs {
+    #[clap(short, long, help = "Database connection string", env = "DATABASE_URL")]
+    database: String,
+    #[clap(flatten)]
+    storage_keys: StorageKeyConfig,
+}
+
+/// Re-encrypt every storage location's credentials with the current storage key. Credentials
+/// saved before encryption was added are encrypted for the first time.
+pub fn main(args: RotateStorageKeysArgs) -> Result<()> {
+    if args.storage_keys.storage_key.is_none() {
+        return Err(eyre!("Set a storage key to encrypt the credentials with"));
+    }
+
+    let keys = args.storage_keys.master_keys()?;
+    let mut conn = PgConnection::establish(args.database.as_str())?;
+
+    let (total, updated) = conn.transaction(|conn| {
+        let locations = storage_locations::table
+            .select((storage_locations::id, storage_locations::provider))
+            .for_update()
+            .load::<(StorageLocationId, Provider)>(conn)?;
+
+        let total = locations.len();
+        let mut updated = 0;
+        for (id, mut provider) in locations {
+            if !provider.encrypt_secrets(&keys)? {
+                continue;
+            }
+
+            diesel::update(storage_locations::table)
+                .filter(storage_locations::id.eq(id))
+                .set(storage_locations::provider.eq(provider))
+                .execute(conn)?;
+            updated += 1;
+        }
+
+        Ok::<_, eyre::Report>((total, updated))
+    })?;
+
+    println!(
+        "Encrypted credentials for {updated} of {total} storage locations with key {}",
+        keys.current_key_id().unwrap_or_default()
+    );
+    println!("Once the servers and workers use the new key, the previous keys can be removed.");
+
+    Ok(())
+}
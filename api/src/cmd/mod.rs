@@ -1,2 +1,22 @@
+use pic_store_api::tracing_config::{HoneycombConfig, TracingExportConfig};
+
 pub mod admin;
 pub mod server;
+pub mod worker;
+
+fn tracing_export_config(
+    honeycomb_team: Option<String>,
+    honeycomb_dataset: String,
+    jaeger_endpoint: Option<String>,
+) -> TracingExportConfig {
+    if let Some(team) = honeycomb_team {
+        TracingExportConfig::Honeycomb(HoneycombConfig {
+            team,
+            dataset: honeycomb_dataset,
+        })
+    } else if let Some(jaeger_endpoint) = jaeger_endpoint {
+        TracingExportConfig::Jaeger(jaeger_endpoint)
+    } else {
+        TracingExportConfig::None
+    }
+}
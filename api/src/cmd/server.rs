@@ -1,18 +1,13 @@
-use pic_store_api::tracing_config::{self, HoneycombConfig, TracingExportConfig};
+use pic_store_api::tracing_config;
 
 pub async fn run(
     mut config: pic_store_api::config::Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let tracing_export_config = if let Some(team) = config.honeycomb_team.take() {
-        TracingExportConfig::Honeycomb(HoneycombConfig {
-            team,
-            dataset: std::mem::take(&mut config.honeycomb_dataset),
-        })
-    } else if let Some(jaeger_endpoint) = config.jaeger_endpoint.take() {
-        TracingExportConfig::Jaeger(jaeger_endpoint)
-    } else {
-        TracingExportConfig::None
-    };
+    let tracing_export_config = super::tracing_export_config(
+        config.honeycomb_team.take(),
+        std::mem::take(&mut config.honeycomb_dataset),
+        config.jaeger_endpoint.take(),
+    );
 
     tracing_config::configure(tracing_export_config)?;
 
@@ -0,0 +1,53 @@
+use std::{sync::Arc, time::Duration};
+
+use pic_store_api::{config::WorkerCommandConfig, jobs, tracing_config};
+use tracing::{event, Level};
+
+pub async fn run(mut config: WorkerCommandConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let tracing_export_config = super::tracing_export_config(
+        config.honeycomb_team.take(),
+        std::mem::take(&mut config.honeycomb_dataset),
+        config.jaeger_endpoint.take(),
+    );
+
+    tracing_config::configure(tracing_export_config)?;
+
+    let result = run_worker(config).await;
+
+    tracing_config::teardown();
+
+    result?;
+    Ok(())
+}
+
+async fn run_worker(config: WorkerCommandConfig) -> Result<(), eyre::Report> {
+    // Each job holds at most one connection at a time.
+    let db = pic_store_db::connect(
+        config.database_url.as_str(),
+        config.worker.job_concurrency as usize + 1,
+    )?;
+
+    let queue = jobs::JobQueue::new(&config.queue, &db)
+        .await
+        .map_err(|e| eyre::eyre!("Failed to create job queue: {}", e))?;
+    let queue = Arc::new(queue);
+    let storage_keys = Arc::new(config.storage_keys.master_keys()?);
+    let worker = jobs::create_worker(
+        &queue,
+        db,
+        storage_keys,
+        &config.worker,
+        config.conversion_job.job_options(),
+    )
+    .await?;
+
+    tokio::signal::ctrl_c().await?;
+
+    event!(Level::INFO, "Shutting down background jobs");
+    if let Err(e) = worker.unregister(Some(Duration::from_secs(10))).await {
+        event!(Level::ERROR, "Failed to shut down queue worker: {}", e);
+    }
+
+    queue.close(Duration::from_secs(10)).await?;
+    Ok(())
+}
@@ -1,4 +1,10 @@
-use clap::Parser;
+use std::time::Duration;
+
+use clap::{Args, Parser, ValueEnum};
+use pic_store_db::secrets::{MasterKeys, SecretError};
+use tracing::{event, Level};
+
+use crate::jobs::JobOptions;
 
 #[derive(Debug, Parser)]
 pub struct Config {
@@ -7,8 +13,8 @@ pub struct Config {
     #[clap(long, env, default_value_t = 7205)]
     pub port: u16,
 
-    #[clap(long, env, default_value_t = String::from("queue.db"))]
-    pub queue_db_path: String,
+    #[clap(flatten)]
+    pub queue: QueueConfig,
 
     #[clap(env, default_value_t = String::from("production"))]
     pub env: String,
@@ -34,6 +40,9 @@ pub struct Config {
     #[clap(long, env, help = "The name of the session cookie", default_value_t = String::from("sid"))]
     pub session_cookie_name: String,
 
+    #[clap(flatten)]
+    pub storage_keys: StorageKeyConfig,
+
     #[clap(
         long,
         env,
@@ -41,4 +50,262 @@ pub struct Config {
         default_value_t = false
     )]
     pub allow_local_fs: bool,
+
+    #[clap(
+        long,
+        env,
+        help = "Allow in-memory storage locations in production. They are always allowed in development",
+        default_value_t = false
+    )]
+    pub allow_memory_storage: bool,
+
+    #[clap(
+        long,
+        env,
+        help = "Allow Azure storage locations to connect to the Azurite emulator in production. It is always allowed in development",
+        default_value_t = false
+    )]
+    pub allow_storage_emulators: bool,
+
+    #[clap(
+        long,
+        env,
+        help = "Allow storage location tests to fetch public URLs on private networks. They are always allowed in development",
+        default_value_t = false
+    )]
+    pub allow_private_urls: bool,
+
+    #[clap(
+        long,
+        env,
+        help = "Don't run background jobs in this process. Use the worker command to run them instead",
+        default_value_t = false
+    )]
+    pub no_worker: bool,
+
+    #[clap(flatten)]
+    pub worker: WorkerConfig,
+
+    #[clap(flatten)]
+    pub conversion_job: ConversionJobConfig,
+}
+
+/// The master keys that encrypt storage credentials in the database.
+#[derive(Args)]
+pub struct StorageKeyConfig {
+    #[clap(
+        long,
+        env,
+        help = "The key that encrypts storage credentials, 32 bytes encoded as base64. Without it, existing credentials can be read but storage locations with credentials can't be saved"
+    )]
+    pub storage_key: Option<String>,
+
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "Comma-separated older storage keys, which can still decrypt credentials until they are rotated to the current key"
+    )]
+    pub previous_storage_keys: Vec<String>,
+}
+
+impl StorageKeyConfig {
+    pub fn master_keys(&self) -> Result<MasterKeys, SecretError> {
+        match &self.storage_key {
+            Some(key) => MasterKeys::new(key, &self.previous_storage_keys),
+            None => {
+                event!(
+                    Level::WARN,
+                    "No storage key is set, so storage locations with credentials can't be saved. Set STORAGE_KEY and run `admin rotate-storage-keys` to encrypt the existing credentials."
+                );
+                MasterKeys::decrypt_only(&self.previous_storage_keys)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for StorageKeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageKeyConfig").finish_non_exhaustive()
+    }
+}
+
+/// Retry settings for the jobs that create output images.
+#[derive(Debug, Args)]
+pub struct ConversionJobConfig {
+    #[clap(
+        long,
+        env,
+        help = "How many times to retry a failed conversion job",
+        default_value_t = 3
+    )]
+    pub conversion_max_retries: u32,
+
+    #[clap(
+        long,
+        env,
+        help = "Seconds to wait before the first retry of a conversion job",
+        default_value_t = 20
+    )]
+    pub conversion_retry_backoff_secs: u64,
+
+    #[clap(
+        long,
+        env,
+        help = "How much to multiply the wait by for each following retry",
+        default_value_t = 2.0
+    )]
+    pub conversion_retry_backoff_multiplier: f32,
+
+    #[clap(
+        long,
+        env,
+        help = "Seconds a conversion job can run without progress before it is retried",
+        default_value_t = 300
+    )]
+    pub conversion_timeout_secs: u64,
+}
+
+impl ConversionJobConfig {
+    pub fn job_options(&self) -> JobOptions {
+        JobOptions {
+            max_retries: self.conversion_max_retries,
+            backoff_initial_interval: Duration::from_secs(self.conversion_retry_backoff_secs),
+            backoff_multiplier: self.conversion_retry_backoff_multiplier,
+            timeout: Duration::from_secs(self.conversion_timeout_secs),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum QueueBackend {
+    /// A SQLite file, which can only be used by a single process.
+    Sqlite,
+    /// The jobs table in the main database, which can be shared by many servers and workers.
+    Postgres,
+}
+
+/// Where background jobs are stored.
+#[derive(Debug, Args)]
+pub struct QueueConfig {
+    #[clap(
+        long,
+        env,
+        value_enum,
+        help = "Where to store the job queue",
+        default_value_t = QueueBackend::Sqlite
+    )]
+    pub queue_backend: QueueBackend,
+
+    #[clap(
+        long,
+        env,
+        help = "The path of the SQLite job queue",
+        default_value_t = String::from("queue.db")
+    )]
+    pub queue_db_path: String,
+}
+
+/// Settings for running background jobs, shared by the server and worker commands.
+#[derive(Debug, Args)]
+pub struct WorkerConfig {
+    #[clap(
+        long,
+        env,
+        help = "Maximum number of jobs to run at once",
+        default_value_t = 10
+    )]
+    pub job_concurrency: u16,
+
+    #[clap(
+        long,
+        env,
+        help = "Approximate memory limit for decoding a single image, in MiB",
+        default_value_t = 1024
+    )]
+    pub decode_memory_limit_mb: u64,
+
+    #[clap(
+        long,
+        env,
+        help = "Number of threads for encoding images. 0 uses one thread per CPU",
+        default_value_t = 0
+    )]
+    pub encode_threads: usize,
+
+    #[clap(
+        long,
+        env,
+        help = "Maximum number of output images to upload at once, across all jobs",
+        default_value_t = 16
+    )]
+    pub upload_concurrency: usize,
+
+    #[clap(
+        long,
+        env,
+        help = "Seconds between checks for stuck uploads and conversions. 0 disables the checks",
+        default_value_t = 300
+    )]
+    pub reaper_interval_secs: u64,
+
+    #[clap(
+        long,
+        env,
+        help = "Seconds after which an unfinished upload is considered abandoned",
+        default_value_t = 3600
+    )]
+    pub stale_upload_secs: u64,
+
+    #[clap(
+        long,
+        env,
+        help = "Seconds after which a conversion with no job working on it is considered stuck",
+        default_value_t = 1800
+    )]
+    pub stale_conversion_secs: u64,
+
+    #[clap(
+        long,
+        env,
+        help = "Seconds to keep an output image after a newer conversion replaces it",
+        default_value_t = 86400
+    )]
+    pub superseded_output_grace_secs: u64,
+
+    #[clap(
+        long,
+        env,
+        help = "Seconds between checks for superseded output images to delete. 0 disables the checks",
+        default_value_t = 3600
+    )]
+    pub superseded_output_interval_secs: u64,
+}
+
+/// Configuration for running only the background job worker.
+#[derive(Debug, Parser)]
+pub struct WorkerCommandConfig {
+    #[clap(flatten)]
+    pub queue: QueueConfig,
+
+    #[clap(long = "db", env)]
+    pub database_url: String,
+
+    #[clap(long, env)]
+    pub honeycomb_team: Option<String>,
+    #[clap(long, env, default_value_t = String::from("dev"))]
+    pub honeycomb_dataset: String,
+
+    #[clap(long, env)]
+    pub jaeger_endpoint: Option<String>,
+
+    #[clap(flatten)]
+    pub storage_keys: StorageKeyConfig,
+
+    #[clap(flatten)]
+    pub worker: WorkerConfig,
+
+    #[clap(flatten)]
+    pub conversion_job: ConversionJobConfig,
 }
@@ -73,7 +73,16 @@ pub enum Error {
     NoUploadProfile,
 
     #[error("Queue error: {0}")]
-    Queue(#[from] effectum::Error),
+    Queue(#[from] crate::jobs::QueueError),
+
+    #[error("Invalid conversion profile: {0}")]
+    InvalidConversionProfile(String),
+
+    #[error("Storage provider {0} is not allowed on this server")]
+    ProviderNotAllowed(String),
+
+    #[error("Storage credentials error: {0}")]
+    Secret(#[from] pic_store_db::secrets::SecretError),
 }
 
 impl Error {
@@ -100,12 +109,17 @@ impl Error {
             Error::InvalidSessionId => "authn",
             Error::NoUploadProfile => "no_upload_profile",
             Error::Queue(_) => "job_queue",
+            Error::InvalidConversionProfile(_) => "invalid_conversion_profile",
+            Error::ProviderNotAllowed(_) => "provider_not_allowed",
+            Error::Secret(_) => "internal_server_error",
         }
     }
 
     pub fn response_tuple(&self) -> (StatusCode, ErrorResponseData) {
         let status = match self {
             Error::NoUploadProfile => StatusCode::BAD_REQUEST,
+            Error::InvalidConversionProfile(_) => StatusCode::BAD_REQUEST,
+            Error::ProviderNotAllowed(_) => StatusCode::BAD_REQUEST,
             Error::MissingPermission(_) => StatusCode::FORBIDDEN,
             Error::NotFound => StatusCode::NOT_FOUND,
             Error::Unauthenticated => StatusCode::FORBIDDEN,
@@ -1,16 +1,75 @@
+pub mod collect_orphans;
 pub mod create_output_images;
+pub mod delete_superseded_outputs;
+pub mod migrate_storage;
+pub mod queue;
+pub mod reap_stuck_images;
 
-use std::path::Path;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
+pub use collect_orphans::*;
 pub use create_output_images::*;
+pub use delete_superseded_outputs::*;
+use futures::future::BoxFuture;
+pub use migrate_storage::*;
+pub use queue::{JobInfo, JobOptions, JobQueue, JobState, JobWorker, QueueError, RunningJob};
+pub use reap_stuck_images::*;
 
+use db::secrets::MasterKeys;
+use pic_store_convert as convert;
 use pic_store_db as db;
-use effectum::{JobRunner, Queue, Worker};
+use tokio::{
+    sync::Semaphore,
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
 use tracing::{event, Level};
 
+use crate::config::WorkerConfig;
+
 #[derive(Clone)]
 pub struct JobContext {
     pub pool: db::Pool,
+    /// The queue, for jobs that add other jobs.
+    pub queue: Arc<JobQueue>,
+    /// Decrypts storage credentials.
+    pub storage_keys: Arc<MasterKeys>,
+    /// Retry settings for conversion jobs added by other jobs.
+    pub conversion_job_options: JobOptions,
+    /// The memory limit for decoding a base image, in bytes.
+    pub decode_memory_limit: u64,
+    pub pipeline: Arc<convert::Pipeline>,
+    /// Limits the number of uploads in progress across all jobs.
+    pub upload_limit: Arc<Semaphore>,
+    /// How long an upload can go without finishing before it is aborted.
+    pub stale_upload_age: Duration,
+    /// How long an image can be converting, with no job working on it, before it is retried.
+    pub stale_conversion_age: Duration,
+    /// How long an output replaced by a newer conversion is kept before it is deleted.
+    pub superseded_output_grace: Duration,
+}
+
+impl JobContext {
+    pub fn new(
+        pool: db::Pool,
+        queue: Arc<JobQueue>,
+        storage_keys: Arc<MasterKeys>,
+        config: &WorkerConfig,
+        conversion_job_options: JobOptions,
+    ) -> Result<JobContext, eyre::Report> {
+        Ok(JobContext {
+            pool,
+            queue,
+            storage_keys,
+            conversion_job_options,
+            decode_memory_limit: config.decode_memory_limit_mb * 1024 * 1024,
+            pipeline: Arc::new(convert::Pipeline::new(config.encode_threads)?),
+            upload_limit: Arc::new(Semaphore::new(config.upload_concurrency.max(1))),
+            stale_upload_age: Duration::from_secs(config.stale_upload_secs),
+            stale_conversion_age: Duration::from_secs(config.stale_conversion_secs),
+            superseded_output_grace: Duration::from_secs(config.superseded_output_grace_secs),
+        })
+    }
 }
 
 impl std::fmt::Debug for JobContext {
@@ -20,23 +79,134 @@ impl std::fmt::Debug for JobContext {
 }
 
 pub const CREATE_OUTPUT_IMAGES: &str = "create_output_images";
+pub const REAP_STUCK_IMAGES: &str = "reap_stuck_images";
+pub const COLLECT_ORPHANS: &str = "collect_orphans";
+pub const MIGRATE_STORAGE: &str = "migrate_storage";
+pub const DELETE_SUPERSEDED_OUTPUTS: &str = "delete_superseded_outputs";
+
+/// A running queue worker, along with the tasks that periodically add maintenance jobs.
+pub struct Worker {
+    worker: JobWorker,
+    maintenance: Vec<JoinHandle<()>>,
+}
 
-pub async fn create_job_queue(
-    db_path: &Path,
+impl Worker {
+    /// Stop adding maintenance jobs and taking new jobs, and wait up to `timeout` for running
+    /// jobs to finish.
+    pub async fn unregister(self, timeout: Option<Duration>) -> Result<(), QueueError> {
+        for maintenance in self.maintenance {
+            maintenance.abort();
+        }
+
+        self.worker.unregister(timeout).await
+    }
+}
+
+/// Start a worker that runs jobs from the queue.
+pub async fn create_worker(
+    queue: &Arc<JobQueue>,
     pool: db::Pool,
-) -> Result<(Queue, Worker), effectum::Error> {
+    storage_keys: Arc<MasterKeys>,
+    config: &WorkerConfig,
+    conversion_job_options: JobOptions,
+) -> Result<Worker, eyre::Report> {
     event!(Level::INFO, "Starting background worker task");
-    let queue = Queue::new(db_path).await?;
-    let context = JobContext { pool };
+    let context = JobContext::new(
+        pool,
+        queue.clone(),
+        storage_keys,
+        config,
+        conversion_job_options,
+    )?;
+
+    let handlers = HashMap::from([
+        (
+            CREATE_OUTPUT_IMAGES,
+            run_create_output_images as queue::JobFn,
+        ),
+        (REAP_STUCK_IMAGES, run_reap_stuck_images as queue::JobFn),
+        (COLLECT_ORPHANS, run_collect_orphans as queue::JobFn),
+        (MIGRATE_STORAGE, run_migrate_storage as queue::JobFn),
+        (
+            DELETE_SUPERSEDED_OUTPUTS,
+            run_delete_superseded_outputs as queue::JobFn,
+        ),
+    ]);
+
+    let worker = JobWorker::start(queue, context, handlers, config.job_concurrency).await?;
 
-    let create_output_images =
-        JobRunner::builder(CREATE_OUTPUT_IMAGES, create_output_images_job).build();
+    let maintenance = [
+        (REAP_STUCK_IMAGES, config.reaper_interval_secs),
+        (
+            DELETE_SUPERSEDED_OUTPUTS,
+            config.superseded_output_interval_secs,
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, interval_secs)| *interval_secs > 0)
+    .map(|(job_type, interval_secs)| {
+        schedule_maintenance(queue.clone(), job_type, Duration::from_secs(interval_secs))
+    })
+    .collect();
 
-    let worker = Worker::builder(&queue, context)
-        .jobs([create_output_images])
-        .max_concurrency(10)
-        .build()
-        .await?;
+    Ok(Worker {
+        worker,
+        maintenance,
+    })
+}
+
+/// Add a maintenance job, such as reaping stuck images or deleting superseded outputs, every
+/// `interval`. When several workers share a queue, each one adds these jobs, which is harmless
+/// since the jobs only act on images that have been stuck or superseded for a while.
+fn schedule_maintenance(
+    queue: Arc<JobQueue>,
+    job_type: &'static str,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            if let Err(e) = queue.add_json(job_type, &()).await {
+                event!(Level::ERROR, error=?e, job_type, "Failed to add maintenance job");
+            }
+        }
+    })
+}
+
+fn run_create_output_images(
+    job: RunningJob,
+    context: JobContext,
+) -> BoxFuture<'static, Result<(), eyre::Report>> {
+    Box::pin(create_output_images_job(job, context))
+}
+
+fn run_reap_stuck_images(
+    job: RunningJob,
+    context: JobContext,
+) -> BoxFuture<'static, Result<(), eyre::Report>> {
+    Box::pin(reap_stuck_images_job(job, context))
+}
+
+fn run_collect_orphans(
+    job: RunningJob,
+    context: JobContext,
+) -> BoxFuture<'static, Result<(), eyre::Report>> {
+    Box::pin(collect_orphans_job(job, context))
+}
+
+fn run_migrate_storage(
+    job: RunningJob,
+    context: JobContext,
+) -> BoxFuture<'static, Result<(), eyre::Report>> {
+    Box::pin(migrate_storage_job(job, context))
+}
 
-    Ok((queue, worker))
+fn run_delete_superseded_outputs(
+    job: RunningJob,
+    context: JobContext,
+) -> BoxFuture<'static, Result<(), eyre::Report>> {
+    Box::pin(delete_superseded_outputs_job(job, context))
 }
@@ -0,0 +1,386 @@
+//! Find objects in a storage location that no base or output image refers to, such as leftovers
+//! from failed uploads, manual edits, or upload profile changes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use db::{
+    base_images,
+    object_id::StorageLocationId,
+    output_images, projects,
+    secrets::MasterKeys,
+    storage_locations::{self, Provider},
+    upload_profiles, PoolExt,
+};
+use diesel::prelude::*;
+use futures::TryStreamExt;
+use pic_store_db as db;
+use pic_store_storage as storage;
+use serde::{Deserialize, Serialize};
+use tracing::{event, instrument, Level};
+
+use super::{JobContext, RunningJob};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CollectOrphansJobPayload {
+    pub storage_location_id: StorageLocationId,
+    /// Only report the orphans instead of deleting them.
+    pub dry_run: bool,
+    /// Objects newer than this are never orphans, since their rows may not be written yet.
+    pub min_age_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OrphanedObject {
+    /// The location relative to the storage location's base.
+    pub location: String,
+    pub size: usize,
+    pub last_modified: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrphanReport {
+    pub storage_location_id: StorageLocationId,
+    pub dry_run: bool,
+    /// The number of objects in the storage location.
+    pub scanned: usize,
+    pub orphans: Vec<OrphanedObject>,
+    /// The number of orphans that were deleted.
+    pub deleted: usize,
+}
+
+#[instrument(skip(job))]
+pub async fn collect_orphans_job(job: RunningJob, context: JobContext) -> Result<(), eyre::Report> {
+    let payload = job.json_payload::<CollectOrphansJobPayload>()?;
+
+    let report = collect_orphans(
+        &context.pool,
+        &context.storage_keys,
+        payload.storage_location_id,
+        Duration::from_secs(payload.min_age_secs),
+        payload.dry_run,
+    )
+    .await?;
+
+    for orphan in &report.orphans {
+        event!(Level::INFO, location=%orphan.location, size=orphan.size, last_modified=%orphan.last_modified, "Orphaned object");
+    }
+
+    event!(
+        Level::INFO,
+        storage_location=%report.storage_location_id,
+        dry_run=report.dry_run,
+        scanned=report.scanned,
+        orphans=report.orphans.len(),
+        deleted=report.deleted,
+        "Finished collecting orphaned objects"
+    );
+
+    Ok(())
+}
+
+/// Compare a storage location's contents with the images in the database, and delete the
+/// objects that no image refers to unless `dry_run` is set.
+pub async fn collect_orphans(
+    pool: &db::Pool,
+    keys: &MasterKeys,
+    storage_location_id: StorageLocationId,
+    min_age: Duration,
+    dry_run: bool,
+) -> Result<OrphanReport, eyre::Report> {
+    let (provider, base_location, expected) = expected_objects(pool, storage_location_id).await?;
+
+    let operator = storage::Provider::from_db(provider, keys)?
+        .create_operator(&base_location)
+        .await?;
+
+    let cutoff = Utc::now() - chrono::Duration::from_std(min_age)?;
+    let (scanned, orphans) = find_orphans(&operator, &expected, cutoff).await?;
+
+    let deleted = if dry_run {
+        0
+    } else {
+        delete_orphans(&operator, &orphans).await
+    };
+
+    Ok(OrphanReport {
+        storage_location_id,
+        dry_run,
+        scanned,
+        orphans,
+        deleted,
+    })
+}
+
+/// Where the objects of another storage location appear in the one being collected, when their
+/// base locations overlap.
+#[derive(Debug, PartialEq, Eq)]
+enum Overlap {
+    /// The other location is at or inside this one, under this path.
+    Inside(String),
+    /// This location is inside the other one, under this path.
+    Contains(String),
+}
+
+impl Overlap {
+    /// Compare two base locations by path segments. Returns `None` if neither contains the other.
+    fn between(base_location: &str, other: &str) -> Option<Overlap> {
+        let base_location = relative_location(&[base_location]);
+        let other = relative_location(&[other]);
+        if let Some(path) = strip_path_prefix(&other, &base_location) {
+            Some(Overlap::Inside(path.to_string()))
+        } else {
+            strip_path_prefix(&base_location, &other)
+                .map(|path| Overlap::Contains(path.to_string()))
+        }
+    }
+
+    /// Convert a location relative to the other storage location's base into one relative to
+    /// this one. Returns `None` if the object is outside this location.
+    fn location(&self, location: &str) -> Option<String> {
+        match self {
+            Overlap::Inside(path) => Some(relative_location(&[path, location])),
+            Overlap::Contains(path) => strip_path_prefix(location, path).map(String::from),
+        }
+    }
+}
+
+/// Remove a prefix of whole path segments from a normalized path.
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if prefix.is_empty() {
+        return Some(path);
+    }
+
+    match path.strip_prefix(prefix)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('/'),
+    }
+}
+
+/// Look up a storage location, along with the locations of every base and output image stored
+/// in it, relative to the storage location's base. Other storage locations with the same
+/// provider type can point into the same bucket, so the images of every location whose base
+/// overlaps this one count too.
+async fn expected_objects(
+    pool: &db::Pool,
+    storage_location_id: StorageLocationId,
+) -> Result<(Provider, String, HashSet<String>), eyre::Report> {
+    pool.interact(move |conn| {
+        let (provider, base_location) = storage_locations::table
+            .filter(storage_locations::id.eq(storage_location_id))
+            .select((
+                storage_locations::provider,
+                storage_locations::base_location,
+            ))
+            .first::<(Provider, String)>(conn)?;
+
+        // This includes other teams' locations, since they could share the bucket too.
+        let overlapping = storage_locations::table
+            .select((
+                storage_locations::id,
+                storage_locations::provider,
+                storage_locations::base_location,
+            ))
+            .load::<(StorageLocationId, Provider, String)>(conn)?
+            .into_iter()
+            .filter(|(_, other_provider, _)| other_provider.to_string() == provider.to_string())
+            .filter_map(|(id, _, other_base)| {
+                Overlap::between(&base_location, &other_base).map(|overlap| (id, overlap))
+            })
+            .collect::<HashMap<_, _>>();
+        let location_ids = overlapping.keys().copied().collect::<Vec<_>>();
+
+        let base = base_images::table
+            .inner_join(upload_profiles::table)
+            .inner_join(projects::table.on(projects::id.eq(base_images::project_id)))
+            .filter(upload_profiles::base_storage_location_id.eq_any(location_ids.clone()))
+            .select((
+                upload_profiles::base_storage_location_id,
+                projects::base_location,
+                upload_profiles::base_storage_location_path,
+                base_images::location,
+            ))
+            .load::<(StorageLocationId, String, Option<String>, String)>(conn)?;
+
+        let output = output_images::table
+            .inner_join(base_images::table.inner_join(upload_profiles::table))
+            .inner_join(projects::table.on(projects::id.eq(base_images::project_id)))
+            .filter(upload_profiles::output_storage_location_id.eq_any(location_ids))
+            .select((
+                upload_profiles::output_storage_location_id,
+                projects::base_location,
+                upload_profiles::output_storage_location_path,
+                output_images::location,
+            ))
+            .load::<(StorageLocationId, String, Option<String>, String)>(conn)?;
+
+        let expected = base
+            .into_iter()
+            .chain(output)
+            .filter_map(|(location_id, project_path, profile_path, location)| {
+                let location = relative_location(&[
+                    project_path.as_str(),
+                    profile_path.as_deref().unwrap_or_default(),
+                    location.as_str(),
+                ]);
+                overlapping.get(&location_id)?.location(&location)
+            })
+            .collect::<HashSet<_>>();
+
+        Ok::<_, eyre::Report>((provider, base_location, expected))
+    })
+    .await
+}
+
+/// Join path segments the same way that the object store normalizes them, skipping empty
+/// segments.
+pub(super) fn relative_location(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .flat_map(|part| part.split('/'))
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// List the objects in the operator's storage that aren't in `expected` and were last modified
+/// before `cutoff`. Returns the number of objects scanned along with the orphans.
+pub async fn find_orphans(
+    operator: &storage::Operator,
+    expected: &HashSet<String>,
+    cutoff: DateTime<Utc>,
+) -> Result<(usize, Vec<OrphanedObject>), eyre::Report> {
+    let mut scanned = 0;
+    let mut orphans = Vec::new();
+    let mut objects = operator.list(None).await?;
+    while let Some(meta) = objects.try_next().await? {
+        scanned += 1;
+        let location = meta.location.to_string();
+        if meta.last_modified < cutoff && !expected.contains(&location) {
+            orphans.push(OrphanedObject {
+                location,
+                size: meta.size,
+                last_modified: meta.last_modified,
+            });
+        }
+    }
+
+    orphans.sort_by(|a, b| a.location.cmp(&b.location));
+    Ok((scanned, orphans))
+}
+
+/// Delete orphaned objects, returning how many were deleted. Failures are logged and skipped so
+/// that one bad object doesn't stop the rest.
+pub async fn delete_orphans(operator: &storage::Operator, orphans: &[OrphanedObject]) -> usize {
+    let mut deleted = 0;
+    for orphan in orphans {
+        match operator.delete(&orphan.location).await {
+            Ok(()) => deleted += 1,
+            Err(e) => {
+                event!(Level::WARN, location=%orphan.location, error=?e, "Failed to delete orphaned object");
+            }
+        }
+    }
+
+    deleted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn local_operator(dir: &temp_dir::TempDir) -> storage::Operator {
+        storage::Provider::new(storage::ProviderConfig::Local)
+            .create_operator(dir.path().to_str().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn relative_location_skips_empty_segments() {
+        assert_eq!(
+            relative_location(&["", "profile/", "abc.jpg"]),
+            "profile/abc.jpg"
+        );
+        assert_eq!(
+            relative_location(&["/project", "a/b", "abc.jpg"]),
+            "project/a/b/abc.jpg"
+        );
+    }
+
+    #[test]
+    fn overlapping_locations() {
+        assert_eq!(
+            Overlap::between("bucket/images", "bucket/images/"),
+            Some(Overlap::Inside(String::new()))
+        );
+        assert_eq!(
+            Overlap::between("bucket", "bucket/images"),
+            Some(Overlap::Inside("images".to_string()))
+        );
+        assert_eq!(
+            Overlap::between("bucket/images/a", "bucket"),
+            Some(Overlap::Contains("images/a".to_string()))
+        );
+        assert_eq!(Overlap::between("bucket/images", "bucket/images2"), None);
+        assert_eq!(Overlap::between("bucket", "other"), None);
+
+        let inside = Overlap::between("bucket", "bucket/images").unwrap();
+        assert_eq!(
+            inside.location("project/a.jpg").as_deref(),
+            Some("images/project/a.jpg")
+        );
+
+        let contains = Overlap::between("bucket/images", "bucket").unwrap();
+        assert_eq!(
+            contains.location("images/project/a.jpg").as_deref(),
+            Some("project/a.jpg")
+        );
+        assert_eq!(contains.location("other/a.jpg"), None);
+    }
+
+    #[tokio::test]
+    async fn finds_and_deletes_orphans() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let operator = local_operator(&dir).await;
+        for location in ["project/kept.jpg", "project/orphan.jpg", "stray.txt"] {
+            operator
+                .put(
+                    location,
+                    bytes::Bytes::from_static(b"data"),
+                    &storage::ObjectAttributes::default(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let expected = HashSet::from(["project/kept.jpg".to_string()]);
+
+        // Everything is newer than the cutoff, so nothing is an orphan yet.
+        let (scanned, orphans) = find_orphans(
+            &operator,
+            &expected,
+            Utc::now() - chrono::Duration::hours(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(scanned, 3);
+        assert!(orphans.is_empty());
+
+        let cutoff = Utc::now() + chrono::Duration::minutes(1);
+        let (_, orphans) = find_orphans(&operator, &expected, cutoff).await.unwrap();
+        let locations = orphans
+            .iter()
+            .map(|o| o.location.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(locations, vec!["project/orphan.jpg", "stray.txt"]);
+
+        assert_eq!(delete_orphans(&operator, &orphans).await, 2);
+        let (scanned, orphans) = find_orphans(&operator, &expected, cutoff).await.unwrap();
+        assert_eq!(scanned, 1);
+        assert!(orphans.is_empty());
+    }
+}
@@ -3,28 +3,109 @@ use std::sync::Arc;
 use bytes::Bytes;
 use db::{
     base_images,
-    conversion_profiles::{ConversionFormat, ConversionSize},
+    conversion_profiles::{
+        ChromaSubsampling, ConversionFormat, ConversionOutput, ConversionSize, PngCompression,
+        ResizeFilter, TargetQuality,
+    },
     image_base_location,
     object_id::{BaseImageId, OutputImageId},
     storage_locations::Provider,
-    upload_profiles, BaseImageStatus, OutputImageStatus, PoolExt,
+    upload_profiles, BaseImageStatus, ImageFormat, OutputFilenameHash, OutputImageStatus, PoolExt,
 };
 use diesel::prelude::*;
-use effectum::RunningJob;
 use image::DynamicImage;
 use pic_store_convert as convert;
 use pic_store_db as db;
 use pic_store_storage as storage;
 use serde::{Deserialize, Serialize};
 use tracing::{event, instrument, Level};
+use uuid::Uuid;
 
-use super::JobContext;
-use crate::Result;
+use super::{JobContext, JobOptions, JobQueue, RunningJob, CREATE_OUTPUT_IMAGES};
+use crate::{Error, Result};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CreateOutputImagesJobPayload {
     pub base_image: BaseImageId,
+    /// The outputs that the job still has to create. Finished outputs are removed as it runs.
     pub conversions: Vec<OutputImageId>,
+    /// Every output that the job was created for.
+    #[serde(default)]
+    pub outputs: Vec<OutputImageId>,
+}
+
+/// Add a job to create the given output images, and record it as the base image's conversion
+/// job. This starts a new conversion, so the base image's attempt count and error are reset.
+pub async fn enqueue_create_output_images(
+    pool: &db::Pool,
+    queue: &JobQueue,
+    options: &JobOptions,
+    base_image_id: BaseImageId,
+    conversions: Vec<OutputImageId>,
+) -> Result<Uuid> {
+    add_conversion_job(pool, queue, options, base_image_id, conversions, true).await
+}
+
+/// Add a new job to finish a conversion that stopped partway. Unlike
+/// [enqueue_create_output_images], this keeps the attempt count, so that a conversion that keeps
+/// getting stuck eventually fails.
+pub(super) async fn requeue_create_output_images(
+    pool: &db::Pool,
+    queue: &JobQueue,
+    options: &JobOptions,
+    base_image_id: BaseImageId,
+    conversions: Vec<OutputImageId>,
+) -> Result<Uuid> {
+    add_conversion_job(pool, queue, options, base_image_id, conversions, false).await
+}
+
+async fn add_conversion_job(
+    pool: &db::Pool,
+    queue: &JobQueue,
+    options: &JobOptions,
+    base_image_id: BaseImageId,
+    conversions: Vec<OutputImageId>,
+    new_conversion: bool,
+) -> Result<Uuid> {
+    let job_id = queue
+        .add_json_with_options(
+            CREATE_OUTPUT_IMAGES,
+            &CreateOutputImagesJobPayload {
+                base_image: base_image_id,
+                outputs: conversions.clone(),
+                conversions,
+            },
+            options,
+        )
+        .await?;
+
+    pool.interact(move |conn| {
+        let update = diesel::update(base_images::table).filter(base_images::id.eq(base_image_id));
+        let result = if new_conversion {
+            update
+                .set((
+                    base_images::status.eq(BaseImageStatus::Converting),
+                    base_images::conversion_job_id.eq(job_id),
+                    base_images::conversion_attempts.eq(0),
+                    base_images::conversion_error.eq(None::<String>),
+                    base_images::updated.eq(diesel::dsl::now),
+                ))
+                .execute(conn)
+        } else {
+            update
+                .set((
+                    base_images::status.eq(BaseImageStatus::Converting),
+                    base_images::conversion_job_id.eq(job_id),
+                    base_images::updated.eq(diesel::dsl::now),
+                ))
+                .execute(conn)
+        };
+
+        result.map_err(Error::from)
+    })
+    .await?;
+
+    Ok(job_id)
 }
 
 #[instrument(skip(job))]
@@ -36,6 +117,82 @@ pub async fn create_output_images_job(
 
     event!(Level::INFO, ?payload);
 
+    let base_image_id = payload.base_image;
+    context
+        .pool
+        .interact(move |conn| {
+            diesel::update(db::base_images::table)
+                .filter(db::base_images::id.eq(base_image_id))
+                .set((
+                    db::base_images::status.eq(BaseImageStatus::Converting),
+                    db::base_images::conversion_attempts
+                        .eq(db::base_images::conversion_attempts + 1),
+                    db::base_images::updated.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            Ok::<_, eyre::Report>(())
+        })
+        .await?;
+
+    let result = create_output_images(&job, &context, &mut payload).await;
+    match &result {
+        // Until the last try, leave the image converting so that clients don't see a failure
+        // while a retry is still coming.
+        Err(e) if job.is_last_try() => {
+            if let Err(mark_error) = mark_failed(&context, &payload, format!("{e:#}")).await {
+                event!(Level::ERROR, error=?mark_error, "Failed to mark conversion as failed");
+            }
+        }
+        _ => {}
+    }
+
+    result
+}
+
+/// Mark the base image and any outputs that weren't finished as failed.
+pub(super) async fn mark_failed(
+    context: &JobContext,
+    payload: &CreateOutputImagesJobPayload,
+    error: String,
+) -> Result<(), eyre::Report> {
+    let base_image_id = payload.base_image;
+    let remaining = payload.conversions.clone();
+    context
+        .pool
+        .transaction(move |conn| {
+            diesel::update(db::output_images::table)
+                .filter(db::output_images::id.eq_any(remaining))
+                .filter(db::output_images::status.eq_any(vec![
+                    OutputImageStatus::Queued,
+                    OutputImageStatus::Converting,
+                ]))
+                .set((
+                    db::output_images::status.eq(OutputImageStatus::Failed),
+                    db::output_images::error.eq(&error),
+                    db::output_images::updated.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+
+            diesel::update(db::base_images::table)
+                .filter(db::base_images::id.eq(base_image_id))
+                .set((
+                    db::base_images::status.eq(BaseImageStatus::Failed),
+                    db::base_images::conversion_error.eq(&error),
+                    db::base_images::updated.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+
+            Ok::<_, eyre::Report>(())
+        })
+        .await
+}
+
+async fn create_output_images(
+    job: &RunningJob,
+    context: &JobContext,
+    payload: &mut CreateOutputImagesJobPayload,
+) -> Result<(), eyre::Report> {
+    let base_image_id = payload.base_image;
     let (bst, ost) = diesel::alias!(db::storage_locations as bst, db::storage_locations as ost);
 
     let (
@@ -47,6 +204,10 @@ pub async fn create_output_images_job(
         output_image_base_location,
         output_image_profile_base_path,
         output_image_storage_provider,
+        output_cache_control,
+        filename_hash,
+        conversion_output,
+        (base_image_format, stored_width, stored_height, base_image_file_size),
     ) = context
         .pool
         .interact(move |conn| {
@@ -66,7 +227,11 @@ pub async fn create_output_images_job(
                 .inner_join(
                     db::projects::table.on(db::projects::id.eq(db::base_images::project_id)),
                 )
-                .filter(db::base_images::id.eq(payload.base_image))
+                .inner_join(
+                    db::conversion_profiles::table
+                        .on(db::conversion_profiles::id.eq(upload_profiles::conversion_profile_id)),
+                )
+                .filter(db::base_images::id.eq(base_image_id))
                 .select((
                     db::projects::base_location,
                     db::base_images::location,
@@ -76,6 +241,15 @@ pub async fn create_output_images_job(
                     ost.field(db::storage_locations::base_location),
                     upload_profiles::output_storage_location_path,
                     ost.field(db::storage_locations::provider),
+                    ost.field(db::storage_locations::cache_control),
+                    upload_profiles::output_filename_hash,
+                    db::conversion_profiles::output,
+                    (
+                        db::base_images::format,
+                        db::base_images::width,
+                        db::base_images::height,
+                        db::base_images::file_size,
+                    ),
                 ))
                 .first::<(
                     String,
@@ -86,6 +260,10 @@ pub async fn create_output_images_job(
                     String,
                     Option<String>,
                     Provider,
+                    Option<String>,
+                    Option<OutputFilenameHash>,
+                    ConversionOutput,
+                    (Option<ImageFormat>, i32, i32, i32),
                 )>(conn)
                 .map_err(eyre::Report::new)
         })
@@ -97,98 +275,274 @@ pub async fn create_output_images_job(
         &base_image_profile_base_path,
     );
 
-    let base_image_storage = storage::Provider::from_db(base_image_storage_provider)?;
-    let base_image = read_image(
+    let base_image_storage =
+        storage::Provider::from_db(base_image_storage_provider, &context.storage_keys)?;
+    let (base_image_data, base_image) = read_image(
         base_image_storage,
         base_image_base_location.as_ref(),
         base_image_location.as_str(),
+        context.decode_memory_limit,
     )
     .await?;
 
+    // Some formats, such as camera RAW, don't have dimensions until they are decoded. Otherwise
+    // keep the stored dimensions, since large images may be decoded at a reduced size.
+    let dimensions_unknown = stored_width == 0 || stored_height == 0;
+    let (base_image_width, base_image_height) = if dimensions_unknown {
+        (base_image.width(), base_image.height())
+    } else {
+        (stored_width as u32, stored_height as u32)
+    };
+    if dimensions_unknown {
+        context
+            .pool
+            .interact(move |conn| {
+                diesel::update(db::base_images::table)
+                    .filter(db::base_images::id.eq(base_image_id))
+                    .set((
+                        db::base_images::width.eq(base_image_width as i32),
+                        db::base_images::height.eq(base_image_height as i32),
+                    ))
+                    .execute(conn)?;
+                Ok::<_, eyre::Report>(())
+            })
+            .await?;
+    }
+
     let output_image_base_location = image_base_location(
         &output_image_base_location,
         &project_base_location,
         &output_image_profile_base_path,
     );
 
-    let output_image_storage = storage::Provider::from_db(output_image_storage_provider)?;
+    let output_image_storage =
+        storage::Provider::from_db(output_image_storage_provider, &context.storage_keys)?;
     let output_operator = output_image_storage
         .create_operator(output_image_base_location.as_ref())
         .await?;
 
-    while let Some(output_image_id) = payload.conversions.pop() {
-        //  Get the next conversion profile from the list
-        let (output_location, conversion_format, conversion_size) = context
+    //  Get the remaining conversions and mark them as in progress.
+    let conversion_ids = payload.conversions.clone();
+    let conversions = context
+        .pool
+        .interact(move |conn| {
+            diesel::update(db::output_images::table)
+                .filter(db::output_images::id.eq_any(conversion_ids))
+                .set((
+                    db::output_images::status.eq(OutputImageStatus::Converting),
+                    db::output_images::attempts.eq(db::output_images::attempts + 1),
+                    db::output_images::updated.eq(diesel::dsl::now),
+                ))
+                .returning((
+                    db::output_images::id,
+                    db::output_images::location,
+                    db::output_images::format,
+                    db::output_images::size,
+                ))
+                .get_results::<(OutputImageId, String, ConversionFormat, ConversionSize)>(conn)
+                .map_err(eyre::Report::new)
+        })
+        .await?;
+
+    // Large images may be decoded at a reduced size, which the sizes were not planned against.
+    // Without upscaling, every size larger than the decoded image comes out at its size, so plan
+    // the sizes again against the decoded dimensions and skip the outputs that would be copies.
+    let svg_input = base_image_format == Some(ImageFormat::Svg);
+    let decoded_size = (base_image.width(), base_image.height());
+    let duplicates = if !svg_input && decoded_size != (base_image_width, base_image_height) {
+        duplicate_outputs(
+            &conversions,
+            decoded_size,
+            conversion_output.allow_upscale(),
+        )
+    } else {
+        Vec::new()
+    };
+
+    if !duplicates.is_empty() {
+        event!(
+            Level::INFO,
+            ?duplicates,
+            "Skipping outputs larger than the decoded image"
+        );
+        let skipped = duplicates.clone();
+        context
             .pool
             .interact(move |conn| {
                 diesel::update(db::output_images::table)
-                    .filter(db::output_images::id.eq(output_image_id))
+                    .filter(db::output_images::id.eq_any(skipped))
                     .set((
-                        db::output_images::status.eq(OutputImageStatus::Converting),
+                        db::output_images::status.eq(OutputImageStatus::Skipped),
                         db::output_images::updated.eq(diesel::dsl::now),
                     ))
-                    .returning((
-                        db::output_images::location,
-                        db::output_images::format,
-                        db::output_images::size,
-                    ))
-                    .get_result::<(String, ConversionFormat, ConversionSize)>(conn)
-                    .map_err(eyre::Report::new)
+                    .execute(conn)?;
+                Ok::<_, eyre::Report>(())
             })
             .await?;
 
-        //  Do the conversion
+        payload.conversions.retain(|id| !duplicates.contains(id));
+        job.checkpoint_json(&*payload).await?;
+    }
 
-        let size = convert::ImageSizeTransform {
-            width: conversion_size.width,
-            height: conversion_size.height,
-            preserve_aspect_ratio: conversion_size.preserve_aspect_ratio.unwrap_or(true),
+    // SVG outputs are a sanitized copy of the original, and everything else goes through the
+    // conversion pipeline together so that resizes can be shared.
+    let (passthrough, converted): (Vec<_>, Vec<_>) = conversions
+        .into_iter()
+        .filter(|(id, ..)| !duplicates.contains(id))
+        .map(|(id, location, format, size)| {
+            let output_format = output_format(&format);
+            // Without a hash, uploading a new version of the base image reuses the output names,
+            // so they can't be cached forever.
+            let attributes = storage::ObjectAttributes {
+                content_type: storage::content_type_for_extension(format.extension())
+                    .map(String::from),
+                cache_control: storage::cache_control(
+                    output_cache_control.as_deref(),
+                    filename_hash.is_some(),
+                ),
+                content_disposition: None,
+            };
+            (id, location, output_format, size, attributes)
+        })
+        .partition(|(_, _, output_format, _, _)| output_format.is_none());
+
+    let requests = converted
+        .iter()
+        .filter_map(|(_, _, format, size, _)| {
+            Some(convert::ConversionRequest {
+                format: format.clone()?,
+                size: size_transform(size, conversion_output.allow_upscale()),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let pipeline = context.pipeline.clone();
+    let b = base_image.clone();
+    let source = base_image_data.clone();
+    let passthrough_count = passthrough.len();
+
+    event!(
+        Level::INFO,
+        count = requests.len() + passthrough_count,
+        "Converting images"
+    );
+    let results = tokio::task::spawn_blocking(move || {
+        let sanitized = (0..passthrough_count).map(|_| {
+            convert::svg::sanitize(&source)
+                .map(|image| convert::ConvertResult {
+                    width: b.width(),
+                    height: b.height(),
+                    image,
+                    quality: None,
+                })
+                .map_err(eyre::Report::from)
+        });
+
+        let converted = if svg_input {
+            pipeline
+                .convert_svg_all(&source, &requests)
+                .into_iter()
+                .map(|r| r.map_err(eyre::Report::from))
+                .collect::<Vec<_>>()
+        } else {
+            pipeline
+                .convert_all(&b, Some(&source), &requests)
+                .into_iter()
+                .map(|r| r.map_err(eyre::Report::from))
+                .collect::<Vec<_>>()
         };
 
-        let output_format = image::ImageFormat::from(&conversion_format);
-        let quality = conversion_format.quality();
-        let b = base_image.clone();
+        sanitized.chain(converted).collect::<Vec<_>>()
+    })
+    .await?;
 
-        event!(Level::INFO, image=%output_location, format=?output_format, quality=?quality, "Converting image");
-        let convert_result = tokio::task::spawn_blocking(move || {
-            convert::convert(&b, output_format, quality, &size)
-        })
-        .await??;
+    // Save each output that succeeded, and record the errors of the others so that they can be
+    // seen while the job retries. They are only marked failed once there are no retries left.
+    let last_try = job.is_last_try();
+    let mut failed = 0;
+    let outputs = passthrough.into_iter().chain(converted);
+    for ((output_image_id, output_location, _, _, attributes), convert_result) in
+        outputs.zip(results)
+    {
+        let saved = match convert_result {
+            Ok(convert_result) => {
+                let output_location = match filename_hash {
+                    Some(OutputFilenameHash::Content) => content_hashed_location(
+                        &output_location,
+                        base_image_id,
+                        &convert_result.image,
+                    ),
+                    Some(OutputFilenameHash::Parameters) | None => output_location,
+                };
 
-        let size_bytes = convert_result.image.len() as i32;
-        output_operator
-            .put(output_location.as_str(), Bytes::from(convert_result.image))
-            .await?;
+                save_output(
+                    context,
+                    &output_operator,
+                    base_image_id,
+                    output_image_id,
+                    output_location,
+                    &attributes,
+                    convert_result,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
 
-        context
-            .pool
-            .interact(move |conn| {
-                // Add the OutputImage entry
-                diesel::update(db::output_images::table)
-                    .filter(db::output_images::id.eq(output_image_id))
-                    .set((
-                        db::output_images::status.eq(OutputImageStatus::Ready),
-                        db::output_images::file_size.eq(size_bytes),
-                        db::output_images::width.eq(convert_result.width as i32),
-                        db::output_images::height.eq(convert_result.height as i32),
-                        db::output_images::updated.eq(diesel::dsl::now),
-                    ))
-                    .execute(conn)?;
+        if let Err(e) = saved {
+            event!(Level::ERROR, output_image=%output_image_id, error=?e, "Failed to create output image");
+            failed += 1;
+            let error = format!("{e:#}");
+            let status = if last_try {
+                OutputImageStatus::Failed
+            } else {
+                OutputImageStatus::Converting
+            };
+            context
+                .pool
+                .interact(move |conn| {
+                    diesel::update(db::output_images::table)
+                        .filter(db::output_images::id.eq(output_image_id))
+                        .set((
+                            db::output_images::status.eq(status),
+                            db::output_images::error.eq(error),
+                            db::output_images::updated.eq(diesel::dsl::now),
+                        ))
+                        .execute(conn)?;
+                    Ok::<_, eyre::Report>(())
+                })
+                .await?;
+            continue;
+        }
 
-                Ok::<_, eyre::Report>(())
-            })
-            .await?;
+        payload.conversions.retain(|id| *id != output_image_id);
+        job.checkpoint_json(&*payload).await?;
+    }
 
-        job.checkpoint_json(&payload).await?;
+    if failed > 0 {
+        return Err(eyre::eyre!("Failed to create {failed} output images"));
     }
 
+    let original = ComparableOutput {
+        id: None,
+        format: base_image_format,
+        width: Some(base_image_width as i32),
+        height: Some(base_image_height as i32),
+        file_size: base_image_file_size,
+    };
+    skip_dominated_outputs(context, &output_operator, base_image_id, original).await?;
+
     // Set the base image status to done.
     context
         .pool
         .interact(move |conn| {
             diesel::update(db::base_images::table)
-                .filter(db::base_images::id.eq(payload.base_image))
-                .set(db::base_images::status.eq(BaseImageStatus::Ready))
+                .filter(db::base_images::id.eq(base_image_id))
+                .set((
+                    db::base_images::status.eq(BaseImageStatus::Ready),
+                    db::base_images::conversion_error.eq(None::<String>),
+                    db::base_images::updated.eq(diesel::dsl::now),
+                ))
                 .execute(conn)?;
 
             Ok::<_, eyre::Report>(())
@@ -198,14 +552,502 @@ pub async fn create_output_images_job(
     Ok(())
 }
 
+/// Insert the hash of an output's encoded bytes into its location. A retried conversion may have
+/// already hashed the location, so any earlier hash after the base image ID is replaced.
+fn content_hashed_location(location: &str, base_image_id: BaseImageId, data: &[u8]) -> String {
+    let id = base_image_id.display_without_prefix().to_string();
+    let unhashed = location
+        .rsplit_once('.')
+        .and_then(|(stem, extension)| {
+            let (unhashed_stem, _) = stem.rsplit_once('-')?;
+            unhashed_stem
+                .ends_with(&id)
+                .then(|| format!("{unhashed_stem}.{extension}"))
+        })
+        .unwrap_or_else(|| location.to_string());
+
+    db::output_images::hashed_location(&unhashed, data)
+}
+
+/// Upload a converted image and mark it ready.
+async fn save_output(
+    context: &JobContext,
+    output_operator: &storage::Operator,
+    base_image_id: BaseImageId,
+    output_image_id: OutputImageId,
+    output_location: String,
+    attributes: &storage::ObjectAttributes,
+    convert_result: convert::ConvertResult,
+) -> Result<(), eyre::Report> {
+    event!(Level::INFO, image=%output_location, "Saving image");
+    let size_bytes = convert_result.image.len() as i32;
+    let upload_permit = context.upload_limit.acquire().await?;
+    output_operator
+        .put(
+            &output_location,
+            Bytes::from(convert_result.image),
+            attributes,
+        )
+        .await?;
+    drop(upload_permit);
+
+    context
+        .pool
+        .transaction(move |conn| {
+            // With content hashes, an earlier version of this output with the same bytes may be
+            // waiting to be deleted. It has the same location, so this output takes it over.
+            diesel::delete(db::output_images::table)
+                .filter(db::output_images::base_image_id.eq(base_image_id))
+                .filter(db::output_images::location.eq(&output_location))
+                .filter(db::output_images::id.ne(output_image_id))
+                .execute(conn)?;
+
+            // Add the OutputImage entry
+            diesel::update(db::output_images::table)
+                .filter(db::output_images::id.eq(output_image_id))
+                .set((
+                    db::output_images::location.eq(&output_location),
+                    db::output_images::status.eq(OutputImageStatus::Ready),
+                    db::output_images::file_size.eq(size_bytes),
+                    db::output_images::width.eq(convert_result.width as i32),
+                    db::output_images::height.eq(convert_result.height as i32),
+                    db::output_images::quality.eq(convert_result.quality),
+                    db::output_images::error.eq(None::<String>),
+                    db::output_images::updated.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+
+            Ok::<_, eyre::Report>(())
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// The size information needed to decide whether an output is worth keeping.
+#[derive(Debug, Clone)]
+pub struct ComparableOutput {
+    /// The output image, or `None` for the original image.
+    pub id: Option<OutputImageId>,
+    pub format: Option<ImageFormat>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub file_size: i32,
+}
+
+/// Formats that every browser can display, and so will always be kept.
+fn is_fallback_format(format: Option<ImageFormat>) -> bool {
+    matches!(format, Some(ImageFormat::Jpg) | Some(ImageFormat::Png))
+}
+
+/// Find outputs in newer formats that are no smaller than a fallback-format output, or the
+/// original image, with the same dimensions. Serving these would only cost bytes.
+pub fn dominated_outputs(
+    outputs: &[ComparableOutput],
+    original: &ComparableOutput,
+) -> Vec<OutputImageId> {
+    outputs
+        .iter()
+        .filter(|o| {
+            // SVG outputs aren't comparable with raster images, so they are always kept.
+            !is_fallback_format(o.format) && o.format != Some(ImageFormat::Svg) && o.width.is_some()
+        })
+        .filter(|o| {
+            outputs
+                .iter()
+                .chain(std::iter::once(original))
+                .filter(|other| is_fallback_format(other.format))
+                .filter(|other| other.width == o.width && other.height == o.height)
+                .any(|other| other.file_size <= o.file_size)
+        })
+        .filter_map(|o| o.id)
+        .collect()
+}
+
+/// Mark outputs that are larger than a fallback format as skipped and remove them from storage.
+async fn skip_dominated_outputs(
+    context: &JobContext,
+    output_operator: &storage::Operator,
+    base_image_id: BaseImageId,
+    original: ComparableOutput,
+) -> Result<(), eyre::Report> {
+    let outputs = context
+        .pool
+        .interact(move |conn| {
+            db::output_images::table
+                .filter(db::output_images::base_image_id.eq(base_image_id))
+                .filter(db::output_images::status.eq(OutputImageStatus::Ready))
+                .select((
+                    db::output_images::id,
+                    db::output_images::location,
+                    db::output_images::format,
+                    db::output_images::width,
+                    db::output_images::height,
+                    db::output_images::file_size,
+                ))
+                .load::<(
+                    OutputImageId,
+                    String,
+                    ConversionFormat,
+                    Option<i32>,
+                    Option<i32>,
+                    i32,
+                )>(conn)
+                .map_err(eyre::Report::new)
+        })
+        .await?;
+
+    let comparable = outputs
+        .iter()
+        .map(
+            |(id, _, format, width, height, file_size)| ComparableOutput {
+                id: Some(*id),
+                format: Some(format.as_db_image_format()),
+                width: *width,
+                height: *height,
+                file_size: *file_size,
+            },
+        )
+        .collect::<Vec<_>>();
+
+    let dominated = dominated_outputs(&comparable, &original);
+    if dominated.is_empty() {
+        return Ok(());
+    }
+
+    event!(
+        Level::INFO,
+        ?dominated,
+        "Skipping outputs larger than fallback formats"
+    );
+
+    // Update the status first so that nothing references the images once they are deleted.
+    let skipped = dominated.clone();
+    context
+        .pool
+        .interact(move |conn| {
+            diesel::update(db::output_images::table)
+                .filter(db::output_images::id.eq_any(skipped))
+                .set((
+                    db::output_images::status.eq(OutputImageStatus::Skipped),
+                    db::output_images::updated.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            Ok::<_, eyre::Report>(())
+        })
+        .await?;
+
+    for (id, location, ..) in outputs.iter().filter(|o| dominated.contains(&o.0)) {
+        if let Err(e) = output_operator.delete(location).await {
+            event!(Level::WARN, output_image=%id, %location, error=?e, "Failed to delete skipped output");
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the outputs that would be copies of another output once their sizes are planned against
+/// the decoded image. Only the lowest density of each format at the decoded size is kept.
+fn duplicate_outputs(
+    outputs: &[(OutputImageId, String, ConversionFormat, ConversionSize)],
+    decoded_size: (u32, u32),
+    allow_upscale: bool,
+) -> Vec<OutputImageId> {
+    let (width, height) = decoded_size;
+    let mut at_decoded_size = outputs
+        .iter()
+        .filter(|(_, _, _, size)| {
+            let transform = size_transform(size, allow_upscale);
+            convert::resize::output_size(width, height, &transform) == decoded_size
+        })
+        .map(|(id, _, format, size)| {
+            let format = serde_json::to_string(format).expect("serializing conversion format");
+            (format, size.density.unwrap_or(1.0), *id)
+        })
+        .collect::<Vec<_>>();
+
+    at_decoded_size.sort_by(|a, b| {
+        a.0.cmp(&b.0)
+            .then(a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+    });
+
+    at_decoded_size
+        .windows(2)
+        .filter(|pair| pair[0].0 == pair[1].0)
+        .map(|pair| pair[1].2)
+        .collect()
+}
+
+pub fn size_transform(size: &ConversionSize, allow_upscale: bool) -> convert::ImageSizeTransform {
+    convert::ImageSizeTransform {
+        width: size.width,
+        height: size.height,
+        preserve_aspect_ratio: size.preserve_aspect_ratio.unwrap_or(true),
+        allow_upscale,
+        resize: convert::ResizeOptions {
+            filter: size.filter.map(|filter| match filter {
+                ResizeFilter::Nearest => convert::ResizeFilter::Nearest,
+                ResizeFilter::Bilinear => convert::ResizeFilter::Bilinear,
+                ResizeFilter::CatmullRom => convert::ResizeFilter::CatmullRom,
+                ResizeFilter::Lanczos3 => convert::ResizeFilter::Lanczos3,
+            }),
+            linear_light: size.linear_light.unwrap_or(false),
+        },
+    }
+}
+
+fn chroma_subsampling(subsampling: ChromaSubsampling) -> convert::write_format::ChromaSubsampling {
+    match subsampling {
+        ChromaSubsampling::Yuv444 => convert::write_format::ChromaSubsampling::Yuv444,
+        ChromaSubsampling::Yuv422 => convert::write_format::ChromaSubsampling::Yuv422,
+        ChromaSubsampling::Yuv420 => convert::write_format::ChromaSubsampling::Yuv420,
+    }
+}
+
+fn target_quality(target: &TargetQuality) -> convert::write_format::TargetQuality {
+    convert::write_format::TargetQuality {
+        dssim: target.dssim,
+        min_quality: target.min_quality.unwrap_or(10.0),
+        max_quality: target.max_quality.unwrap_or(95.0),
+    }
+}
+
+/// Get the encoder settings for a conversion format. SVG outputs aren't encoded, so they return
+/// `None`.
+pub fn output_format(format: &ConversionFormat) -> Option<convert::OutputFormat> {
+    use convert::write_format::{
+        AvifOptions, HeicOptions, JpegOptions, JxlOptions, PngOptions, WebpOptions,
+    };
+
+    let output = match format {
+        ConversionFormat::Png {
+            compression,
+            palette_colors,
+            ..
+        } => convert::OutputFormat::Png(PngOptions {
+            compression: match compression {
+                Some(PngCompression::Fast) => convert::write_format::PngCompression::Fast,
+                Some(PngCompression::Default) => convert::write_format::PngCompression::Default,
+                Some(PngCompression::Best) | None => convert::write_format::PngCompression::Best,
+            },
+            palette_colors: *palette_colors,
+        }),
+        ConversionFormat::Jpg {
+            quality,
+            target_quality: target,
+            progressive,
+            chroma_subsampling: subsampling,
+            ..
+        } => convert::OutputFormat::Jpeg(JpegOptions {
+            quality: *quality,
+            target_quality: target.as_ref().map(target_quality),
+            progressive: progressive.unwrap_or(false),
+            chroma_subsampling: subsampling.map(chroma_subsampling),
+        }),
+        ConversionFormat::Webp {
+            quality,
+            target_quality: target,
+            method,
+            lossless,
+            near_lossless,
+            ..
+        } => convert::OutputFormat::Webp(WebpOptions {
+            quality: *quality,
+            target_quality: target.as_ref().map(target_quality),
+            method: *method,
+            lossless: lossless.unwrap_or(false),
+            near_lossless: *near_lossless,
+        }),
+        ConversionFormat::Avif {
+            quality,
+            target_quality: target,
+            speed,
+            chroma_subsampling: subsampling,
+            bit_depth,
+            ..
+        } => convert::OutputFormat::Avif(AvifOptions {
+            quality: *quality,
+            target_quality: target.as_ref().map(target_quality),
+            speed: *speed,
+            chroma_subsampling: subsampling.map(chroma_subsampling),
+            bit_depth: *bit_depth,
+        }),
+        ConversionFormat::Jxl {
+            quality,
+            target_quality: target,
+            effort,
+            lossless,
+            lossless_jpeg,
+            ..
+        } => convert::OutputFormat::Jxl(JxlOptions {
+            quality: *quality,
+            target_quality: target.as_ref().map(target_quality),
+            effort: *effort,
+            lossless: lossless.unwrap_or(false),
+            lossless_jpeg: lossless_jpeg.unwrap_or(false),
+        }),
+        ConversionFormat::Heic {
+            quality,
+            target_quality: target,
+            lossless,
+            ..
+        } => convert::OutputFormat::Heic(HeicOptions {
+            quality: *quality,
+            target_quality: target.as_ref().map(target_quality),
+            lossless: lossless.unwrap_or(false),
+        }),
+        ConversionFormat::Svg { .. } => return None,
+    };
+
+    Some(output)
+}
+
+/// Read the base image, returning both the original file and the decoded image.
 async fn read_image(
     storage_provider: pic_store_storage::Provider,
     base_location: &str,
     location: &str,
-) -> Result<Arc<DynamicImage>, eyre::Report> {
+    decode_memory_limit: u64,
+) -> Result<(Bytes, Arc<DynamicImage>), eyre::Report> {
     let op = storage_provider.create_operator(base_location).await?;
     let base_image_data = op.get(location).await?;
     let buffer = base_image_data.bytes().await?;
-    let base_image = Arc::new(convert::image_from_bytes(&buffer)?);
-    Ok(base_image)
+    let base_image = Arc::new(convert::image_from_bytes_with_limit(
+        &buffer,
+        Some(decode_memory_limit),
+    )?);
+    Ok((buffer, base_image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(format: ImageFormat, width: i32, file_size: i32) -> ComparableOutput {
+        ComparableOutput {
+            id: Some(OutputImageId::new()),
+            format: Some(format),
+            width: Some(width),
+            height: Some(width / 2),
+            file_size,
+        }
+    }
+
+    fn original(format: ImageFormat, file_size: i32) -> ComparableOutput {
+        ComparableOutput {
+            id: None,
+            format: Some(format),
+            width: Some(800),
+            height: Some(400),
+            file_size,
+        }
+    }
+
+    #[test]
+    fn content_hash_replaces_earlier_hash() {
+        let base_image_id = BaseImageId::new();
+        let location = format!("photo-w200-{}.avif", base_image_id.display_without_prefix());
+
+        let hashed = content_hashed_location(&location, base_image_id, b"first");
+        assert_ne!(hashed, location);
+        assert_eq!(
+            content_hashed_location(&hashed, base_image_id, b"first"),
+            hashed
+        );
+        assert_eq!(
+            content_hashed_location(&hashed, base_image_id, b"second"),
+            content_hashed_location(&location, base_image_id, b"second")
+        );
+    }
+
+    #[test]
+    fn keeps_smaller_outputs() {
+        let outputs = vec![
+            output(ImageFormat::Jpg, 400, 1000),
+            output(ImageFormat::Webp, 400, 800),
+            output(ImageFormat::Avif, 400, 600),
+        ];
+
+        let dominated = dominated_outputs(&outputs, &original(ImageFormat::Jpg, 5000));
+        assert!(dominated.is_empty());
+    }
+
+    #[test]
+    fn skips_outputs_larger_than_fallback() {
+        let outputs = vec![
+            output(ImageFormat::Jpg, 400, 1000),
+            output(ImageFormat::Webp, 400, 1200),
+            output(ImageFormat::Avif, 400, 600),
+            // A different size, so it isn't compared with the JPEG above.
+            output(ImageFormat::Webp, 200, 1200),
+        ];
+
+        let dominated = dominated_outputs(&outputs, &original(ImageFormat::Jpg, 5000));
+        assert_eq!(dominated, vec![outputs[1].id.unwrap()]);
+    }
+
+    #[test]
+    fn skips_outputs_larger_than_original() {
+        let outputs = vec![
+            output(ImageFormat::Webp, 800, 3000),
+            output(ImageFormat::Avif, 800, 1500),
+            output(ImageFormat::Avif, 400, 3000),
+        ];
+
+        let dominated = dominated_outputs(&outputs, &original(ImageFormat::Png, 2000));
+        assert_eq!(dominated, vec![outputs[0].id.unwrap()]);
+    }
+
+    #[test]
+    fn original_in_new_format_is_not_a_fallback() {
+        let outputs = vec![output(ImageFormat::Avif, 800, 3000)];
+
+        let dominated = dominated_outputs(&outputs, &original(ImageFormat::Heic, 2000));
+        assert!(dominated.is_empty());
+    }
+
+    #[test]
+    fn skips_sizes_larger_than_decoded_image() {
+        let png = ConversionFormat::Png {
+            condition: None,
+            compression: None,
+            palette_colors: None,
+        };
+        let jpg = ConversionFormat::Jpg {
+            quality: None,
+            target_quality: None,
+            condition: None,
+            progressive: None,
+            chroma_subsampling: None,
+        };
+        let conversion = |format: &ConversionFormat, width: u32, density: Option<f32>| {
+            (
+                OutputImageId::new(),
+                String::new(),
+                format.clone(),
+                ConversionSize {
+                    width: Some(width),
+                    density,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // Planned against a 4000x2000 image that was decoded at a quarter of its size.
+        let outputs = vec![
+            conversion(&png, 2000, Some(2.0)),
+            conversion(&png, 1000, Some(1.0)),
+            conversion(&png, 500, None),
+            conversion(&png, 3000, None),
+            conversion(&jpg, 3000, None),
+        ];
+
+        let duplicates = duplicate_outputs(&outputs, (1000, 500), false);
+        assert_eq!(duplicates.len(), 2);
+        assert!(duplicates.contains(&outputs[0].0));
+        assert!(duplicates.contains(&outputs[3].0));
+
+        let duplicates = duplicate_outputs(&outputs, (1000, 500), true);
+        assert!(duplicates.is_empty());
+    }
 }
@@ -0,0 +1,109 @@
+//! Delete output images that were replaced by a newer conversion, once pages and caches have had
+//! time to stop referencing them.
+
+use std::collections::{hash_map::Entry, HashMap};
+
+use chrono::Utc;
+use db::{
+    base_images, image_base_location,
+    object_id::{OutputImageId, StorageLocationId},
+    output_images, projects,
+    storage_locations::{self, Provider},
+    upload_profiles, OutputImageStatus, PoolExt,
+};
+use diesel::prelude::*;
+use pic_store_db as db;
+use pic_store_storage as storage;
+use tracing::{event, instrument, Level};
+
+use super::{JobContext, RunningJob};
+
+#[instrument(skip(_job))]
+pub async fn delete_superseded_outputs_job(
+    _job: RunningJob,
+    context: JobContext,
+) -> Result<(), eyre::Report> {
+    let cutoff = Utc::now() - chrono::Duration::from_std(context.superseded_output_grace)?;
+
+    let superseded = context
+        .pool
+        .interact(move |conn| {
+            output_images::table
+                .inner_join(base_images::table.inner_join(
+                    upload_profiles::table.inner_join(
+                        storage_locations::table.on(
+                            storage_locations::id.eq(upload_profiles::output_storage_location_id),
+                        ),
+                    ),
+                ))
+                .inner_join(projects::table.on(projects::id.eq(base_images::project_id)))
+                .filter(output_images::status.eq(OutputImageStatus::QueuedForDelete))
+                .filter(output_images::updated.lt(cutoff))
+                .select((
+                    output_images::id,
+                    output_images::location,
+                    storage_locations::id,
+                    storage_locations::provider,
+                    storage_locations::base_location,
+                    projects::base_location,
+                    upload_profiles::output_storage_location_path,
+                ))
+                .load::<(
+                    OutputImageId,
+                    String,
+                    StorageLocationId,
+                    Provider,
+                    String,
+                    String,
+                    Option<String>,
+                )>(conn)
+                .map_err(eyre::Report::new)
+        })
+        .await?;
+
+    if superseded.is_empty() {
+        return Ok(());
+    }
+
+    let mut operators = HashMap::new();
+    let mut deleted = Vec::with_capacity(superseded.len());
+    for (id, location, storage_location_id, provider, storage_base, project_base, profile_path) in
+        superseded
+    {
+        let base_location = image_base_location(&storage_base, &project_base, &profile_path);
+        let operator = match operators.entry((storage_location_id, base_location.to_string())) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let operator = storage::Provider::from_db(provider, &context.storage_keys)?
+                    .create_operator(base_location.as_ref())
+                    .await?;
+                entry.insert(operator)
+            }
+        };
+
+        match operator.delete(&location).await {
+            Ok(()) => deleted.push(id),
+            // Outputs that never finished converting have nothing to delete.
+            Err(e) if e.is_not_found() => deleted.push(id),
+            Err(e) => {
+                // Leave the row so that the next run tries again.
+                event!(Level::WARN, output_image=%id, %location, error=?e, "Failed to delete superseded output");
+            }
+        }
+    }
+
+    let count = deleted.len();
+    context
+        .pool
+        .interact(move |conn| {
+            diesel::delete(output_images::table)
+                .filter(output_images::id.eq_any(deleted))
+                .filter(output_images::status.eq(OutputImageStatus::QueuedForDelete))
+                .execute(conn)?;
+            Ok::<_, eyre::Report>(())
+        })
+        .await?;
+
+    event!(Level::INFO, count, "Deleted superseded outputs");
+    Ok(())
+}
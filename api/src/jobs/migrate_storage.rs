@@ -0,0 +1,536 @@
+//! Move every image from one storage location to another, and then point the upload profiles
+//! at the new location.
+//!
+//! The job copies objects in batches ordered by ID, and checkpoints its cursor after each batch,
+//! so a retry picks up where the last try stopped. Once everything is copied it switches the
+//! upload profiles, then makes one more pass over images that changed since the migration
+//! started, to catch uploads and conversions that finished while it ran.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use db::{
+    base_images,
+    object_id::{BaseImageId, OutputImageId, StorageLocationId, UploadProfileId},
+    output_images, projects,
+    secrets::MasterKeys,
+    storage_locations::{self, Provider},
+    upload_profiles, BaseImageStatus, OutputImageStatus, PoolExt,
+};
+use diesel::prelude::*;
+use futures::{stream, StreamExt};
+use pic_store_db as db;
+use pic_store_storage as storage;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::{event, instrument, Level};
+use uuid::Uuid;
+
+use super::{collect_orphans::relative_location, JobContext, RunningJob};
+
+/// The number of objects to copy between checkpoints.
+const BATCH_SIZE: i64 = 100;
+/// The number of objects to copy at once.
+const COPY_CONCURRENCY: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MigrateStorageJobPayload {
+    pub from: StorageLocationId,
+    pub to: StorageLocationId,
+    #[serde(default)]
+    pub progress: StorageMigrationProgress,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationPhase {
+    /// Copying all the existing objects.
+    #[default]
+    Copying,
+    /// The upload profiles have been switched, and objects changed since the start are being
+    /// copied again.
+    CatchingUp,
+    Done,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StorageMigrationProgress {
+    pub phase: MigrationPhase,
+    pub started_at: Option<DateTime<Utc>>,
+    /// Upload profiles that stored base images in the old location when the migration started.
+    pub base_profiles: Option<Vec<UploadProfileId>>,
+    /// Upload profiles that stored output images in the old location when the migration started.
+    pub output_profiles: Option<Vec<UploadProfileId>>,
+    /// The number of objects to copy in the first phase.
+    pub total: Option<i64>,
+    /// The last base image copied in the current phase.
+    pub last_base_image: Option<Uuid>,
+    /// The last output image copied in the current phase.
+    pub last_output_image: Option<Uuid>,
+    pub copied: u64,
+    pub copied_bytes: u64,
+    /// Objects that have a row but weren't found in the old location.
+    pub missing: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ObjectKind {
+    Base,
+    Output,
+}
+
+enum CopyResult {
+    Copied(usize),
+    Missing,
+}
+
+#[instrument(skip(job))]
+pub async fn migrate_storage_job(job: RunningJob, context: JobContext) -> Result<(), eyre::Report> {
+    let mut payload = job.json_payload::<MigrateStorageJobPayload>()?;
+    event!(Level::INFO, from=%payload.from, to=%payload.to, progress=?payload.progress, "Migrating storage");
+
+    if payload.progress.phase == MigrationPhase::Done {
+        return Ok(());
+    }
+
+    let (from, to) = create_operators(
+        &context.pool,
+        &context.storage_keys,
+        payload.from,
+        payload.to,
+    )
+    .await?;
+
+    if payload.progress.started_at.is_none() {
+        start_migration(&context.pool, &mut payload).await?;
+        job.checkpoint_json(&payload).await?;
+    }
+
+    for kind in [ObjectKind::Base, ObjectKind::Output] {
+        copy_objects(&job, &context, &from, &to, &mut payload, kind).await?;
+    }
+
+    if payload.progress.phase == MigrationPhase::Copying {
+        switch_upload_profiles(&context.pool, &payload).await?;
+        payload.progress.phase = MigrationPhase::CatchingUp;
+        payload.progress.last_base_image = None;
+        payload.progress.last_output_image = None;
+        job.checkpoint_json(&payload).await?;
+
+        for kind in [ObjectKind::Base, ObjectKind::Output] {
+            copy_objects(&job, &context, &from, &to, &mut payload, kind).await?;
+        }
+    }
+
+    payload.progress.phase = MigrationPhase::Done;
+    job.checkpoint_json(&payload).await?;
+
+    event!(
+        Level::INFO,
+        from=%payload.from,
+        to=%payload.to,
+        copied=payload.progress.copied,
+        copied_bytes=payload.progress.copied_bytes,
+        missing=payload.progress.missing,
+        "Finished migrating storage"
+    );
+
+    Ok(())
+}
+
+/// Check the storage locations and create operators for them.
+async fn create_operators(
+    pool: &db::Pool,
+    keys: &MasterKeys,
+    from_id: StorageLocationId,
+    to_id: StorageLocationId,
+) -> Result<(storage::Operator, storage::Operator), eyre::Report> {
+    let locations = pool
+        .interact(move |conn| {
+            storage_locations::table
+                .filter(storage_locations::id.eq_any(vec![from_id, to_id]))
+                .filter(storage_locations::deleted.is_null())
+                .select((
+                    storage_locations::id,
+                    storage_locations::team_id,
+                    storage_locations::provider,
+                    storage_locations::base_location,
+                ))
+                .load::<(StorageLocationId, Uuid, Provider, String)>(conn)
+                .map_err(eyre::Report::new)
+        })
+        .await?;
+
+    let find = |id: StorageLocationId| {
+        locations
+            .iter()
+            .find(|location| location.0 == id)
+            .ok_or_else(|| eyre::eyre!("Storage location {id} not found"))
+    };
+
+    let from = find(from_id)?;
+    let to = find(to_id)?;
+    if from_id == to_id {
+        return Err(eyre::eyre!("Can not migrate a storage location to itself"));
+    }
+    if from.1 != to.1 {
+        return Err(eyre::eyre!("Storage locations belong to different teams"));
+    }
+
+    let from_operator = storage::Provider::from_db(from.2.clone(), keys)?
+        .create_operator(&from.3)
+        .await?;
+    let to_operator = storage::Provider::from_db(to.2.clone(), keys)?
+        .create_operator(&to.3)
+        .await?;
+
+    Ok((from_operator, to_operator))
+}
+
+/// Record which upload profiles use the old location and how many objects there are to copy.
+async fn start_migration(
+    pool: &db::Pool,
+    payload: &mut MigrateStorageJobPayload,
+) -> Result<(), eyre::Report> {
+    let from = payload.from;
+    let (base_profiles, output_profiles, total) = pool
+        .interact(move |conn| {
+            let base_profiles = upload_profiles::table
+                .filter(upload_profiles::base_storage_location_id.eq(from))
+                .select(upload_profiles::id)
+                .load::<UploadProfileId>(conn)?;
+            let output_profiles = upload_profiles::table
+                .filter(upload_profiles::output_storage_location_id.eq(from))
+                .select(upload_profiles::id)
+                .load::<UploadProfileId>(conn)?;
+
+            let base_count = base_images::table
+                .filter(base_images::upload_profile_id.eq_any(base_profiles.clone()))
+                .filter(base_images::status.ne_all(vec![
+                    BaseImageStatus::AwaitingUpload,
+                    BaseImageStatus::Deleted,
+                ]))
+                .count()
+                .get_result::<i64>(conn)?;
+            let output_count = output_images::table
+                .inner_join(base_images::table)
+                .filter(base_images::upload_profile_id.eq_any(output_profiles.clone()))
+                .filter(output_images::status.eq_any(vec![
+                    OutputImageStatus::Ready,
+                    OutputImageStatus::QueuedForDelete,
+                ]))
+                .count()
+                .get_result::<i64>(conn)?;
+
+            Ok::<_, eyre::Report>((base_profiles, output_profiles, base_count + output_count))
+        })
+        .await?;
+
+    event!(Level::INFO, total, "Starting storage migration");
+    payload.progress.started_at = Some(Utc::now());
+    payload.progress.base_profiles = Some(base_profiles);
+    payload.progress.output_profiles = Some(output_profiles);
+    payload.progress.total = Some(total);
+    Ok(())
+}
+
+/// Copy the objects of one kind, a batch at a time, starting after the cursor.
+async fn copy_objects(
+    job: &RunningJob,
+    context: &JobContext,
+    from: &storage::Operator,
+    to: &storage::Operator,
+    payload: &mut MigrateStorageJobPayload,
+    kind: ObjectKind,
+) -> Result<(), eyre::Report> {
+    // In the catch-up phase, only objects that changed after the migration started need to be
+    // copied again.
+    let changed_since = match payload.progress.phase {
+        MigrationPhase::CatchingUp => payload.progress.started_at,
+        _ => None,
+    };
+
+    loop {
+        let (profiles, after) = match kind {
+            ObjectKind::Base => (
+                payload.progress.base_profiles.clone().unwrap_or_default(),
+                payload.progress.last_base_image,
+            ),
+            ObjectKind::Output => (
+                payload.progress.output_profiles.clone().unwrap_or_default(),
+                payload.progress.last_output_image,
+            ),
+        };
+
+        let batch = next_batch(&context.pool, kind, profiles, after, changed_since).await?;
+        let last_id = match batch.last() {
+            Some((id, _)) => *id,
+            None => return Ok(()),
+        };
+
+        let results = stream::iter(batch)
+            .map(|(_, location)| {
+                let upload_limit = context.upload_limit.clone();
+                async move { copy_object(from, to, &location, upload_limit).await }
+            })
+            .buffer_unordered(COPY_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+
+        for result in results {
+            match result? {
+                CopyResult::Copied(size) => {
+                    payload.progress.copied += 1;
+                    payload.progress.copied_bytes += size as u64;
+                }
+                CopyResult::Missing => payload.progress.missing += 1,
+            }
+        }
+
+        match kind {
+            ObjectKind::Base => payload.progress.last_base_image = Some(last_id),
+            ObjectKind::Output => payload.progress.last_output_image = Some(last_id),
+        }
+        job.checkpoint_json(&*payload).await?;
+
+        event!(
+            Level::INFO,
+            phase=?payload.progress.phase,
+            copied=payload.progress.copied,
+            missing=payload.progress.missing,
+            total=?payload.progress.total,
+            "Storage migration progress"
+        );
+    }
+}
+
+/// Get the next batch of object IDs and their locations relative to the storage location.
+async fn next_batch(
+    pool: &db::Pool,
+    kind: ObjectKind,
+    profiles: Vec<UploadProfileId>,
+    after: Option<Uuid>,
+    changed_since: Option<DateTime<Utc>>,
+) -> Result<Vec<(Uuid, String)>, eyre::Report> {
+    let rows = pool
+        .interact(move |conn| {
+            let rows = match kind {
+                ObjectKind::Base => {
+                    let mut query = base_images::table
+                        .inner_join(upload_profiles::table)
+                        .inner_join(projects::table.on(projects::id.eq(base_images::project_id)))
+                        .filter(base_images::upload_profile_id.eq_any(profiles))
+                        .filter(base_images::status.ne_all(vec![
+                            BaseImageStatus::AwaitingUpload,
+                            BaseImageStatus::Deleted,
+                        ]))
+                        .select((
+                            base_images::id,
+                            projects::base_location,
+                            upload_profiles::base_storage_location_path,
+                            base_images::location,
+                        ))
+                        .order_by(base_images::id)
+                        .limit(BATCH_SIZE)
+                        .into_boxed();
+
+                    if let Some(after) = after {
+                        query = query.filter(base_images::id.gt(BaseImageId::from_uuid(after)));
+                    }
+                    if let Some(since) = changed_since {
+                        query = query.filter(base_images::updated.ge(since));
+                    }
+
+                    query
+                        .load::<(BaseImageId, String, Option<String>, String)>(conn)?
+                        .into_iter()
+                        .map(|(id, project, profile, location)| {
+                            (id.into_inner(), project, profile, location)
+                        })
+                        .collect::<Vec<_>>()
+                }
+                ObjectKind::Output => {
+                    let mut query = output_images::table
+                        .inner_join(base_images::table.inner_join(upload_profiles::table))
+                        .inner_join(projects::table.on(projects::id.eq(base_images::project_id)))
+                        .filter(base_images::upload_profile_id.eq_any(profiles))
+                        .filter(output_images::status.eq_any(vec![
+                            OutputImageStatus::Ready,
+                            OutputImageStatus::QueuedForDelete,
+                        ]))
+                        .select((
+                            output_images::id,
+                            projects::base_location,
+                            upload_profiles::output_storage_location_path,
+                            output_images::location,
+                        ))
+                        .order_by(output_images::id)
+                        .limit(BATCH_SIZE)
+                        .into_boxed();
+
+                    if let Some(after) = after {
+                        query = query.filter(output_images::id.gt(OutputImageId::from_uuid(after)));
+                    }
+                    if let Some(since) = changed_since {
+                        query = query.filter(output_images::updated.ge(since));
+                    }
+
+                    query
+                        .load::<(OutputImageId, String, Option<String>, String)>(conn)?
+                        .into_iter()
+                        .map(|(id, project, profile, location)| {
+                            (id.into_inner(), project, profile, location)
+                        })
+                        .collect::<Vec<_>>()
+                }
+            };
+
+            Ok::<_, eyre::Report>(rows)
+        })
+        .await?;
+
+    let batch = rows
+        .into_iter()
+        .map(|(id, project_path, profile_path, location)| {
+            let location = relative_location(&[
+                project_path.as_str(),
+                profile_path.as_deref().unwrap_or_default(),
+                location.as_str(),
+            ]);
+            (id, location)
+        })
+        .collect();
+
+    Ok(batch)
+}
+
+/// Copy an object and read it back to make sure that the copy matches.
+async fn copy_object(
+    from: &storage::Operator,
+    to: &storage::Operator,
+    location: &str,
+    upload_limit: Arc<Semaphore>,
+) -> Result<CopyResult, eyre::Report> {
+    let source = match from.get(location).await {
+        Ok(source) => source,
+        Err(e) if e.is_not_found() => {
+            event!(Level::WARN, %location, "Object to migrate does not exist");
+            return Ok(CopyResult::Missing);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // Keep the headers that the object was saved with. Objects saved before attributes were
+    // added get a content type from their extension.
+    let mut attributes = storage::ObjectAttributes::from_object_store(&source.attributes);
+    if attributes.content_type.is_none() {
+        attributes.content_type = storage::content_type_for_location(location).map(String::from);
+    }
+
+    let data = source.bytes().await?;
+    let size = data.len();
+    let hash = blake3::hash(&data);
+
+    let upload_permit = upload_limit.acquire().await?;
+    to.put(location, data, &attributes).await?;
+    drop(upload_permit);
+
+    let copied = to.get(location).await?.bytes().await?;
+    if copied.len() != size {
+        return Err(eyre::eyre!(
+            "Copy of {location} has size {}, expected {size}",
+            copied.len()
+        ));
+    }
+    if blake3::hash(&copied) != hash {
+        return Err(eyre::eyre!(
+            "Copy of {location} does not match the original"
+        ));
+    }
+
+    Ok(CopyResult::Copied(size))
+}
+
+/// Point the upload profiles at the new storage location.
+async fn switch_upload_profiles(
+    pool: &db::Pool,
+    payload: &MigrateStorageJobPayload,
+) -> Result<(), eyre::Report> {
+    let (from, to) = (payload.from, payload.to);
+    let base_profiles = payload.progress.base_profiles.clone().unwrap_or_default();
+    let output_profiles = payload.progress.output_profiles.clone().unwrap_or_default();
+
+    pool.transaction(move |conn| {
+        diesel::update(upload_profiles::table)
+            .filter(upload_profiles::id.eq_any(base_profiles))
+            .filter(upload_profiles::base_storage_location_id.eq(from))
+            .set((
+                upload_profiles::base_storage_location_id.eq(to),
+                upload_profiles::updated.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+
+        diesel::update(upload_profiles::table)
+            .filter(upload_profiles::id.eq_any(output_profiles))
+            .filter(upload_profiles::output_storage_location_id.eq(from))
+            .set((
+                upload_profiles::output_storage_location_id.eq(to),
+                upload_profiles::updated.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+
+        Ok::<_, eyre::Report>(())
+    })
+    .await?;
+
+    event!(Level::INFO, %from, %to, "Switched upload profiles to the new storage location");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn local_operator(dir: &temp_dir::TempDir) -> storage::Operator {
+        storage::Provider::new(storage::ProviderConfig::Local)
+            .create_operator(dir.path().to_str().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn copies_objects_between_locations() {
+        let from_dir = temp_dir::TempDir::new().unwrap();
+        let to_dir = temp_dir::TempDir::new().unwrap();
+        let from = local_operator(&from_dir).await;
+        let to = local_operator(&to_dir).await;
+        let upload_limit = Arc::new(Semaphore::new(1));
+
+        from.put(
+            "project/image.jpg",
+            bytes::Bytes::from_static(b"image data"),
+            &storage::ObjectAttributes::default(),
+        )
+        .await
+        .unwrap();
+
+        let result = copy_object(&from, &to, "project/image.jpg", upload_limit.clone())
+            .await
+            .unwrap();
+        assert!(matches!(result, CopyResult::Copied(10)));
+
+        let copied = to
+            .get("project/image.jpg")
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(copied.as_ref(), b"image data");
+
+        let result = copy_object(&from, &to, "project/missing.jpg", upload_limit)
+            .await
+            .unwrap();
+        assert!(matches!(result, CopyResult::Missing));
+    }
+}
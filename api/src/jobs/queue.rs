@@ -0,0 +1,281 @@
+//! A job queue that can be stored either in a local SQLite file or in the Postgres database.
+
+pub mod postgres;
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use futures::future::BoxFuture;
+use pic_store_db as db;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use self::postgres::{PostgresQueue, PostgresRunningJob, PostgresWorker};
+use super::JobContext;
+use crate::config::{QueueBackend, QueueConfig};
+
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error(transparent)]
+    Sqlite(#[from] effectum::Error),
+
+    #[error("Database Error: {0}")]
+    Db(#[from] diesel::result::Error),
+
+    #[error("Database Pool Error: {0}")]
+    DbPool(#[from] deadpool_diesel::PoolError),
+
+    #[error("Invalid job payload: {0}")]
+    Payload(#[from] serde_json::Error),
+
+    #[error("Job {0} is no longer running on this worker")]
+    JobExpired(Uuid),
+}
+
+/// Retry and timeout settings for a job. The defaults match effectum's defaults, so jobs behave
+/// the same with either queue.
+#[derive(Debug, Clone)]
+pub struct JobOptions {
+    pub priority: i32,
+    /// How many times to retry the job after the first try fails.
+    pub max_retries: u32,
+    pub backoff_initial_interval: Duration,
+    pub backoff_multiplier: f32,
+    /// Randomly vary each backoff by up to this fraction.
+    pub backoff_randomization: f32,
+    /// How long the job may run before it is considered lost and run again. Each checkpoint
+    /// restarts the timeout.
+    pub timeout: Duration,
+}
+
+impl Default for JobOptions {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            max_retries: 3,
+            backoff_initial_interval: Duration::from_secs(20),
+            backoff_multiplier: 2.0,
+            backoff_randomization: 0.2,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting to run, either for the first time or for a retry.
+    Queued,
+    Running,
+    Succeeded,
+    /// Failed on every try.
+    Failed,
+}
+
+/// The status of a job in the queue.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: Uuid,
+    pub job_type: String,
+    pub state: JobState,
+    /// The number of times the job has been started.
+    pub attempts: i32,
+    pub max_retries: i32,
+    /// The error from the most recent failed try.
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub payload: serde_json::Value,
+}
+
+pub enum JobQueue {
+    Sqlite(effectum::Queue),
+    Postgres(PostgresQueue),
+}
+
+impl JobQueue {
+    pub async fn new(config: &QueueConfig, pool: &db::Pool) -> Result<JobQueue, QueueError> {
+        let queue = match config.queue_backend {
+            QueueBackend::Sqlite => {
+                JobQueue::Sqlite(effectum::Queue::new(Path::new(&config.queue_db_path)).await?)
+            }
+            QueueBackend::Postgres => JobQueue::Postgres(PostgresQueue::new(pool.clone())),
+        };
+
+        Ok(queue)
+    }
+
+    /// Add a job with the default options.
+    pub async fn add_json<T: Serialize>(
+        &self,
+        job_type: &str,
+        payload: &T,
+    ) -> Result<Uuid, QueueError> {
+        self.add_json_with_options(job_type, payload, &JobOptions::default())
+            .await
+    }
+
+    pub async fn add_json_with_options<T: Serialize>(
+        &self,
+        job_type: &str,
+        payload: &T,
+        options: &JobOptions,
+    ) -> Result<Uuid, QueueError> {
+        match self {
+            JobQueue::Sqlite(queue) => {
+                let id = effectum::Job::builder(job_type)
+                    .priority(options.priority)
+                    .max_retries(options.max_retries)
+                    .backoff_initial_interval(options.backoff_initial_interval)
+                    .backoff_multiplier(options.backoff_multiplier)
+                    .backoff_randomization(options.backoff_randomization)
+                    .timeout(options.timeout)
+                    .json_payload(payload)?
+                    .add_to(queue)
+                    .await?;
+                Ok(id)
+            }
+            JobQueue::Postgres(queue) => {
+                let payload = serde_json::to_value(payload)?;
+                queue.add(job_type, payload, options).await
+            }
+        }
+    }
+
+    /// Get the status of a job, or `None` if it doesn't exist.
+    pub async fn job_status(&self, id: Uuid) -> Result<Option<JobInfo>, QueueError> {
+        match self {
+            JobQueue::Sqlite(queue) => {
+                let status = match queue.get_job_status(id).await {
+                    Ok(status) => status,
+                    Err(effectum::Error::NotFound) => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+
+                let state = match status.state {
+                    effectum::JobState::Pending => JobState::Queued,
+                    effectum::JobState::Running => JobState::Running,
+                    effectum::JobState::Succeeded => JobState::Succeeded,
+                    effectum::JobState::Failed => JobState::Failed,
+                };
+
+                // Failure info is the error message, stored as a JSON string.
+                let last_error = status
+                    .run_info
+                    .iter()
+                    .rev()
+                    .find(|run| !run.success)
+                    .map(|run| {
+                        serde_json::from_str::<String>(run.info.get())
+                            .unwrap_or_else(|_| run.info.get().to_string())
+                    });
+
+                let finished_runs = status.run_info.len() as i32;
+                Ok(Some(JobInfo {
+                    id,
+                    job_type: status.job_type,
+                    state,
+                    attempts: finished_runs + i32::from(state == JobState::Running),
+                    max_retries: status.max_retries as i32,
+                    last_error,
+                    payload: serde_json::from_slice(&status.payload)
+                        .unwrap_or(serde_json::Value::Null),
+                }))
+            }
+            JobQueue::Postgres(queue) => queue.job_status(id).await,
+        }
+    }
+
+    pub async fn close(&self, timeout: Duration) -> Result<(), QueueError> {
+        match self {
+            JobQueue::Sqlite(queue) => queue.close(timeout).await?,
+            JobQueue::Postgres(_) => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// A job that a worker is running.
+pub enum RunningJob {
+    Sqlite(effectum::RunningJob),
+    Postgres(PostgresRunningJob),
+}
+
+impl RunningJob {
+    pub fn json_payload<T: DeserializeOwned>(&self) -> Result<T, QueueError> {
+        match self {
+            RunningJob::Sqlite(job) => Ok(job.json_payload()?),
+            RunningJob::Postgres(job) => job.json_payload(),
+        }
+    }
+
+    /// Returns true if the queue won't retry the job when this try fails.
+    pub fn is_last_try(&self) -> bool {
+        match self {
+            // effectum counts the retries, so the first try is 0.
+            RunningJob::Sqlite(job) => job.current_try >= job.max_retries,
+            RunningJob::Postgres(job) => job.current_try > job.max_retries,
+        }
+    }
+
+    /// Save updated progress, so that a retry of the job can skip the completed work.
+    pub async fn checkpoint_json<T: Serialize>(&self, payload: &T) -> Result<(), QueueError> {
+        match self {
+            RunningJob::Sqlite(job) => job.checkpoint_json(payload).await?,
+            RunningJob::Postgres(job) => job.checkpoint_json(payload).await?,
+        }
+
+        Ok(())
+    }
+}
+
+pub type JobFn = fn(RunningJob, JobContext) -> BoxFuture<'static, Result<(), eyre::Report>>;
+
+pub enum JobWorker {
+    Sqlite(effectum::Worker),
+    Postgres(PostgresWorker),
+}
+
+impl JobWorker {
+    /// Start running jobs from the queue, using `handlers` to look up the function for each job
+    /// type.
+    pub async fn start(
+        queue: &JobQueue,
+        context: JobContext,
+        handlers: HashMap<&'static str, JobFn>,
+        concurrency: u16,
+    ) -> Result<JobWorker, QueueError> {
+        let worker = match queue {
+            JobQueue::Sqlite(queue) => {
+                let runners = handlers.into_iter().map(|(job_type, handler)| {
+                    effectum::JobRunner::builder(job_type, move |job, context| {
+                        handler(RunningJob::Sqlite(job), context)
+                    })
+                    .build()
+                });
+
+                let worker = effectum::Worker::builder(queue, context)
+                    .jobs(runners)
+                    .max_concurrency(concurrency)
+                    .build()
+                    .await?;
+                JobWorker::Sqlite(worker)
+            }
+            JobQueue::Postgres(queue) => {
+                JobWorker::Postgres(PostgresWorker::start(queue, context, handlers, concurrency))
+            }
+        };
+
+        Ok(worker)
+    }
+
+    /// Stop taking new jobs, and wait up to `timeout` for running jobs to finish.
+    pub async fn unregister(self, timeout: Option<Duration>) -> Result<(), QueueError> {
+        match self {
+            JobWorker::Sqlite(worker) => worker.unregister(timeout).await?,
+            JobWorker::Postgres(worker) => worker.unregister(timeout).await,
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+//! A job queue in the Postgres database, so that any number of processes can share it.
+//!
+//! Workers poll for pending jobs and claim them with `SELECT ... FOR UPDATE SKIP LOCKED`, so
+//! each job goes to only one worker. A running job expires if it isn't checkpointed or finished
+//! within its timeout, and is then retried like any other failure.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use diesel::{
+    dsl::{now, sql},
+    prelude::*,
+    sql_query,
+    sql_types::{Int4, Nullable, Text, Timestamptz},
+};
+use pic_store_db as db;
+use pic_store_db::{jobs, JobStatus, PoolExt};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{watch, Semaphore};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use super::{JobFn, JobInfo, JobOptions, JobState, QueueError, RunningJob};
+use crate::jobs::JobContext;
+
+/// How long to wait before checking for new jobs when the queue is empty.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Retry a job after a backoff, or mark it failed if it has no retries left. This is shared by
+/// failed and expired jobs, and is completed by a `WHERE` clause selecting the jobs.
+const RETRY_OR_FAIL_JOB: &str = r##"UPDATE jobs SET
+  status = CASE WHEN current_try > max_retries
+    THEN 'failed'::job_status ELSE 'pending'::job_status END,
+  run_at = now() + backoff_initial_interval_ms
+    * power(backoff_multiplier, greatest(current_try - 1, 0))
+    * (1 + backoff_randomization * (random() * 2 - 1))
+    * interval '1 millisecond',
+  finished_at = CASE WHEN current_try > max_retries THEN now() END,
+  expires_at = NULL,
+  error = $1
+"##;
+
+/// The expiration time for a running job, starting from now.
+fn expiration() -> diesel::expression::SqlLiteral<Nullable<Timestamptz>> {
+    sql::<Nullable<Timestamptz>>("now() + timeout_ms * interval '1 millisecond'")
+}
+
+#[derive(Clone)]
+pub struct PostgresQueue {
+    pool: db::Pool,
+}
+
+impl PostgresQueue {
+    pub fn new(pool: db::Pool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn add(
+        &self,
+        job_type: &str,
+        payload: serde_json::Value,
+        options: &JobOptions,
+    ) -> Result<Uuid, QueueError> {
+        let job = db::jobs::NewJob {
+            id: Uuid::new_v4(),
+            job_type: job_type.to_string(),
+            priority: options.priority,
+            payload,
+            max_retries: options.max_retries as i32,
+            backoff_initial_interval_ms: options.backoff_initial_interval.as_millis() as i32,
+            backoff_multiplier: options.backoff_multiplier,
+            backoff_randomization: options.backoff_randomization,
+            timeout_ms: options.timeout.as_millis() as i32,
+            run_at: Utc::now(),
+        };
+
+        let id = job.id;
+        self.pool
+            .interact(move |conn| {
+                diesel::insert_into(jobs::table)
+                    .values(&job)
+                    .execute(conn)
+                    .map_err(QueueError::from)
+            })
+            .await?;
+
+        Ok(id)
+    }
+
+    /// Claim up to `limit` jobs that are ready to run.
+    pub async fn claim(
+        &self,
+        job_types: Vec<&'static str>,
+        limit: i64,
+    ) -> Result<Vec<PostgresRunningJob>, QueueError> {
+        let claimed = self
+            .pool
+            .transaction(move |conn| {
+                let ids = jobs::table
+                    .filter(jobs::status.eq(JobStatus::Pending))
+                    .filter(jobs::run_at.le(now))
+                    .filter(jobs::job_type.eq_any(job_types))
+                    .order((jobs::priority.desc(), jobs::run_at.asc()))
+                    .limit(limit)
+                    .select(jobs::id)
+                    .for_update()
+                    .skip_locked()
+                    .load::<Uuid>(conn)?;
+
+                if ids.is_empty() {
+                    return Ok(Vec::new());
+                }
+
+                diesel::update(jobs::table)
+                    .filter(jobs::id.eq_any(ids))
+                    .set((
+                        jobs::status.eq(JobStatus::Running),
+                        jobs::current_try.eq(jobs::current_try + 1),
+                        jobs::started_at.eq(now),
+                        jobs::expires_at.eq(expiration()),
+                    ))
+                    .returning(jobs::all_columns)
+                    .get_results::<db::jobs::Job>(conn)
+                    .map_err(QueueError::from)
+            })
+            .await?;
+
+        let jobs = claimed
+            .into_iter()
+            .map(|job| PostgresRunningJob {
+                id: job.id,
+                job_type: job.job_type,
+                current_try: job.current_try,
+                max_retries: job.max_retries,
+                payload: job.payload,
+                pool: self.pool.clone(),
+            })
+            .collect();
+
+        Ok(jobs)
+    }
+
+    pub async fn job_status(&self, id: Uuid) -> Result<Option<JobInfo>, QueueError> {
+        let job = self
+            .pool
+            .interact(move |conn| {
+                jobs::table
+                    .find(id)
+                    .first::<db::jobs::Job>(conn)
+                    .optional()
+                    .map_err(QueueError::from)
+            })
+            .await?;
+
+        let info = job.map(|job| JobInfo {
+            id: job.id,
+            job_type: job.job_type,
+            state: match job.status {
+                JobStatus::Pending => JobState::Queued,
+                JobStatus::Running => JobState::Running,
+                JobStatus::Succeeded => JobState::Succeeded,
+                JobStatus::Failed => JobState::Failed,
+            },
+            attempts: job.current_try,
+            max_retries: job.max_retries,
+            last_error: job.error,
+            payload: job.payload,
+        });
+
+        Ok(info)
+    }
+
+    /// Retry or fail running jobs that have passed their expiration time, since the worker
+    /// running them has probably gone away.
+    pub async fn expire_jobs(&self) -> Result<usize, QueueError> {
+        let query = format!(
+            "{RETRY_OR_FAIL_JOB} WHERE id IN (
+                SELECT id FROM jobs
+                WHERE status = 'running' AND expires_at <= now()
+                FOR UPDATE SKIP LOCKED
+            )"
+        );
+
+        self.pool
+            .interact(move |conn| {
+                sql_query(query)
+                    .bind::<Text, _>("Job timed out")
+                    .execute(conn)
+                    .map_err(QueueError::from)
+            })
+            .await
+    }
+}
+
+pub struct PostgresRunningJob {
+    pub id: Uuid,
+    pub job_type: String,
+    /// The number of times the job has been started, including this one.
+    pub current_try: i32,
+    /// The job fails once it has been tried more than this many times.
+    pub max_retries: i32,
+    payload: serde_json::Value,
+    pool: db::Pool,
+}
+
+impl PostgresRunningJob {
+    pub fn json_payload<T: DeserializeOwned>(&self) -> Result<T, QueueError> {
+        Ok(T::deserialize(&self.payload)?)
+    }
+
+    /// Save the payload and restart the timeout.
+    pub async fn checkpoint_json<T: Serialize>(&self, payload: &T) -> Result<(), QueueError> {
+        let payload = serde_json::to_value(payload)?;
+        let (id, current_try) = (self.id, self.current_try);
+        let updated = self
+            .pool
+            .interact(move |conn| {
+                diesel::update(jobs::table)
+                    .filter(jobs::id.eq(id))
+                    .filter(jobs::status.eq(JobStatus::Running))
+                    .filter(jobs::current_try.eq(current_try))
+                    .set((jobs::payload.eq(payload), jobs::expires_at.eq(expiration())))
+                    .execute(conn)
+                    .map_err(QueueError::from)
+            })
+            .await?;
+
+        if updated == 0 {
+            return Err(QueueError::JobExpired(id));
+        }
+
+        Ok(())
+    }
+}
+
+/// Record the result of a job, as long as it hasn't expired and been claimed again.
+async fn finish_job(
+    pool: &db::Pool,
+    id: Uuid,
+    current_try: i32,
+    result: Result<(), String>,
+) -> Result<(), QueueError> {
+    pool.interact(move |conn| {
+        match result {
+            Ok(()) => diesel::update(jobs::table)
+                .filter(jobs::id.eq(id))
+                .filter(jobs::status.eq(JobStatus::Running))
+                .filter(jobs::current_try.eq(current_try))
+                .set((
+                    jobs::status.eq(JobStatus::Succeeded),
+                    jobs::finished_at.eq(now),
+                    jobs::expires_at.eq(None::<chrono::DateTime<Utc>>),
+                ))
+                .execute(conn)?,
+            Err(error) => sql_query(format!(
+                "{RETRY_OR_FAIL_JOB} WHERE id = $2 AND status = 'running' AND current_try = $3"
+            ))
+            .bind::<Text, _>(error)
+            .bind::<diesel::sql_types::Uuid, _>(id)
+            .bind::<Int4, _>(current_try)
+            .execute(conn)?,
+        };
+
+        Ok(())
+    })
+    .await
+}
+
+pub struct PostgresWorker {
+    shutdown: watch::Sender<bool>,
+    running: Arc<Semaphore>,
+    concurrency: u32,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl PostgresWorker {
+    pub fn start(
+        queue: &PostgresQueue,
+        context: JobContext,
+        handlers: HashMap<&'static str, JobFn>,
+        concurrency: u16,
+    ) -> Self {
+        let concurrency = concurrency.max(1) as u32;
+        let running = Arc::new(Semaphore::new(concurrency as usize));
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        let task = tokio::spawn(run_worker(
+            queue.clone(),
+            context,
+            Arc::new(handlers),
+            running.clone(),
+            shutdown_rx,
+        ));
+
+        Self {
+            shutdown,
+            running,
+            concurrency,
+            task,
+        }
+    }
+
+    /// Stop claiming jobs, and wait for running jobs to finish. Jobs still running after the
+    /// timeout will expire and run again on another worker.
+    pub async fn unregister(self, timeout: Option<Duration>) {
+        self.shutdown.send(true).ok();
+        self.task.await.ok();
+
+        let finished = self.running.acquire_many(self.concurrency);
+        match timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, finished).await.is_err() {
+                    event!(Level::WARN, "Timed out waiting for running jobs to finish");
+                }
+            }
+            None => {
+                finished.await.ok();
+            }
+        }
+    }
+}
+
+async fn run_worker(
+    queue: PostgresQueue,
+    context: JobContext,
+    handlers: Arc<HashMap<&'static str, JobFn>>,
+    running: Arc<Semaphore>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let job_types = handlers.keys().copied().collect::<Vec<_>>();
+
+    while !*shutdown.borrow() {
+        // Wait for a free slot before looking for jobs.
+        let permit = tokio::select! {
+            permit = running.clone().acquire_owned() => permit.expect("semaphore is never closed"),
+            _ = shutdown.changed() => break,
+        };
+        let available = running.available_permits() + 1;
+
+        if let Err(e) = queue.expire_jobs().await {
+            event!(Level::ERROR, error=?e, "Failed to check for expired jobs");
+        }
+
+        let jobs = match queue.claim(job_types.clone(), available as i64).await {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                event!(Level::ERROR, error=?e, "Failed to claim jobs");
+                Vec::new()
+            }
+        };
+        let queue_drained = jobs.len() < available;
+
+        let mut permit = Some(permit);
+        for job in jobs {
+            let permit = match permit.take() {
+                Some(permit) => permit,
+                None => running
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed"),
+            };
+
+            let handler = handlers[job.job_type.as_str()];
+            let context = context.clone();
+            tokio::spawn(async move {
+                run_job(handler, job, context).await;
+                drop(permit);
+            });
+        }
+        drop(permit);
+
+        if queue_drained {
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {},
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+}
+
+async fn run_job(handler: JobFn, job: PostgresRunningJob, context: JobContext) {
+    let (id, job_type, current_try) = (job.id, job.job_type.clone(), job.current_try);
+    let pool = job.pool.clone();
+
+    // Run the job in its own task so that a panic is recorded as a failure.
+    let result = tokio::spawn(handler(RunningJob::Postgres(job), context))
+        .await
+        .map_err(eyre::Report::new)
+        .and_then(|result| result)
+        .map_err(|e| {
+            event!(Level::ERROR, %id, %job_type, current_try, error=?e, "Job failed");
+            format!("{e:#}")
+        });
+
+    if let Err(e) = finish_job(&pool, id, current_try, result).await {
+        event!(Level::ERROR, %id, %job_type, error=?e, "Failed to record job result");
+    }
+}
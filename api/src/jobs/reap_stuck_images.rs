@@ -0,0 +1,289 @@
+//! Clean up after uploads and conversions that stopped partway, for example because the process
+//! handling them crashed.
+
+use chrono::Utc;
+use db::{
+    base_images, image_base_location,
+    object_id::{BaseImageId, OutputImageId},
+    output_images, projects,
+    storage_locations::{self, Provider},
+    upload_profiles, BaseImageStatus, OutputImageStatus, PoolExt,
+};
+use diesel::prelude::*;
+use pic_store_db as db;
+use pic_store_storage as storage;
+use tracing::{event, instrument, Level};
+use uuid::Uuid;
+
+use super::{
+    create_output_images::{mark_failed, requeue_create_output_images},
+    CreateOutputImagesJobPayload, JobContext, JobState, RunningJob,
+};
+
+#[instrument(skip(_job))]
+pub async fn reap_stuck_images_job(
+    _job: RunningJob,
+    context: JobContext,
+) -> Result<(), eyre::Report> {
+    let uploads = reap_stale_uploads(&context).await?;
+    let conversions = reap_stale_conversions(&context).await?;
+
+    if uploads > 0 || conversions > 0 {
+        event!(Level::INFO, uploads, conversions, "Reaped stuck images");
+    }
+
+    Ok(())
+}
+
+/// Abort multipart uploads that were started a long time ago and never finished. Images that
+/// have been waiting for their first upload that long are marked failed, since they have no file
+/// to convert, whether or not their upload got far enough to have an ID.
+async fn reap_stale_uploads(context: &JobContext) -> Result<usize, eyre::Report> {
+    let cutoff = Utc::now() - chrono::Duration::from_std(context.stale_upload_age)?;
+
+    let stale = context
+        .pool
+        .interact(move |conn| {
+            base_images::table
+                .inner_join(upload_profiles::table.inner_join(
+                    storage_locations::table
+                        .on(storage_locations::id.eq(upload_profiles::base_storage_location_id)),
+                ))
+                .inner_join(projects::table.on(projects::id.eq(base_images::project_id)))
+                .filter(
+                    base_images::upload_id
+                        .is_not_null()
+                        .or(base_images::status.eq(BaseImageStatus::AwaitingUpload)),
+                )
+                .filter(base_images::updated.lt(cutoff))
+                .select((
+                    base_images::id,
+                    base_images::location,
+                    base_images::upload_id,
+                    storage_locations::provider,
+                    storage_locations::base_location,
+                    projects::base_location,
+                    upload_profiles::base_storage_location_path,
+                ))
+                .load::<(
+                    BaseImageId,
+                    String,
+                    Option<String>,
+                    Provider,
+                    String,
+                    String,
+                    Option<String>,
+                )>(conn)
+                .map_err(eyre::Report::new)
+        })
+        .await?;
+
+    let mut reaped = 0;
+    for (
+        base_image_id,
+        location,
+        upload_id,
+        provider,
+        storage_base_location,
+        project_base_location,
+        profile_path,
+    ) in stale
+    {
+        if let Some(upload_id) = upload_id.as_ref() {
+            let base_location = image_base_location(
+                &storage_base_location,
+                &project_base_location,
+                &profile_path,
+            );
+
+            let aborted = async {
+                let operator = storage::Provider::from_db(provider, &context.storage_keys)?
+                    .create_operator(base_location.as_ref())
+                    .await?;
+                operator.abort_multipart(&location, upload_id).await?;
+                Ok::<_, eyre::Report>(())
+            }
+            .await;
+
+            // Keep the upload ID so that the next run can try again.
+            if let Err(e) = aborted {
+                event!(Level::WARN, base_image=%base_image_id, %upload_id, error=?e, "Failed to abort stale upload");
+                continue;
+            }
+        }
+
+        let saved_upload_id = upload_id.clone();
+        context
+            .pool
+            .transaction(move |conn| {
+                if let Some(upload_id) = saved_upload_id {
+                    // Only touch the image if another upload hasn't started in the meantime.
+                    let updated = diesel::update(base_images::table)
+                        .filter(base_images::id.eq(base_image_id))
+                        .filter(base_images::upload_id.eq(upload_id))
+                        .set(base_images::upload_id.eq(None::<String>))
+                        .execute(conn)?;
+                    if updated == 0 {
+                        return Ok(());
+                    }
+                }
+
+                // Skip images whose upload started or finished since they were loaded.
+                diesel::update(base_images::table)
+                    .filter(base_images::id.eq(base_image_id))
+                    .filter(base_images::status.eq(BaseImageStatus::AwaitingUpload))
+                    .filter(base_images::upload_id.is_null())
+                    .filter(base_images::updated.lt(cutoff))
+                    .set((
+                        base_images::status.eq(BaseImageStatus::Failed),
+                        base_images::conversion_error.eq("The upload did not finish"),
+                        base_images::updated.eq(diesel::dsl::now),
+                    ))
+                    .execute(conn)?;
+
+                Ok::<_, eyre::Report>(())
+            })
+            .await?;
+
+        match upload_id {
+            Some(upload_id) => {
+                event!(Level::INFO, base_image=%base_image_id, %upload_id, "Aborted stale upload")
+            }
+            None => {
+                event!(Level::INFO, base_image=%base_image_id, "Failed image that was never uploaded")
+            }
+        }
+        reaped += 1;
+    }
+
+    Ok(reaped)
+}
+
+/// Find images that have been converting for a long time without a job working on them, and
+/// either queue a new conversion job or mark them failed if they are out of retries.
+async fn reap_stale_conversions(context: &JobContext) -> Result<usize, eyre::Report> {
+    let cutoff = Utc::now() - chrono::Duration::from_std(context.stale_conversion_age)?;
+
+    let stale = context
+        .pool
+        .interact(move |conn| {
+            base_images::table
+                .filter(base_images::status.eq(BaseImageStatus::Converting))
+                .filter(base_images::deleted.is_null())
+                .filter(base_images::updated.lt(cutoff))
+                .select((
+                    base_images::id,
+                    base_images::conversion_job_id,
+                    base_images::conversion_attempts,
+                ))
+                .load::<(BaseImageId, Option<Uuid>, i32)>(conn)
+                .map_err(eyre::Report::new)
+        })
+        .await?;
+
+    let mut reaped = 0;
+    for (base_image_id, job_id, attempts) in stale {
+        // A queued job may be waiting out a retry backoff, and a running job will expire on its
+        // own if its worker went away, so leave those to the queue.
+        if let Some(job_id) = job_id {
+            let job = context.queue.job_status(job_id).await?;
+            if matches!(
+                job.map(|job| job.state),
+                Some(JobState::Queued | JobState::Running)
+            ) {
+                continue;
+            }
+        }
+
+        let (remaining, any_failed) = context
+            .pool
+            .interact(move |conn| {
+                let outputs = output_images::table
+                    .filter(output_images::base_image_id.eq(base_image_id))
+                    .select((output_images::id, output_images::status))
+                    .load::<(OutputImageId, OutputImageStatus)>(conn)?;
+
+                let remaining = outputs
+                    .iter()
+                    .filter(|(_, status)| {
+                        matches!(
+                            status,
+                            OutputImageStatus::Queued | OutputImageStatus::Converting
+                        )
+                    })
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                let any_failed = outputs
+                    .iter()
+                    .any(|(_, status)| *status == OutputImageStatus::Failed);
+
+                Ok::<_, eyre::Report>((remaining, any_failed))
+            })
+            .await?;
+
+        if remaining.is_empty() {
+            // The outputs all finished, but the job stopped before updating the base image.
+            let status = if any_failed {
+                BaseImageStatus::Failed
+            } else {
+                BaseImageStatus::Ready
+            };
+
+            context
+                .pool
+                .interact(move |conn| {
+                    diesel::update(base_images::table)
+                        .filter(base_images::id.eq(base_image_id))
+                        .filter(base_images::status.eq(BaseImageStatus::Converting))
+                        .set((
+                            base_images::status.eq(status),
+                            base_images::updated.eq(diesel::dsl::now),
+                        ))
+                        .execute(conn)?;
+                    Ok::<_, eyre::Report>(())
+                })
+                .await?;
+        } else if attempts as u32 > context.conversion_job_options.max_retries {
+            let payload = CreateOutputImagesJobPayload {
+                base_image: base_image_id,
+                outputs: remaining.clone(),
+                conversions: remaining,
+            };
+            mark_failed(
+                context,
+                &payload,
+                "The conversion did not finish".to_string(),
+            )
+            .await?;
+        } else {
+            let requeued = remaining.clone();
+            context
+                .pool
+                .interact(move |conn| {
+                    diesel::update(output_images::table)
+                        .filter(output_images::id.eq_any(requeued))
+                        .set((
+                            output_images::status.eq(OutputImageStatus::Queued),
+                            output_images::updated.eq(diesel::dsl::now),
+                        ))
+                        .execute(conn)?;
+                    Ok::<_, eyre::Report>(())
+                })
+                .await?;
+
+            let job_id = requeue_create_output_images(
+                &context.pool,
+                &context.queue,
+                &context.conversion_job_options,
+                base_image_id,
+                remaining,
+            )
+            .await?;
+            event!(Level::INFO, base_image=%base_image_id, %job_id, "Requeued stuck conversion");
+        }
+
+        reaped += 1;
+    }
+
+    Ok(reaped)
+}
@@ -17,7 +17,6 @@ use hyper::server::conn::AddrIncoming;
 use pic_store_db::object_id::{ProjectId, TeamId, UserId};
 use std::{
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
@@ -44,7 +43,8 @@ pub struct Server {
     pub port: u16,
     pub server: axum::Server<AddrIncoming, IntoMakeService<Router>>,
     pub state: Arc<InnerState>,
-    pub worker: effectum::Worker,
+    /// The background job worker, unless it runs in a separate process.
+    pub worker: Option<jobs::Worker>,
 }
 
 impl Server {
@@ -72,9 +72,11 @@ impl Server {
             shutdown_rx.await;
             internal_shutdown_tx.send(()).ok();
 
-            event!(Level::INFO, "Shutting down background jobs");
-            if let Err(e) = self.worker.unregister(Some(Duration::from_secs(10))).await {
-                event!(Level::ERROR, "Failed to shut down queue worker: {}", e);
+            if let Some(worker) = self.worker {
+                event!(Level::INFO, "Shutting down background jobs");
+                if let Err(e) = worker.unregister(Some(Duration::from_secs(10))).await {
+                    event!(Level::ERROR, "Failed to shut down queue worker: {}", e);
+                }
             }
         });
 
@@ -96,14 +98,37 @@ pub async fn create_server(config: config::Config) -> Result<Server, eyre::Repor
 
     let production = config.env != "development" && !cfg!(debug_assertions);
 
-    let (queue, worker) = jobs::create_job_queue(&PathBuf::from(config.queue_db_path), db.clone())
+    let queue = jobs::JobQueue::new(&config.queue, &db)
         .await
         .map_err(|e| eyre::eyre!("Failed to create job queue: {}", e))?;
+    let queue = Arc::new(queue);
+    let conversion_job_options = config.conversion_job.job_options();
+    let storage_keys = Arc::new(config.storage_keys.master_keys()?);
+
+    let worker = if config.no_worker {
+        None
+    } else {
+        Some(
+            jobs::create_worker(
+                &queue,
+                db.clone(),
+                storage_keys.clone(),
+                &config.worker,
+                conversion_job_options.clone(),
+            )
+            .await?,
+        )
+    };
 
     let state = Arc::new(InnerState {
         production,
+        allow_memory_storage: !production || config.allow_memory_storage,
+        allow_storage_emulators: !production || config.allow_storage_emulators,
+        allow_private_urls: !production || config.allow_private_urls,
         db: db.clone(),
         queue,
+        storage_keys,
+        conversion_job_options,
         // Temporary hardcoded values
         project_id: std::env::var("DEFAULT_PROJECT_ID")
             .expect("DEFAULT_PROJECT_ID")
@@ -12,6 +12,8 @@ struct Args {
 enum Commands {
     #[clap(about = "Run the server")]
     Server(pic_store_api::config::Config),
+    #[clap(about = "Run background jobs without the server")]
+    Worker(pic_store_api::config::WorkerCommandConfig),
     #[clap(about = "Administration and bootstrapping commands")]
     Admin(cmd::admin::AdminArgs),
 }
@@ -24,7 +26,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cmd = Args::parse();
     match cmd.command {
         Commands::Server(config) => cmd::server::run(config).await?,
-        Commands::Admin(cmd) => cmd::admin::admin_commands(cmd)?,
+        Commands::Worker(config) => cmd::worker::run(config).await?,
+        Commands::Admin(cmd) => cmd::admin::admin_commands(cmd).await?,
     };
 
     Ok(())
@@ -12,11 +12,15 @@ use serde_json::json;
 
 use db::{
     conversion_profiles,
-    conversion_profiles::{ConversionOutput, ConversionProfile, NewConversionProfile},
+    conversion_profiles::{
+        ChromaSubsampling, ConversionFormat, ConversionOutput, ConversionProfile, ConversionSize,
+        NewConversionProfile,
+    },
     object_id::{ConversionProfileId, ProjectId},
     permissions::ProjectPermission,
     Permission,
 };
+use pic_store_convert as convert;
 use pic_store_db as db;
 
 use crate::{
@@ -52,6 +56,56 @@ impl From<ConversionProfile> for ConversionProfileOutput {
     }
 }
 
+/// Check that this server can produce every output in a profile, so that unsupported settings
+/// are rejected up front instead of failing in the conversion job.
+fn validate_format(format: &ConversionFormat) -> Result<(), String> {
+    match format {
+        ConversionFormat::Png {
+            palette_colors: Some(colors),
+            ..
+        } if !(2..=256).contains(colors) => Err(format!(
+            "PNG palette must have 2 to 256 colors, not {colors}"
+        )),
+        ConversionFormat::Avif {
+            bit_depth: Some(depth),
+            ..
+        } if *depth != 8 && *depth != 10 => {
+            Err(format!("AVIF bit depth must be 8 or 10, not {depth}"))
+        }
+        ConversionFormat::Avif {
+            chroma_subsampling: Some(subsampling),
+            ..
+        } if *subsampling != ChromaSubsampling::Yuv444
+            && !convert::write_format::avif_subsampling_available() =>
+        {
+            Err("AVIF chroma subsampling other than 444 is not supported by this server".into())
+        }
+        ConversionFormat::Heic { .. } if !convert::write_format::heic_encoder_available() => {
+            Err("HEIC output is not supported by this server".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `density` is only set on the sizes expanded from `densities`, so profiles can't set it.
+fn validate_size(size: &ConversionSize) -> Result<(), String> {
+    if size.density.is_some() {
+        return Err("Sizes can't set density directly, use densities instead".into());
+    }
+
+    Ok(())
+}
+
+fn validate_output(output: &ConversionOutput) -> Result<(), Error> {
+    match output {
+        ConversionOutput::Cross { formats, sizes, .. } => sizes
+            .iter()
+            .try_for_each(validate_size)
+            .and_then(|_| formats.iter().try_for_each(validate_format))
+            .map_err(Error::InvalidConversionProfile),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct ProjectConversionProfilePath {
     project_id: ProjectId,
@@ -124,6 +178,8 @@ async fn write_profile(
     profile_id: ConversionProfileId,
     body: ConversionProfileInput,
 ) -> Result<impl IntoResponse, Error> {
+    validate_output(&body.output)?;
+
     let result = write_object!(
         conversion_profiles,
         state,
@@ -166,6 +222,8 @@ async fn new_profile(
     project_id: Option<ProjectId>,
     body: ConversionProfileInput,
 ) -> Result<impl IntoResponse, Error> {
+    validate_output(&body.output)?;
+
     let value = NewConversionProfile {
         id: ConversionProfileId::new(),
         name: body.name,
@@ -291,3 +349,75 @@ pub fn configure() -> Router<AppState> {
 
     global_router.merge(project_router)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(format: ConversionFormat) -> ConversionOutput {
+        ConversionOutput::Cross {
+            formats: vec![format],
+            sizes: vec![ConversionSize::default()],
+            allow_upscale: false,
+            rasterize_svg: false,
+        }
+    }
+
+    #[test]
+    fn accepts_valid_formats() {
+        let format = ConversionFormat::Png {
+            condition: None,
+            compression: None,
+            palette_colors: Some(256),
+        };
+        assert!(validate_output(&output(format)).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let format = ConversionFormat::Png {
+            condition: None,
+            compression: None,
+            palette_colors: Some(1000),
+        };
+        assert!(matches!(
+            validate_output(&output(format)),
+            Err(Error::InvalidConversionProfile(_))
+        ));
+
+        let format = ConversionFormat::Avif {
+            quality: None,
+            target_quality: None,
+            condition: None,
+            speed: None,
+            chroma_subsampling: None,
+            bit_depth: Some(12),
+        };
+        assert!(matches!(
+            validate_output(&output(format)),
+            Err(Error::InvalidConversionProfile(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_density_without_densities() {
+        let output = ConversionOutput::Cross {
+            formats: vec![ConversionFormat::Png {
+                condition: None,
+                compression: None,
+                palette_colors: None,
+            }],
+            sizes: vec![ConversionSize {
+                width: Some(200),
+                density: Some(2.0),
+                ..Default::default()
+            }],
+            allow_upscale: false,
+            rasterize_svg: false,
+        };
+        assert!(matches!(
+            validate_output(&output),
+            Err(Error::InvalidConversionProfile(_))
+        ));
+    }
+}
@@ -0,0 +1,147 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use db::{
+    base_images,
+    object_id::{BaseImageId, OutputImageId},
+    output_images, BaseImageStatus, OutputImageStatus, PoolExt,
+};
+use diesel::prelude::*;
+use http::StatusCode;
+use pic_store_db as db;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    auth::{Authenticated, UserInfo},
+    jobs::JobInfo,
+    shared_state::AppState,
+    Error, Result,
+};
+
+#[derive(Debug, Queryable, Selectable, Serialize)]
+#[diesel(table_name = output_images)]
+pub struct OutputImageProgress {
+    pub id: OutputImageId,
+    pub location: String,
+    pub status: OutputImageStatus,
+    /// The most recent error from creating the image.
+    pub error: Option<String>,
+    pub attempts: i32,
+    pub updated: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversionStatus {
+    pub base_image_id: BaseImageId,
+    pub base_image_status: BaseImageStatus,
+    /// The number of times the conversion job has run for this image.
+    pub attempts: i32,
+    /// The most recent error from the conversion job.
+    pub error: Option<String>,
+    /// The job creating the output images, if it is still in the queue.
+    pub job: Option<JobInfo>,
+    /// The number of outputs that are done, out of `total`.
+    pub finished: usize,
+    pub total: usize,
+    pub outputs: Vec<OutputImageProgress>,
+}
+
+/// Get the progress of creating output images for a base image. When `job` is given, only the
+/// outputs that the job was created for are included. Otherwise the image's most recent
+/// conversion job is used, and all of its current outputs are included.
+pub async fn conversion_status(
+    state: &AppState,
+    user: UserInfo,
+    image_id: BaseImageId,
+    job: Option<(JobInfo, Vec<OutputImageId>)>,
+) -> Result<ConversionStatus> {
+    let (job, job_outputs) = job.unzip();
+    let ((base_image_status, conversion_job_id, attempts, error), outputs) = state
+        .db
+        .interact(move |conn| {
+            let (info, allowed) = base_images::table
+                .filter(base_images::id.eq(image_id))
+                .filter(base_images::deleted.is_null())
+                .filter(base_images::team_id.eq(user.team_id))
+                .select((
+                    (
+                        base_images::status,
+                        base_images::conversion_job_id,
+                        base_images::conversion_attempts,
+                        base_images::conversion_error,
+                    ),
+                    db::obj_allowed!(
+                        user.team_id,
+                        &user.roles,
+                        base_images::project_id.assume_not_null(),
+                        db::Permission::ProjectRead
+                    ),
+                ))
+                .first::<((BaseImageStatus, Option<Uuid>, i32, Option<String>), bool)>(conn)
+                .optional()?
+                .ok_or(Error::NotFound)?;
+
+            if !allowed {
+                return Err(Error::NotFound);
+            }
+
+            let mut query = output_images::table
+                .filter(output_images::base_image_id.eq(image_id))
+                .select(OutputImageProgress::as_select())
+                .order_by(output_images::location)
+                .into_boxed();
+
+            // A job's own outputs are kept even once a newer conversion replaces them.
+            query = match job_outputs {
+                Some(ids) => query.filter(output_images::id.eq_any(ids)),
+                None => query.filter(output_images::status.ne_all(vec![
+                    OutputImageStatus::QueuedForDelete,
+                    OutputImageStatus::Deleted,
+                ])),
+            };
+
+            let outputs = query.load::<OutputImageProgress>(conn)?;
+
+            Ok((info, outputs))
+        })
+        .await?;
+
+    let job = match (job, conversion_job_id) {
+        (Some(job), _) => Some(job),
+        (None, Some(job_id)) => state.queue.job_status(job_id).await?,
+        (None, None) => None,
+    };
+
+    let finished = outputs
+        .iter()
+        .filter(|o| {
+            matches!(
+                o.status,
+                OutputImageStatus::Ready | OutputImageStatus::Skipped
+            )
+        })
+        .count();
+
+    Ok(ConversionStatus {
+        base_image_id: image_id,
+        base_image_status,
+        attempts,
+        error,
+        job,
+        finished,
+        total: outputs.len(),
+        outputs,
+    })
+}
+
+pub async fn get_conversion_status(
+    State(state): State<AppState>,
+    Authenticated(user): Authenticated,
+    Path(image_id): Path<BaseImageId>,
+) -> Result<impl IntoResponse> {
+    let status = conversion_status(&state, user, image_id, None).await?;
+    Ok((StatusCode::OK, Json(status)))
+}
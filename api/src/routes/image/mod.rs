@@ -1,3 +1,5 @@
+pub mod conversion;
+mod srcset;
 mod upload;
 
 use axum::{
@@ -14,8 +16,8 @@ use db::{
     image_path,
     object_id::{BaseImageId, OutputImageId, ProjectId, TeamId, UploadProfileId},
     output_images::{self, NewOutputImage},
-    projects, storage_locations, upload_profiles, BaseImageStatus, ImageFormat, OutputImageStatus,
-    Permission, PoolExt,
+    projects, storage_locations, upload_profiles, BaseImageStatus, ImageFormat, OutputFilenameHash,
+    OutputImageStatus, Permission, PoolExt,
 };
 use diesel::{prelude::*, upsert::excluded};
 use http::StatusCode;
@@ -23,10 +25,13 @@ use pic_store_db as db;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tracing::{event, Level};
+use uuid::Uuid;
 
+use self::srcset::{build_srcsets, SrcSet, SrcSetCandidate};
 use crate::{
     auth::{Authenticated, UserInfo},
     get_object_by_field_query, get_object_query,
+    jobs::size_transform,
     shared_state::AppState,
     Error, Result,
 };
@@ -177,6 +182,7 @@ async fn get_base_image(
         pub height: Option<i32>,
         pub size: ConversionSize,
         pub format: ConversionFormat,
+        pub quality: Option<f32>,
 
         pub status: OutputImageStatus,
         pub updated: chrono::DateTime<chrono::Utc>,
@@ -322,6 +328,7 @@ async fn get_base_image(
         pub height: Option<i32>,
         pub size_rule: ConversionSize,
         pub format: ImageFormat,
+        pub quality: Option<f32>,
 
         pub status: OutputImageStatus,
 
@@ -348,6 +355,7 @@ async fn get_base_image(
         pub updated: chrono::DateTime<chrono::Utc>,
 
         pub output: Vec<OutputImageResult>,
+        pub srcset: Vec<SrcSet>,
     }
 
     let base_image_path = image_path(
@@ -388,12 +396,25 @@ async fn get_base_image(
                 height: o.height,
                 size_rule: o.size,
                 format: o.format.as_db_image_format(),
+                quality: o.quality,
                 status: o.status,
                 updated: o.updated,
             }
         })
         .collect::<Vec<_>>();
 
+    let srcset = build_srcsets(
+        output_images
+            .iter()
+            .filter(|o| o.status == OutputImageStatus::Ready)
+            .map(|o| SrcSetCandidate {
+                format: o.format,
+                url: &o.url,
+                width: o.width,
+                density: o.size_rule.density,
+            }),
+    );
+
     let result = Result {
         id: info.id,
         project_id: info.project_id,
@@ -411,6 +432,7 @@ async fn get_base_image(
         placeholder: info.placeholder,
         updated: info.updated,
         output: output_images,
+        srcset,
     };
 
     Ok((StatusCode::OK, Json(result)))
@@ -421,14 +443,26 @@ async fn reconvert_base_image(
     Authenticated(user): Authenticated,
     Path(image_id): Path<BaseImageId>,
 ) -> impl IntoResponse {
-    let (base_image_id, base_image_location, base_image_format, conversion_profile) = state
+    let (
+        base_image_id,
+        base_image_location,
+        base_image_format,
+        base_image_size,
+        base_image_hash,
+        conversion_profile,
+        filename_hash,
+    ) = state
         .db
         .interact(move |conn| {
             let (
                 base_image_id,
                 base_image_location,
                 base_image_format,
+                base_image_width,
+                base_image_height,
+                base_image_hash,
                 conversion_profile,
+                filename_hash,
                 allowed,
             ) = base_images::table
                 .filter(base_images::id.eq(image_id))
@@ -439,7 +473,11 @@ async fn reconvert_base_image(
                     base_images::id,
                     base_images::location,
                     base_images::format,
+                    base_images::width,
+                    base_images::height,
+                    base_images::hash,
                     conversion_profiles::all_columns,
+                    upload_profiles::output_filename_hash,
                     db::obj_allowed!(
                         user.team_id,
                         &user.roles,
@@ -451,7 +489,11 @@ async fn reconvert_base_image(
                     BaseImageId,
                     String,
                     Option<ImageFormat>,
+                    i32,
+                    i32,
+                    Option<String>,
                     ConversionProfile,
+                    Option<OutputFilenameHash>,
                     bool,
                 )>(conn)
                 .optional()?
@@ -465,7 +507,10 @@ async fn reconvert_base_image(
                 base_image_id,
                 base_image_location,
                 base_image_format,
+                (base_image_width as u32, base_image_height as u32),
+                base_image_hash,
                 conversion_profile,
+                filename_hash,
             ))
         })
         .await?;
@@ -477,12 +522,18 @@ async fn reconvert_base_image(
         ));
     };
 
-    let output_images = generate_output_images(
+    let mut output_images = generate_output_images(
         user.team_id,
         &conversion_profile,
         base_image_id,
         &base_image_location,
         base_image_format,
+        base_image_size,
+    );
+    hash_output_parameters(
+        &mut output_images,
+        filename_hash,
+        base_image_hash.as_deref().unwrap_or_default(),
     );
 
     if output_images.is_empty() {
@@ -494,19 +545,66 @@ async fn reconvert_base_image(
         .transaction(move |conn| replace_output_images(conn, user.team_id, image_id, output_images))
         .await?;
 
-    let job_id = effectum::Job::builder(crate::jobs::CREATE_OUTPUT_IMAGES)
-        .json_payload(&crate::jobs::CreateOutputImagesJobPayload {
-            base_image: image_id,
-            conversions: output_image_ids.clone(),
-        })?
-        .add_to(&state.queue)
-        .await?;
+    let job_id = queue_conversion_job(&state, image_id, output_image_ids.clone()).await?;
 
     event!(Level::INFO, %job_id, "enqueued image conversion job");
 
     Ok::<_, Error>((StatusCode::OK, Json(json!({ "images": output_image_ids }))))
 }
 
+/// Queue a new conversion job for only the outputs that failed.
+async fn retry_failed_outputs(
+    State(state): State<AppState>,
+    Authenticated(user): Authenticated,
+    Path(image_id): Path<BaseImageId>,
+) -> Result<impl IntoResponse> {
+    let output_image_ids = state
+        .db
+        .transaction(move |conn| {
+            let allowed = base_images::table
+                .filter(base_images::id.eq(image_id))
+                .filter(base_images::deleted.is_null())
+                .filter(base_images::team_id.eq(user.team_id))
+                .select(db::obj_allowed!(
+                    user.team_id,
+                    &user.roles,
+                    base_images::project_id.assume_not_null(),
+                    db::Permission::ImageEdit
+                ))
+                .first::<bool>(conn)
+                .optional()?
+                .ok_or(Error::NotFound)?;
+
+            if !allowed {
+                return Err(Error::MissingPermission(Permission::ImageEdit));
+            }
+
+            diesel::update(output_images::table)
+                .filter(output_images::base_image_id.eq(image_id))
+                .filter(output_images::status.eq(OutputImageStatus::Failed))
+                .set((
+                    output_images::status.eq(OutputImageStatus::Queued),
+                    output_images::updated.eq(diesel::dsl::now),
+                ))
+                .returning(output_images::id)
+                .get_results::<OutputImageId>(conn)
+                .map_err(Error::from)
+        })
+        .await?;
+
+    if output_image_ids.is_empty() {
+        return Ok((StatusCode::OK, Json(json!({ "images": [] }))));
+    }
+
+    let job_id = queue_conversion_job(&state, image_id, output_image_ids.clone()).await?;
+    event!(Level::INFO, %job_id, count = output_image_ids.len(), "enqueued retry of failed outputs");
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "job_id": job_id, "images": output_image_ids })),
+    ))
+}
+
 async fn remove_base_image() -> impl IntoResponse {
     // Set the base image to deleting, and queue jobs to delete
     // all the output images.
@@ -523,6 +621,7 @@ fn generate_output_images(
     base_image_id: BaseImageId,
     base_image_location: &str,
     base_image_format: ImageFormat,
+    base_image_size: (u32, u32),
 ) -> Vec<NewOutputImage> {
     let basename = match base_image_location.rsplit_once('.') {
         Some((base, _ext)) => base,
@@ -530,44 +629,151 @@ fn generate_output_images(
     };
 
     let output_images = match &conversion_profile.output {
-        ConversionOutput::Cross { formats, sizes, .. } => formats
-            .iter()
-            .filter(|format| format.matches_condition(base_image_format))
-            .flat_map(|format| {
-                sizes.iter().map(|size| {
-                    let size_str = match (size.width, size.height) {
-                        (Some(w), Some(h)) => format!("{w}x{h}"),
-                        (Some(w), None) => format!("w{w}"),
-                        (None, Some(h)) => format!("h{h}"),
-                        (None, None) => "szun".to_string(),
+        ConversionOutput::Cross {
+            formats,
+            sizes,
+            allow_upscale,
+            rasterize_svg,
+        } => {
+            let svg_input = base_image_format == ImageFormat::Svg;
+            // Vector images can be rendered at any size without losing quality.
+            let sizes = plan_sizes(sizes, base_image_size, *allow_upscale || svg_input);
+            formats
+                .iter()
+                .filter(|format| format.matches_condition(base_image_format))
+                .flat_map(|format| {
+                    let sizes = match format {
+                        // SVG output is a copy of the original, so only one is needed.
+                        ConversionFormat::Svg { .. } if svg_input => {
+                            vec![ConversionSize::default()]
+                        }
+                        ConversionFormat::Svg { .. } => Vec::new(),
+                        _ if svg_input && !*rasterize_svg => Vec::new(),
+                        _ => sizes.clone(),
                     };
 
-                    let output_image_id = OutputImageId::new();
-                    let location = format!(
-                        "{basename}-{size_str}-{}.{}",
-                        base_image_id.display_without_prefix(),
-                        format.extension()
-                    );
-
-                    NewOutputImage {
-                        id: output_image_id,
-                        base_image_id,
-                        width: None,
-                        height: None,
-                        size: size.clone(),
-                        format: format.clone(),
-                        team_id,
-                        status: db::OutputImageStatus::Queued,
-                        location,
-                    }
+                    sizes.into_iter().map(move |size| {
+                        let mut size_str = match (size.width, size.height) {
+                            (Some(w), Some(h)) => format!("{w}x{h}"),
+                            (Some(w), None) => format!("w{w}"),
+                            (None, Some(h)) => format!("h{h}"),
+                            (None, None) => "szun".to_string(),
+                        };
+
+                        if let Some(density) = size.density {
+                            size_str = format!("{size_str}-{density}x");
+                        }
+
+                        let output_image_id = OutputImageId::new();
+                        let location = format!(
+                            "{basename}-{size_str}-{}.{}",
+                            base_image_id.display_without_prefix(),
+                            format.extension()
+                        );
+
+                        NewOutputImage {
+                            id: output_image_id,
+                            base_image_id,
+                            width: None,
+                            height: None,
+                            size,
+                            format: format.clone(),
+                            team_id,
+                            status: db::OutputImageStatus::Queued,
+                            location,
+                        }
+                    })
                 })
-            })
-            .collect::<Vec<_>>(),
+                .collect::<Vec<_>>()
+        }
     };
 
     output_images
 }
 
+/// Add the hash of each output's settings to its location, if the upload profile uses parameter
+/// hashes. Content hashes are added by the conversion job, once the output has been encoded.
+fn hash_output_parameters(
+    output_images: &mut [NewOutputImage],
+    filename_hash: Option<OutputFilenameHash>,
+    base_image_hash: &str,
+) {
+    if filename_hash != Some(OutputFilenameHash::Parameters) {
+        return;
+    }
+
+    for output in output_images {
+        // The base image is included since uploading a new version keeps the same settings.
+        let parameters = serde_json::to_vec(&(base_image_hash, &output.format, &output.size))
+            .expect("serializing conversion parameters");
+        output.location = output_images::hashed_location(&output.location, &parameters);
+    }
+}
+
+/// Figure out the sizes to generate for an image.
+///
+/// Density-based sizes are expanded into one size per density. Unless upscaling is allowed,
+/// densities that would upscale past the original image are skipped, so an image smaller than
+/// the element's lowest density gets no outputs for that size. Other sizes larger than the
+/// original are collapsed into a single output at the original size.
+fn plan_sizes(
+    sizes: &[ConversionSize],
+    base_image_size: (u32, u32),
+    allow_upscale: bool,
+) -> Vec<ConversionSize> {
+    let (base_width, base_height) = base_image_size;
+    // If we don't know the size of the image there's nothing to compare against.
+    let check_upscale = !allow_upscale && base_width > 0 && base_height > 0;
+
+    let upscales = |size: &ConversionSize| {
+        let (w, h) = pic_store_convert::resize::target_size(
+            base_width,
+            base_height,
+            &size_transform(size, allow_upscale),
+        );
+        w > base_width || h > base_height
+    };
+
+    let mut result: Vec<ConversionSize> = Vec::with_capacity(sizes.len());
+    let mut add = |size: ConversionSize| {
+        let exists = result
+            .iter()
+            .any(|s| s.width == size.width && s.height == size.height && s.density == size.density);
+
+        if !exists {
+            result.push(size);
+        }
+    };
+
+    for size in sizes {
+        if size.densities.is_some() {
+            let mut expanded = size.expand_densities();
+            expanded.sort_by(|a, b| {
+                a.density
+                    .partial_cmp(&b.density)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+
+            expanded
+                .into_iter()
+                .filter(|s| !check_upscale || !upscales(s))
+                .for_each(&mut add);
+        } else if check_upscale && upscales(size) {
+            // Collapse to the original size, keeping the entry's other settings. Without a
+            // height, the original aspect ratio is kept.
+            add(ConversionSize {
+                width: Some(base_width),
+                height: None,
+                ..size.clone()
+            });
+        } else {
+            add(size.clone());
+        }
+    }
+
+    result
+}
+
 fn replace_output_images(
     conn: &mut PgConnection,
     team_id: TeamId,
@@ -579,15 +785,17 @@ fn replace_output_images(
         .map(|oi| &oi.location)
         .collect::<Vec<_>>();
 
-    // Set the existing output images to be deleted, but don't delete them yet since the
-    // user may need to transition some other code away that uses it.
-    // (Alternatively, should we just replace the files with the same parameters? I'm leaning
-    // toward that.)
+    // Set the existing output images to be deleted, but don't delete them until the grace period
+    // passes, since pages and caches may still reference them.
     diesel::update(output_images::table)
         .filter(output_images::base_image_id.eq(base_image_id))
         .filter(output_images::team_id.eq(team_id))
         .filter(output_images::location.ne_all(output_image_locations))
-        .set((output_images::status.eq(db::OutputImageStatus::QueuedForDelete),))
+        .filter(output_images::status.ne(db::OutputImageStatus::QueuedForDelete))
+        .set((
+            output_images::status.eq(db::OutputImageStatus::QueuedForDelete),
+            output_images::updated.eq(diesel::dsl::now),
+        ))
         .execute(conn)?;
 
     let output_image_ids = diesel::insert_into(db::output_images::table)
@@ -596,6 +804,8 @@ fn replace_output_images(
         .do_update()
         .set((
             output_images::status.eq(db::OutputImageStatus::Queued),
+            output_images::error.eq(None::<String>),
+            output_images::attempts.eq(0),
             output_images::updated.eq(diesel::dsl::now),
             output_images::size.eq(excluded(output_images::size)),
             output_images::format.eq(excluded(output_images::format)),
@@ -606,13 +816,34 @@ fn replace_output_images(
     Ok::<_, eyre::Report>(output_image_ids)
 }
 
+/// Add a job to create output images, and remember it so that its progress can be checked.
+async fn queue_conversion_job(
+    state: &AppState,
+    image_id: BaseImageId,
+    conversions: Vec<OutputImageId>,
+) -> Result<Uuid> {
+    crate::jobs::enqueue_create_output_images(
+        &state.db,
+        &state.queue,
+        &state.conversion_job_options,
+        image_id,
+        conversions,
+    )
+    .await
+}
+
 pub fn configure() -> Router<AppState> {
     let routes = Router::new()
         .route("/", post(new_base_image))
         .route("/:image_id", get(get_base_image_by_id))
         .route("/:image_id", put(update_base_image_info))
         .route("/:image_id", delete(remove_base_image))
-        .route("/:image_id/reconvert", post(reconvert_base_image));
+        .route("/:image_id/reconvert", post(reconvert_base_image))
+        .route("/:image_id/retry", post(retry_failed_outputs))
+        .route(
+            "/:image_id/conversion",
+            get(conversion::get_conversion_status),
+        );
 
     let upload_route = Router::new()
         .route("/:image_id/upload", post(upload::upload_image))
@@ -624,3 +855,162 @@ pub fn configure() -> Router<AppState> {
         .route("/image_by_hash/:hash", get(get_base_image_by_hash))
         .merge(image_id_routes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_output(location: &str, width: u32) -> NewOutputImage {
+        NewOutputImage {
+            id: OutputImageId::new(),
+            team_id: TeamId::new(),
+            base_image_id: BaseImageId::new(),
+            location: location.to_string(),
+            width: None,
+            height: None,
+            size: ConversionSize {
+                width: Some(width),
+                ..Default::default()
+            },
+            format: ConversionFormat::Png {
+                condition: None,
+                compression: None,
+                palette_colors: None,
+            },
+            status: OutputImageStatus::Queued,
+        }
+    }
+
+    #[test]
+    fn parameter_hashes() {
+        let mut outputs = vec![png_output("a-w100.png", 100), png_output("a-w200.png", 200)];
+        hash_output_parameters(&mut outputs, Some(OutputFilenameHash::Content), "abc");
+        assert_eq!(outputs[0].location, "a-w100.png");
+
+        hash_output_parameters(&mut outputs, Some(OutputFilenameHash::Parameters), "abc");
+        assert!(outputs[0].location.starts_with("a-w100-"));
+        assert!(outputs[0].location.ends_with(".png"));
+
+        // A new version of the base image gets new names.
+        let mut reuploaded = vec![png_output("a-w100.png", 100)];
+        hash_output_parameters(&mut reuploaded, Some(OutputFilenameHash::Parameters), "def");
+        assert_ne!(outputs[0].location, reuploaded[0].location);
+    }
+
+    #[test]
+    fn expand_densities() {
+        let sizes = vec![
+            ConversionSize {
+                width: Some(100),
+                densities: Some(vec![1.0, 2.0, 3.0]),
+                ..Default::default()
+            },
+            ConversionSize {
+                width: Some(400),
+                ..Default::default()
+            },
+        ];
+
+        let expanded = plan_sizes(&sizes, (1000, 800), false);
+        let widths = expanded
+            .iter()
+            .map(|s| (s.width, s.density))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            widths,
+            vec![
+                (Some(100), Some(1.0)),
+                (Some(200), Some(2.0)),
+                (Some(300), Some(3.0)),
+                (Some(400), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_upscaled_densities() {
+        let sizes = vec![ConversionSize {
+            width: Some(200),
+            densities: Some(vec![1.0, 2.0, 3.0]),
+            ..Default::default()
+        }];
+
+        let expanded = plan_sizes(&sizes, (500, 400), false);
+        let densities = expanded.iter().map(|s| s.density).collect::<Vec<_>>();
+        assert_eq!(densities, vec![Some(1.0), Some(2.0)]);
+    }
+
+    #[test]
+    fn skip_all_densities_when_all_upscale() {
+        let sizes = vec![ConversionSize {
+            width: Some(200),
+            densities: Some(vec![2.0, 1.0]),
+            ..Default::default()
+        }];
+
+        let expanded = plan_sizes(&sizes, (100, 100), false);
+        assert!(expanded.is_empty());
+    }
+
+    #[test]
+    fn collapse_upscaled_sizes() {
+        let sizes = vec![
+            ConversionSize {
+                width: Some(200),
+                ..Default::default()
+            },
+            ConversionSize {
+                width: Some(800),
+                ..Default::default()
+            },
+            ConversionSize {
+                width: Some(1600),
+                ..Default::default()
+            },
+        ];
+
+        let planned = plan_sizes(&sizes, (500, 400), false);
+        let widths = planned.iter().map(|s| s.width).collect::<Vec<_>>();
+        assert_eq!(widths, vec![Some(200), Some(500)]);
+    }
+
+    #[test]
+    fn collapsed_sizes_keep_settings() {
+        let sizes = vec![ConversionSize {
+            width: Some(1600),
+            height: Some(1200),
+            preserve_aspect_ratio: Some(false),
+            filter: Some(conversion_profiles::ResizeFilter::Lanczos3),
+            ..Default::default()
+        }];
+
+        let planned = plan_sizes(&sizes, (500, 400), false);
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].width, Some(500));
+        assert_eq!(planned[0].height, None);
+        assert_eq!(planned[0].preserve_aspect_ratio, Some(false));
+        assert!(matches!(
+            planned[0].filter,
+            Some(conversion_profiles::ResizeFilter::Lanczos3)
+        ));
+    }
+
+    #[test]
+    fn allow_upscale_keeps_sizes() {
+        let sizes = vec![
+            ConversionSize {
+                width: Some(800),
+                ..Default::default()
+            },
+            ConversionSize {
+                width: Some(200),
+                densities: Some(vec![1.0, 4.0]),
+                ..Default::default()
+            },
+        ];
+
+        let planned = plan_sizes(&sizes, (500, 400), true);
+        let widths = planned.iter().map(|s| s.width).collect::<Vec<_>>();
+        assert_eq!(widths, vec![Some(800), Some(200), Some(800)]);
+    }
+}
@@ -0,0 +1,146 @@
+use db::ImageFormat;
+use pic_store_db as db;
+use serde::Serialize;
+
+/// A single output image that can appear in a `srcset`.
+pub struct SrcSetCandidate<'a> {
+    pub format: ImageFormat,
+    pub url: &'a str,
+    pub width: Option<i32>,
+    pub density: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SrcSet {
+    pub format: ImageFormat,
+    pub srcset: String,
+}
+
+/// Build a `srcset` for each output format. Formats where every output was generated for a
+/// pixel density use `x` descriptors, and everything else uses `w` descriptors, since the two
+/// can not be mixed within a single `srcset`.
+pub fn build_srcsets<'a>(candidates: impl IntoIterator<Item = SrcSetCandidate<'a>>) -> Vec<SrcSet> {
+    let mut by_format: Vec<(ImageFormat, Vec<SrcSetCandidate<'a>>)> = Vec::new();
+    for candidate in candidates {
+        match by_format.iter_mut().find(|(f, _)| *f == candidate.format) {
+            Some((_, list)) => list.push(candidate),
+            None => by_format.push((candidate.format, vec![candidate])),
+        }
+    }
+
+    by_format
+        .into_iter()
+        .filter_map(|(format, mut list)| {
+            let use_density = list.iter().all(|c| c.density.is_some());
+
+            let entries = if use_density {
+                list.sort_by(|a, b| {
+                    a.density
+                        .partial_cmp(&b.density)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                list.iter()
+                    .map(|c| format!("{} {}x", c.url, c.density.unwrap_or(1.0)))
+                    .collect::<Vec<_>>()
+            } else {
+                list.sort_by_key(|c| c.width);
+                list.iter()
+                    .filter_map(|c| c.width.map(|w| format!("{} {w}w", c.url)))
+                    .collect::<Vec<_>>()
+            };
+
+            if entries.is_empty() {
+                None
+            } else {
+                Some(SrcSet {
+                    format,
+                    srcset: entries.join(", "),
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn width_descriptors() {
+        let srcsets = build_srcsets([
+            SrcSetCandidate {
+                format: ImageFormat::Webp,
+                url: "https://img/a-w400.webp",
+                width: Some(400),
+                density: None,
+            },
+            SrcSetCandidate {
+                format: ImageFormat::Webp,
+                url: "https://img/a-w200.webp",
+                width: Some(200),
+                density: None,
+            },
+        ]);
+
+        assert_eq!(srcsets.len(), 1);
+        assert_eq!(
+            srcsets[0].srcset,
+            "https://img/a-w200.webp 200w, https://img/a-w400.webp 400w"
+        );
+    }
+
+    #[test]
+    fn density_descriptors() {
+        let srcsets = build_srcsets([
+            SrcSetCandidate {
+                format: ImageFormat::Avif,
+                url: "https://img/a-w200-2x.avif",
+                width: Some(200),
+                density: Some(2.0),
+            },
+            SrcSetCandidate {
+                format: ImageFormat::Avif,
+                url: "https://img/a-w100-1x.avif",
+                width: Some(100),
+                density: Some(1.0),
+            },
+            SrcSetCandidate {
+                format: ImageFormat::Jpg,
+                url: "https://img/a-w100-1x.jpg",
+                width: Some(100),
+                density: Some(1.0),
+            },
+        ]);
+
+        assert_eq!(srcsets.len(), 2);
+        assert_eq!(srcsets[0].format, ImageFormat::Avif);
+        assert_eq!(
+            srcsets[0].srcset,
+            "https://img/a-w100-1x.avif 1x, https://img/a-w200-2x.avif 2x"
+        );
+        assert_eq!(srcsets[1].srcset, "https://img/a-w100-1x.jpg 1x");
+    }
+
+    #[test]
+    fn mixed_descriptors_use_width() {
+        let srcsets = build_srcsets([
+            SrcSetCandidate {
+                format: ImageFormat::Png,
+                url: "https://img/a-w100-1x.png",
+                width: Some(100),
+                density: Some(1.0),
+            },
+            SrcSetCandidate {
+                format: ImageFormat::Png,
+                url: "https://img/a-w800.png",
+                width: Some(800),
+                density: None,
+            },
+        ]);
+
+        assert_eq!(
+            srcsets[0].srcset,
+            "https://img/a-w100-1x.png 100w, https://img/a-w800.png 800w"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::future::Future;
+
 use axum::{
     extract::{BodyStream, Path, State},
     http::StatusCode,
@@ -12,15 +14,17 @@ use db::{
 use diesel::prelude::*;
 use futures::TryStreamExt;
 use imageinfo::{ImageFormat, ImageInfo, ImageInfoError};
+use pic_store_convert as convert;
 use pic_store_db as db;
 use pic_store_storage as storage;
 use serde_json::json;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tracing::{event, Level};
 
 use crate::{
     auth::Authenticated,
-    routes::image::{generate_output_images, replace_output_images},
+    routes::image::{
+        generate_output_images, hash_output_parameters, queue_conversion_job, replace_output_images,
+    },
     shared_state::AppState,
     Error,
 };
@@ -96,17 +100,49 @@ impl Header {
         let bytes = self.as_slice();
         ImageInfo::from_raw_data(bytes)
     }
+
+    fn is_empty(&self) -> bool {
+        matches!(self.buf, HeaderBuf::Empty)
+    }
+
+    /// Detect the file type. Unlike `parse`, this also works on a partial header, so that small
+    /// files can be detected once the upload finishes.
+    fn detect(&self) -> Result<HeaderInfo, ImageInfoError> {
+        let bytes = self.as_slice();
+        if convert::raw::is_raw(bytes) {
+            Ok(HeaderInfo::Raw)
+        } else if convert::svg::is_svg(bytes) {
+            Ok(HeaderInfo::Svg)
+        } else {
+            ImageInfo::from_raw_data(bytes).map(HeaderInfo::Image)
+        }
+    }
 }
 
-async fn handle_upload(
-    upload: &mut Box<dyn AsyncWrite + Unpin + Send>,
+enum HeaderInfo {
+    Image(ImageInfo),
+    /// Camera RAW files need a full decode to find their dimensions.
+    Raw,
+    /// SVG dimensions come from parsing the whole document.
+    Svg,
+}
+
+/// Write the uploaded file to storage. Once the upload switches to a multipart upload, its ID is
+/// passed to `record_upload_id` so that it can be aborted if this process dies before it finishes.
+async fn handle_upload<F>(
+    upload: &mut storage::Upload,
     mut stream: BodyStream,
-) -> Result<(String, usize, ImageInfo), Error> {
+    record_upload_id: impl FnOnce(String) -> F,
+) -> Result<(String, usize, HeaderInfo), Error>
+where
+    F: Future<Output = Result<(), Error>>,
+{
+    let mut record_upload_id = Some(record_upload_id);
     let mut hasher = blake3::Hasher::new();
 
     let mut header = Header::new();
     let mut total_size = 0;
-    let mut info: Option<ImageInfo> = None;
+    let mut info: Option<HeaderInfo> = None;
 
     while let Some(chunk) = stream.try_next().await? {
         hasher.update(&chunk);
@@ -115,12 +151,22 @@ async fn handle_upload(
         if info.is_none() {
             header.add_chunk(&chunk);
             if header.ready() {
-                let i = header.parse()?;
+                let i = header.detect()?;
                 info = Some(i);
             }
         }
 
-        upload.write_all(&chunk).await?;
+        upload.write(&chunk).await?;
+        if let Some(upload_id) = upload.upload_id() {
+            if let Some(record) = record_upload_id.take() {
+                record(upload_id.clone()).await?;
+            }
+        }
+    }
+
+    // Files smaller than the header buffer, such as icons, end before the header is ready.
+    if info.is_none() && !header.is_empty() {
+        info = Some(header.detect()?);
     }
 
     let info = info.ok_or(Error::ImageHeaderDecode(ImageInfoError::UnrecognizedFormat))?;
@@ -147,6 +193,7 @@ pub async fn upload_image(
         conversion_profile,
         project_base_path,
         base_image_profile_location,
+        filename_hash,
         allowed,
     ) = conn
         .interact(move |conn| {
@@ -167,6 +214,7 @@ pub async fn upload_image(
                     conversion_profiles::all_columns,
                     projects::base_location,
                     upload_profiles::base_storage_location_path,
+                    upload_profiles::output_filename_hash,
                     db::obj_allowed!(
                         user.team_id,
                         &user.roles,
@@ -180,6 +228,7 @@ pub async fn upload_image(
                     conversion_profiles::ConversionProfile,
                     String,
                     Option<String>,
+                    Option<db::OutputFilenameHash>,
                     bool,
                 )>(conn)
         })
@@ -189,7 +238,7 @@ pub async fn upload_image(
         return Err(Error::MissingPermission(Permission::ImageCreate));
     }
 
-    let provider = storage::Provider::from_db(output_path.provider)?;
+    let provider = storage::Provider::from_db(output_path.provider, &state.storage_keys)?;
 
     let output_base_location = image_base_location(
         &output_path.base_location,
@@ -201,36 +250,98 @@ pub async fn upload_image(
         .create_operator(output_base_location.as_ref())
         .await?;
 
-    let (upload_id, mut writer) = operator.put_multipart(&base_image.location).await?;
-    let (hash_hex, total_size, info) = match handle_upload(&mut writer, stream).await {
-        Ok(result) => {
-            writer.shutdown().await?;
-            result
-        }
-        Err(e) => {
-            operator
-                .abort_multipart(&base_image.location, &upload_id)
-                .await
-                .ok();
-            return Err(e);
+    // The base image is stored exactly as uploaded. SVG files can carry scripts, which run when
+    // the file is opened directly, so those and files of unknown type are only offered as
+    // downloads. The SVG outputs are sanitized and can be displayed.
+    let (content_type, content_disposition) =
+        match storage::content_type_for_location(&base_image.location) {
+            Some(content_type) if content_type != "image/svg+xml" => (
+                content_type,
+                storage::inline_content_disposition(&base_image.filename),
+            ),
+            _ => (
+                "application/octet-stream",
+                storage::attachment_content_disposition(&base_image.filename),
+            ),
+        };
+    let attributes = storage::ObjectAttributes {
+        content_type: Some(content_type.to_string()),
+        // The base image can be replaced by uploading again, so it isn't content-hashed.
+        cache_control: storage::cache_control(output_path.cache_control.as_deref(), false),
+        content_disposition: Some(content_disposition),
+    };
+    let mut writer = operator.put_multipart(&base_image.location, &attributes)?;
+
+    let record_upload_id = |upload_id: String| {
+        let conn = &conn;
+        async move {
+            conn.interact(move |conn| {
+                diesel::update(base_images::table)
+                    .filter(base_images::id.eq(image_id))
+                    .set((
+                        base_images::upload_id.eq(upload_id),
+                        base_images::updated.eq(diesel::dsl::now),
+                    ))
+                    .execute(conn)
+            })
+            .await??;
+            Ok::<_, Error>(())
         }
     };
 
-    let upload_format = match info.format {
-        ImageFormat::PNG => db::ImageFormat::Png,
-        ImageFormat::AVIF => db::ImageFormat::Avif,
-        ImageFormat::JPEG => db::ImageFormat::Jpg,
-        ImageFormat::WEBP => db::ImageFormat::Webp,
-        _ => return Err(Error::ImageHeaderDecode(ImageInfoError::UnrecognizedFormat)),
+    let (hash_hex, total_size, info) =
+        match handle_upload(&mut writer, stream, record_upload_id).await {
+            Ok(result) => {
+                writer.finish().await?;
+                result
+            }
+            Err(e) => {
+                let upload_id = writer.upload_id().cloned();
+                writer.abort().await.ok();
+                if let Some(upload_id) = upload_id {
+                    conn.interact(move |conn| {
+                        diesel::update(base_images::table)
+                            .filter(base_images::id.eq(image_id))
+                            .filter(base_images::upload_id.eq(upload_id))
+                            .set(base_images::upload_id.eq(None::<String>))
+                            .execute(conn)
+                    })
+                    .await
+                    .ok();
+                }
+                return Err(e);
+            }
+        };
+
+    let (upload_format, width, height) = match info {
+        // The conversion job fills in the dimensions once it has decoded the image.
+        HeaderInfo::Raw => (db::ImageFormat::Raw, 0, 0),
+        HeaderInfo::Svg => (db::ImageFormat::Svg, 0, 0),
+        HeaderInfo::Image(info) => {
+            let format = match info.format {
+                ImageFormat::PNG => db::ImageFormat::Png,
+                ImageFormat::AVIF => db::ImageFormat::Avif,
+                ImageFormat::JPEG => db::ImageFormat::Jpg,
+                ImageFormat::WEBP => db::ImageFormat::Webp,
+                ImageFormat::JXL => db::ImageFormat::Jxl,
+                ImageFormat::HEIC => db::ImageFormat::Heic,
+                ImageFormat::TIFF => db::ImageFormat::Tiff,
+                ImageFormat::BMP => db::ImageFormat::Bmp,
+                _ => return Err(Error::ImageHeaderDecode(ImageInfoError::UnrecognizedFormat)),
+            };
+            (format, info.size.width as u32, info.size.height as u32)
+        }
     };
 
-    let output_images = generate_output_images(
+    let mut output_images = generate_output_images(
         user.team_id,
         &conversion_profile,
         base_image.id,
         &base_image.location,
         upload_format,
+        (width, height),
     );
+    hash_output_parameters(&mut output_images, filename_hash, &hash_hex);
 
     let output_image_ids = state
         .db
@@ -242,22 +353,18 @@ pub async fn upload_image(
                     base_images::hash.eq(hash_hex),
                     base_images::file_size.eq(total_size as i32),
                     base_images::format.eq(Some(upload_format)),
-                    base_images::width.eq(info.size.width as i32),
-                    base_images::height.eq(info.size.height as i32),
+                    base_images::width.eq(width as i32),
+                    base_images::height.eq(height as i32),
                     base_images::status.eq(db::BaseImageStatus::Converting),
+                    base_images::upload_id.eq(None::<String>),
+                    base_images::updated.eq(diesel::dsl::now),
                 ))
                 .execute(conn)?;
             replace_output_images(conn, user.team_id, image_id, output_images)
         })
         .await?;
 
-    let job_id = effectum::Job::builder(crate::jobs::CREATE_OUTPUT_IMAGES)
-        .json_payload(&crate::jobs::CreateOutputImagesJobPayload {
-            base_image: image_id,
-            conversions: output_image_ids,
-        })?
-        .add_to(&state.queue)
-        .await?;
+    let job_id = queue_conversion_job(&state, image_id, output_image_ids).await?;
     event!(Level::INFO, %job_id, "enqueued image conversion job");
 
     Ok((StatusCode::OK, Json(json!({}))))
@@ -341,6 +448,35 @@ mod tests {
         assert_eq!(info.size.height, 890);
     }
 
+    #[test]
+    fn detect_raw() {
+        let mut dng = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        dng.extend([0x12, 0xc6, 1, 0, 4, 0, 0, 0, 1, 4, 0, 0]);
+        dng.resize(super::HEADER_CAP, 0);
+
+        let mut header = super::Header::new();
+        header.add_chunk(&Bytes::from(dng));
+        assert!(matches!(header.detect(), Ok(super::HeaderInfo::Raw)));
+    }
+
+    #[test]
+    fn detect_image() {
+        let file = read_test_image_header("test-input.png");
+        let mut header = super::Header::new();
+        header.add_chunk(&Bytes::from(file));
+        assert!(matches!(header.detect(), Ok(super::HeaderInfo::Image(_))));
+    }
+
+    #[test]
+    fn detect_small_svg() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="16" height="16"/>"#;
+        let mut header = super::Header::new();
+        header.add_chunk(&Bytes::from_static(svg));
+
+        assert!(!header.ready());
+        assert!(matches!(header.detect(), Ok(super::HeaderInfo::Svg)));
+    }
+
     /// Ensure that the Header class properly handles multiple small chunks
     #[test]
     fn header_small_chunks() {
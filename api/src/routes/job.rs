@@ -0,0 +1,51 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use http::StatusCode;
+use uuid::Uuid;
+
+use super::image::conversion::conversion_status;
+use crate::{
+    auth::Authenticated,
+    jobs::{CreateOutputImagesJobPayload, CREATE_OUTPUT_IMAGES},
+    shared_state::AppState,
+    Error, Result,
+};
+
+async fn get_job(
+    State(state): State<AppState>,
+    Authenticated(user): Authenticated,
+    Path(job_id): Path<Uuid>,
+) -> Result<impl IntoResponse> {
+    let job = state
+        .queue
+        .job_status(job_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    // Access to a job follows access to the image that it's working on.
+    let payload = match job.job_type.as_str() {
+        CREATE_OUTPUT_IMAGES => {
+            serde_json::from_value::<CreateOutputImagesJobPayload>(job.payload.clone())
+                .map_err(|_| Error::NotFound)?
+        }
+        _ => return Err(Error::NotFound),
+    };
+
+    // Jobs queued before the full output list was saved only know their remaining outputs.
+    let outputs = if payload.outputs.is_empty() {
+        payload.conversions
+    } else {
+        payload.outputs
+    };
+
+    let status = conversion_status(&state, user, payload.base_image, Some((job, outputs))).await?;
+    Ok((StatusCode::OK, Json(status)))
+}
+
+pub fn configure() -> Router<AppState> {
+    Router::new().route("/jobs/:job_id", get(get_job))
+}
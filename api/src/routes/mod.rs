@@ -5,6 +5,7 @@ use crate::shared_state::AppState;
 mod conversion_profile;
 mod health;
 mod image;
+mod job;
 pub mod storage_location;
 mod upload_profile;
 
@@ -12,6 +13,7 @@ pub fn configure_routes(router: Router<AppState>) -> Router<AppState> {
     let api_routes = router
         .merge(health::configure())
         .merge(image::configure())
+        .merge(job::configure())
         .merge(upload_profile::configure())
         .merge(conversion_profile::configure())
         .merge(storage_location::configure());
@@ -1,3 +1,8 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
 use axum::{
     extract::{Path, State},
     http::StatusCode,
@@ -5,17 +10,20 @@ use axum::{
     routing::{delete, get, post, put},
     Json, Router,
 };
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use diesel::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 
 use db::{
+    image_path,
     object_id::{ProjectId, StorageLocationId},
     permissions::ProjectPermission,
     storage_locations::{self, NewStorageLocation, Provider},
-    Permission,
+    Permission, PoolExt,
 };
 use pic_store_db as db;
+use pic_store_storage as storage;
 use serde_json::json;
 
 use crate::{
@@ -37,6 +45,8 @@ pub struct StorageLocationInput {
     pub provider: Provider,
     pub base_location: String,
     pub public_url_base: String,
+    #[serde(default)]
+    pub cache_control: Option<String>,
 }
 
 #[derive(Debug, Serialize, Queryable, Selectable)]
@@ -44,12 +54,22 @@ pub struct StorageLocationInput {
 pub struct StorageLocationOutput {
     pub id: StorageLocationId,
     pub name: String,
+    #[serde(serialize_with = "serialize_redacted")]
     pub provider: Provider,
     pub base_location: String,
     pub public_url_base: String,
+    pub cache_control: Option<String>,
     pub updated: DateTime<Utc>,
 }
 
+/// Never send the storage credentials to clients.
+fn serialize_redacted<S: Serializer>(
+    provider: &Provider,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    provider.redacted().serialize(serializer)
+}
+
 async fn list_global_locations(
     State(state): State<AppState>,
     Authenticated(user): Authenticated,
@@ -108,6 +128,22 @@ async fn write_global_location(
     write_location(state, user, None, location_id, body).await
 }
 
+/// Make sure that the server allows the provider to be used.
+fn check_provider_allowed(state: &AppState, provider: &Provider) -> Result<(), Error> {
+    match provider {
+        Provider::Memory if !state.allow_memory_storage => {
+            Err(Error::ProviderNotAllowed(provider.to_string()))
+        }
+        Provider::Azure {
+            use_emulator: Some(true),
+            ..
+        } if !state.allow_storage_emulators => {
+            Err(Error::ProviderNotAllowed("azure emulator".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
 async fn write_location(
     state: AppState,
     user: UserInfo,
@@ -115,6 +151,36 @@ async fn write_location(
     location_id: StorageLocationId,
     body: StorageLocationInput,
 ) -> Result<impl IntoResponse, Error> {
+    check_provider_allowed(&state, &body.provider)?;
+
+    let mut provider = body.provider;
+    let any_redacted = provider
+        .secrets()
+        .into_iter()
+        .flatten()
+        .any(|secret| secret.is_redacted());
+    if any_redacted {
+        // The client sent back the redacted secrets that it received, so keep the existing ones.
+        let team_id = user.team_id;
+        let existing = state
+            .db
+            .interact(move |conn| {
+                storage_locations::table
+                    .filter(storage_locations::id.eq(location_id))
+                    .filter(storage_locations::team_id.eq(team_id))
+                    .select(storage_locations::provider)
+                    .first::<Provider>(conn)
+                    .optional()
+                    .map_err(Error::from)
+            })
+            .await?;
+
+        if let Some(existing) = existing {
+            provider.restore_redacted(&existing);
+        }
+    }
+    provider.encrypt_secrets(&state.storage_keys)?;
+
     let result = write_object!(
         storage_locations,
         state,
@@ -125,9 +191,10 @@ async fn write_location(
         ProjectPermission::StorageLocationWrite,
         (
             dsl::name.eq(body.name),
-            dsl::provider.eq(body.provider),
+            dsl::provider.eq(provider),
             dsl::base_location.eq(body.base_location),
             dsl::public_url_base.eq(body.public_url_base),
+            dsl::cache_control.eq(body.cache_control),
             dsl::updated.eq(Utc::now()),
         )
     )
@@ -159,12 +226,18 @@ async fn new_location(
     project_id: Option<ProjectId>,
     body: StorageLocationInput,
 ) -> Result<impl IntoResponse, Error> {
+    check_provider_allowed(&state, &body.provider)?;
+
+    let mut provider = body.provider;
+    provider.encrypt_secrets(&state.storage_keys)?;
+
     let value = NewStorageLocation {
         id: StorageLocationId::new(),
         name: body.name,
-        provider: body.provider,
+        provider,
         base_location: body.base_location,
         public_url_base: body.public_url_base,
+        cache_control: body.cache_control,
         team_id: state.team_id,
         project_id,
     };
@@ -223,6 +296,254 @@ async fn get_location(
     Ok((StatusCode::OK, Json(location)))
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TestStep {
+    /// Set up the connection to the storage provider.
+    Connect,
+    /// Write the probe object.
+    Put,
+    /// Read the probe object back.
+    Get,
+    /// Fetch the probe object through the public URL base.
+    PublicUrl,
+    /// Delete the probe object.
+    Delete,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TestStepResult {
+    pub step: TestStep,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageLocationTestResult {
+    /// True if every step succeeded.
+    pub ok: bool,
+    /// The steps that ran, in order. The test stops if it can't connect or write the probe
+    /// object, but once the probe object is written it is always deleted.
+    pub steps: Vec<TestStepResult>,
+}
+
+impl StorageLocationTestResult {
+    /// Record the result of a step, and return true if it succeeded.
+    fn record<T, E: std::fmt::Display>(&mut self, step: TestStep, result: &Result<T, E>) -> bool {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let ok = error.is_none();
+        self.ok &= ok;
+        self.steps.push(TestStepResult { step, ok, error });
+        ok
+    }
+}
+
+async fn test_global_location(
+    State(state): State<AppState>,
+    Authenticated(user): Authenticated,
+    Path(location_id): Path<StorageLocationId>,
+) -> Result<impl IntoResponse, crate::Error> {
+    test_location(state, user, None, location_id).await
+}
+
+async fn test_project_location(
+    State(state): State<AppState>,
+    Authenticated(user): Authenticated,
+    Path(path): Path<ProjectStorageLocationPath>,
+) -> Result<impl IntoResponse, crate::Error> {
+    test_location(state, user, Some(path.project_id), path.storage_location_id).await
+}
+
+/// Write, read, and delete a probe object to check that the storage location is set up
+/// correctly.
+async fn test_location(
+    state: AppState,
+    user: UserInfo,
+    project_id: Option<ProjectId>,
+    location_id: StorageLocationId,
+) -> Result<impl IntoResponse, crate::Error> {
+    let team_id = user.team_id;
+    let (location, allowed) = get_object!(
+        storage_locations,
+        state,
+        user,
+        StorageLocationOutput,
+        location_id,
+        db::role_permissions::Permission::StorageLocationWrite
+    )
+    .await?;
+
+    if !allowed {
+        return Err(Error::MissingPermission(
+            db::role_permissions::Permission::StorageLocationWrite,
+        ));
+    }
+
+    // Like the other routes, each URL only reaches the locations in its own project, or the
+    // global locations.
+    let location_project_id = state
+        .db
+        .interact(move |conn| {
+            storage_locations::table
+                .filter(storage_locations::id.eq(location_id))
+                .filter(storage_locations::team_id.eq(team_id))
+                .select(storage_locations::project_id)
+                .first::<Option<ProjectId>>(conn)
+                .map_err(Error::from)
+        })
+        .await?;
+    if location_project_id != project_id {
+        return Err(Error::NotFound);
+    }
+
+    let result = probe_location(&state, location).await;
+    Ok((StatusCode::OK, Json(result)))
+}
+
+async fn probe_location(
+    state: &AppState,
+    location: StorageLocationOutput,
+) -> StorageLocationTestResult {
+    let mut result = StorageLocationTestResult {
+        ok: true,
+        steps: Vec::new(),
+    };
+
+    let operator = match storage::Provider::from_db(location.provider, &state.storage_keys) {
+        Ok(provider) => provider
+            .create_operator(&location.base_location)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    result.record(TestStep::Connect, &operator);
+    let Ok(operator) = operator else {
+        return result;
+    };
+
+    let probe_location = format!(".pic-store-probe/{}.txt", db::new_uuid());
+    let contents = Bytes::from(format!("pic-store probe {probe_location}"));
+
+    let attributes = storage::ObjectAttributes {
+        content_type: Some("text/plain".to_string()),
+        ..Default::default()
+    };
+    let put = operator
+        .put(&probe_location, contents.clone(), &attributes)
+        .await;
+    if !result.record(TestStep::Put, &put) {
+        return result;
+    }
+
+    let get = match operator.get(&probe_location).await {
+        Ok(data) => data.bytes().await.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+    .and_then(|data| {
+        if data == contents {
+            Ok(())
+        } else {
+            Err("The probe object's contents did not match what was written".to_string())
+        }
+    });
+    let get_ok = result.record(TestStep::Get, &get);
+
+    if get_ok && !location.public_url_base.is_empty() {
+        let url = image_path(&location.public_url_base, "", &None, &probe_location);
+        let public = fetch_public_url(&url, &contents, state.allow_private_urls).await;
+        result.record(TestStep::PublicUrl, &public);
+    }
+
+    let delete = operator.delete(&probe_location).await;
+    result.record(TestStep::Delete, &delete);
+
+    result
+}
+
+/// Check that the probe object can be downloaded from its public URL. The URL is set by users,
+/// so unless `allow_private` is set, it must resolve to a public address. Otherwise it could be
+/// used to send requests to internal services.
+async fn fetch_public_url(url: &str, expected: &Bytes, allow_private: bool) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid URL {url}: {e}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("{url} must use http or https"));
+    }
+
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| format!("{url} has no host"))?;
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let addrs = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|e| format!("Failed to look up {host}: {e}"))?
+        .collect::<Vec<SocketAddr>>();
+    if addrs.is_empty() {
+        return Err(format!("Failed to look up {host}"));
+    }
+    if !allow_private && !addrs.iter().all(|addr| is_public_address(addr.ip())) {
+        return Err(format!("{url} is not a public address"));
+    }
+
+    // Connect to the checked addresses, and don't follow redirects that could lead elsewhere.
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(host, &addrs)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch {url}: {e}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("{url} returned status {status}"));
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to read {url}: {e}"))?;
+    if &body != expected {
+        return Err(format!("{url} did not return the probe object"));
+    }
+
+    Ok(())
+}
+
+/// Returns false for loopback, private, link-local, and other addresses that aren't reachable
+/// from the public internet.
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_address(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // Link-local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
 async fn disable_project_location(
     State(state): State<AppState>,
     Authenticated(user): Authenticated,
@@ -264,7 +585,8 @@ pub fn configure() -> Router<AppState> {
         .route("/", post(new_project_location))
         .route("/:storage_location_id", get(get_project_location))
         .route("/:storage_location_id", put(write_project_location))
-        .route("/:storage_location_id", delete(disable_project_location));
+        .route("/:storage_location_id", delete(disable_project_location))
+        .route("/:storage_location_id/test", post(test_project_location));
 
     let project_router =
         Router::new().nest("/projects/:project_id/storage_locations", project_routes);
@@ -274,9 +596,38 @@ pub fn configure() -> Router<AppState> {
         .route("/", post(new_global_location))
         .route("/:storage_location_id", get(get_global_location))
         .route("/:storage_location_id", put(write_global_location))
-        .route("/:storage_location_id", delete(disable_global_location));
+        .route("/:storage_location_id", delete(disable_global_location))
+        .route("/:storage_location_id/test", post(test_global_location));
 
     let global_router = Router::new().nest("/projects/global/storage_locations", global_routes);
 
     global_router.merge(project_router)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -10,7 +10,7 @@ use db::{
     object_id::{ConversionProfileId, ProjectId, StorageLocationId, UploadProfileId},
     permissions::ProjectPermission,
     upload_profiles::{self, NewUploadProfile},
-    Permission, PoolExt,
+    OutputFilenameHash, Permission, PoolExt,
 };
 use diesel::prelude::*;
 use pic_store_db as db;
@@ -33,6 +33,7 @@ struct UploadProfileInput {
     pub output_storage_location_id: StorageLocationId,
     pub output_storage_location_path: Option<String>,
     pub conversion_profile_id: ConversionProfileId,
+    pub output_filename_hash: Option<OutputFilenameHash>,
 }
 
 #[derive(Debug, Serialize, Queryable, Selectable)]
@@ -46,6 +47,7 @@ struct UploadProfileOutput {
     pub output_storage_location_id: StorageLocationId,
     pub output_storage_location_path: Option<String>,
     pub conversion_profile_id: ConversionProfileId,
+    pub output_filename_hash: Option<OutputFilenameHash>,
 }
 
 async fn list_project_upload_profiles(
@@ -112,6 +114,7 @@ async fn write_project_upload_profile(
             dsl::base_storage_location_path.eq(body.base_storage_location_path),
             dsl::output_storage_location_id.eq(body.output_storage_location_id),
             dsl::output_storage_location_path.eq(body.output_storage_location_path),
+            dsl::output_filename_hash.eq(body.output_filename_hash),
         )
     )
     .await?;
@@ -136,6 +139,7 @@ async fn new_project_upload_profile(
         output_storage_location_id: payload.output_storage_location_id,
         output_storage_location_path: payload.output_storage_location_path,
         conversion_profile_id: payload.conversion_profile_id,
+        output_filename_hash: payload.output_filename_hash,
         project_id,
         team_id: user.team_id,
     };
@@ -4,12 +4,27 @@ use uuid::Uuid;
 
 use pic_store_db as db;
 
-use crate::auth::ApiKeyStore;
+use db::secrets::MasterKeys;
+
+use crate::{
+    auth::ApiKeyStore,
+    jobs::{JobOptions, JobQueue},
+};
 
 pub struct InnerState {
     pub production: bool,
+    /// Whether storage locations can use the in-memory provider.
+    pub allow_memory_storage: bool,
+    /// Whether Azure storage locations can connect to the Azurite emulator.
+    pub allow_storage_emulators: bool,
+    /// Whether storage location tests can fetch public URLs that resolve to private addresses.
+    pub allow_private_urls: bool,
     pub db: db::Pool,
-    pub queue: effectum::Queue,
+    pub queue: Arc<JobQueue>,
+    /// Encrypts and decrypts storage credentials.
+    pub storage_keys: Arc<MasterKeys>,
+    /// Retry settings for conversion jobs.
+    pub conversion_job_options: JobOptions,
 
     // Hardcoded values until we have real user auth and such.
     pub user_id: UserId,
@@ -55,14 +55,42 @@ async fn start_app(
         database_url: database.url.clone(),
         port: 0, // Bind to random port
         host: "127.0.0.1".to_string(),
-        queue_db_path: queue_path.to_string_lossy().to_string(),
+        queue: pic_store_api::config::QueueConfig {
+            queue_backend: pic_store_api::config::QueueBackend::Sqlite,
+            queue_db_path: queue_path.to_string_lossy().to_string(),
+        },
         honeycomb_team: None,
         honeycomb_dataset: String::new(),
         env: "test".to_string(),
         jaeger_endpoint: None,
         allow_local_fs: true,
+        allow_memory_storage: true,
+        allow_storage_emulators: true,
+        allow_private_urls: true,
         cookie_key: "QjX+c1Nggom7lrxVTJFxMI7iQ0BRVr1oR9N64orRgdW3pp/SV+lE/1FOwo12UZj9QoBUUuv2rvcO0x+Omq+25Q==".to_string(),
         session_cookie_name: "sid".to_string(),
+        storage_keys: pic_store_api::config::StorageKeyConfig {
+            storage_key: Some("3mV7TfVYq5pg1q8KcK1p6iO3ZtWqL8x7pD2Ykx0nE8A=".to_string()),
+            previous_storage_keys: Vec::new(),
+        },
+        no_worker: false,
+        worker: pic_store_api::config::WorkerConfig {
+            job_concurrency: 10,
+            decode_memory_limit_mb: 1024,
+            encode_threads: 2,
+            upload_concurrency: 4,
+            reaper_interval_secs: 300,
+            stale_upload_secs: 3600,
+            stale_conversion_secs: 1800,
+            superseded_output_grace_secs: 86400,
+            superseded_output_interval_secs: 3600,
+        },
+        conversion_job: pic_store_api::config::ConversionJobConfig {
+            conversion_max_retries: 3,
+            conversion_retry_backoff_secs: 20,
+            conversion_retry_backoff_multiplier: 2.0,
+            conversion_timeout_secs: 300,
+        },
     };
     Lazy::force(&pic_store_test::TRACING);
     let server = pic_store_api::create_server(config).await?;
@@ -0,0 +1,41 @@
+use pic_store_api::jobs::{queue::postgres::PostgresQueue, JobOptions, JobState};
+use pic_store_db::test::run_database_test;
+
+#[tokio::test]
+async fn postgres_queue_claims_each_job_once() {
+    run_database_test(|database| async move {
+        let queue = PostgresQueue::new(database.pool.clone());
+        let id = queue
+            .add(
+                "test_job",
+                serde_json::json!({ "value": 1 }),
+                &JobOptions::default(),
+            )
+            .await?;
+
+        let claimed = queue.claim(vec!["other_job"], 10).await?;
+        assert!(claimed.is_empty(), "only claims registered job types");
+
+        let claimed = queue.claim(vec!["test_job"], 10).await?;
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, id);
+        assert_eq!(claimed[0].current_try, 1);
+
+        let payload = claimed[0].json_payload::<serde_json::Value>()?;
+        assert_eq!(payload["value"], 1);
+
+        let claimed_again = queue.claim(vec!["test_job"], 10).await?;
+        assert!(claimed_again.is_empty(), "running job is not claimed again");
+
+        let status = queue.job_status(id).await?.expect("job exists");
+        assert_eq!(status.state, JobState::Running);
+        assert_eq!(status.attempts, 1);
+        assert_eq!(status.last_error, None);
+
+        // The job hasn't timed out yet.
+        assert_eq!(queue.expire_jobs().await?, 0);
+
+        Ok(())
+    })
+    .await
+}
@@ -1,3 +1,5 @@
 mod client;
 mod common;
+mod job_queue;
 mod smoke_test;
+mod storage_location;
@@ -0,0 +1,99 @@
+use serde_json::{json, Value};
+
+use crate::common::{run_app_test, TestApp};
+
+async fn create_memory_location(app: &TestApp, public_url_base: &str) -> eyre::Result<String> {
+    let response = app
+        .admin_user
+        .client
+        .post("projects/global/storage_locations")
+        .json(&json!({
+            "name": "Probe Test",
+            "provider": { "type": "memory" },
+            "base_location": "probe-test/base",
+            "public_url_base": public_url_base,
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status().as_u16(), 202, "creating storage location");
+
+    let body = response.json::<Value>().await?;
+    Ok(body["id"].as_str().unwrap().to_string())
+}
+
+async fn test_location(app: &TestApp, id: &str) -> eyre::Result<Value> {
+    let response = app
+        .admin_user
+        .client
+        .post(format!("projects/global/storage_locations/{id}/test"))
+        .send()
+        .await?;
+    assert_eq!(response.status().as_u16(), 200, "testing storage location");
+    Ok(response.json::<Value>().await?)
+}
+
+fn step_names(result: &Value) -> Vec<&str> {
+    result["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|step| step["step"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_working_location() {
+    run_app_test(|app| async move {
+        let id = create_memory_location(&app, "").await?;
+        let result = test_location(&app, &id).await?;
+
+        assert_eq!(result["ok"], true, "{result}");
+        assert_eq!(step_names(&result), vec!["connect", "put", "get", "delete"]);
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_location_with_bad_public_url() {
+    run_app_test(|app| async move {
+        // The mock server returns 404 for everything.
+        let server = wiremock::MockServer::start().await;
+        let id = create_memory_location(&app, &server.uri()).await?;
+        let result = test_location(&app, &id).await?;
+
+        assert_eq!(result["ok"], false, "{result}");
+        assert_eq!(
+            step_names(&result),
+            vec!["connect", "put", "get", "public_url", "delete"]
+        );
+
+        let steps = result["steps"].as_array().unwrap();
+        assert_eq!(steps[3]["ok"], false);
+        assert!(steps[3]["error"].as_str().unwrap().contains("404"));
+        assert_eq!(steps[4]["ok"], true, "probe object should still be deleted");
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_location_from_other_project() {
+    run_app_test(|app| async move {
+        let id = create_memory_location(&app, "").await?;
+        let project_id = pic_store_db::object_id::ProjectId::new();
+        let response = app
+            .admin_user
+            .client
+            .post(format!("projects/{project_id}/storage_locations/{id}/test"))
+            .send()
+            .await?;
+        assert_eq!(
+            response.status().as_u16(),
+            404,
+            "a global location should not be reachable from a project URL"
+        );
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,113 @@
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::DynamicImage;
+use pic_store_convert::{
+    pipeline::resize_cascade,
+    resize::{default_backend, output_size, resize_image},
+    write_format::WebpOptions,
+    ConversionRequest, ImageSizeTransform, OutputFormat, Pipeline,
+};
+
+const FIXTURES: [&str; 3] = ["test-input.jpeg", "test-input.png", "test-input.webp"];
+const WIDTHS: [u32; 6] = [1200, 1000, 800, 600, 400, 200];
+
+fn read_fixture(filename: &str) -> (Vec<u8>, DynamicImage) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../fixtures")
+        .join(filename);
+    let bytes = std::fs::read(path).expect("reading image");
+    let image = pic_store_convert::image_from_bytes(&bytes).expect("decoding image");
+    (bytes, image)
+}
+
+fn size(width: u32) -> ImageSizeTransform {
+    ImageSizeTransform {
+        width: Some(width),
+        height: None,
+        preserve_aspect_ratio: true,
+        allow_upscale: false,
+        ..Default::default()
+    }
+}
+
+fn requests() -> Vec<ConversionRequest> {
+    WIDTHS
+        .iter()
+        .map(|&width| ConversionRequest {
+            format: OutputFormat::Webp(WebpOptions::default()),
+            size: size(width),
+        })
+        .collect()
+}
+
+/// Compare resizing every size from the full image against the cascade.
+fn resize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("resize");
+    for filename in FIXTURES {
+        let (_, image) = read_fixture(filename);
+        let sizes = WIDTHS
+            .iter()
+            .map(|&w| {
+                let (width, height) = output_size(image.width(), image.height(), &size(w));
+                (width, height, Default::default())
+            })
+            .collect::<Vec<_>>();
+
+        group.bench_with_input(
+            BenchmarkId::new("independent", filename),
+            &image,
+            |b, image| {
+                b.iter(|| {
+                    WIDTHS
+                        .iter()
+                        .map(|&w| resize_image(image, &size(w)))
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("cascade", filename), &image, |b, image| {
+            b.iter(|| resize_cascade(default_backend(), image, &sizes))
+        });
+    }
+    group.finish();
+}
+
+/// Compare converting each output in turn against the parallel pipeline.
+fn convert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("convert");
+    group.sample_size(10);
+
+    let pipeline = Pipeline::new(0).expect("creating pipeline");
+    let requests = requests();
+
+    for filename in FIXTURES {
+        let (bytes, image) = read_fixture(filename);
+
+        group.bench_with_input(
+            BenchmarkId::new("sequential", filename),
+            &image,
+            |b, image| {
+                b.iter(|| {
+                    requests
+                        .iter()
+                        .map(|r| {
+                            pic_store_convert::convert(image, Some(&bytes), &r.format, &r.size)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("pipeline", filename),
+            &image,
+            |b, image| b.iter(|| pipeline.convert_all(image, Some(&bytes), &requests)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, resize, convert);
+criterion_main!(benches);
@@ -11,6 +11,17 @@ pub enum Error {
     },
     #[error(transparent)]
     Encode(#[from] EncodeError),
+    #[error(
+        "Image is {width}x{height}, which needs about {} MiB to decode, over the limit of {} MiB",
+        estimated_bytes / 1024 / 1024,
+        max_bytes / 1024 / 1024
+    )]
+    TooLarge {
+        width: u32,
+        height: u32,
+        estimated_bytes: u64,
+        max_bytes: u64,
+    },
 }
 
 impl Error {
@@ -4,12 +4,17 @@ use image::{
     error::DecodingError, flat::SampleLayout, DynamicImage, FlatSamples, ImageBuffer, ImageError,
     Rgb, Rgba,
 };
+pub use pipeline::{ConversionRequest, Pipeline};
 use resize::resize_image;
-pub use resize::ImageSizeTransform;
-pub use write_format::EncodeError;
+pub use resize::{ImageSizeTransform, ResizeFilter, ResizeOptions};
+pub use write_format::{EncodeError, OutputFormat};
 
 mod error;
+pub mod pipeline;
+pub mod raw;
 pub mod resize;
+pub mod svg;
+pub mod target_quality;
 pub mod write_format;
 
 fn load_avif(bytes: &[u8]) -> eyre::Result<DynamicImage> {
@@ -24,12 +29,15 @@ fn load_avif(bytes: &[u8]) -> eyre::Result<DynamicImage> {
 }
 
 fn load_heic(bytes: &[u8]) -> eyre::Result<DynamicImage> {
-    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
-
-    let lib_heif = LibHeif::new();
-    let context = HeifContext::read_from_bytes(bytes)?;
+    let context = libheif_rs::HeifContext::read_from_bytes(bytes)?;
     let handle = context.primary_image_handle()?;
+    decode_heic_handle(&handle)
+}
 
+fn decode_heic_handle(handle: &libheif_rs::ImageHandle) -> eyre::Result<DynamicImage> {
+    use libheif_rs::{ColorSpace, LibHeif, RgbChroma};
+
+    let lib_heif = LibHeif::new();
     let bits_per_pixel = handle.luma_bits_per_pixel();
     let bytes_per_pixel = ((bits_per_pixel + 7) / 8) as usize;
 
@@ -40,7 +48,7 @@ fn load_heic(bytes: &[u8]) -> eyre::Result<DynamicImage> {
         RgbChroma::Rgb
     };
 
-    let image = lib_heif.decode(&handle, ColorSpace::Rgb(chroma), None)?;
+    let image = lib_heif.decode(handle, ColorSpace::Rgb(chroma), None)?;
 
     let plane = image
         .planes()
@@ -102,13 +110,233 @@ fn load_heic(bytes: &[u8]) -> eyre::Result<DynamicImage> {
     Ok(output)
 }
 
+fn load_jxl(bytes: &[u8]) -> eyre::Result<DynamicImage> {
+    use jpegxl_rs::image::ToDynamic;
+
+    let decoder = jpegxl_rs::decoder_builder().build()?;
+    decoder
+        .decode_to_image(bytes)?
+        .ok_or_else(|| eyre!("Unsupported JPEG XL pixel format"))
+}
+
+/// The estimated memory used by each pixel of a decoded image when its color type can't be read
+/// from the file, as 8-bit RGBA. SVGs are always rendered at this depth.
+const DECODED_BYTES_PER_PIXEL: u64 = 4;
+/// RAW processing holds floating point RGB for each pixel, plus the 16-bit output.
+const RAW_BYTES_PER_PIXEL: u64 = 18;
+
+fn decoded_size(width: u32, height: u32, bytes_per_pixel: u64) -> u64 {
+    width as u64 * height as u64 * bytes_per_pixel
+}
+
+/// The memory used by each pixel of the decoded image, from the color type that its decoder will
+/// produce. 16-bit images use twice as much as 8-bit ones.
+fn decoded_bytes_per_pixel(bytes: &[u8], format: imageinfo::ImageFormat) -> u64 {
+    use image::ImageDecoder;
+
+    let cursor = std::io::Cursor::new(bytes);
+    let color_type = match format {
+        imageinfo::ImageFormat::JPEG => image::codecs::jpeg::JpegDecoder::new(cursor)
+            .map(|d| d.color_type())
+            .ok(),
+        imageinfo::ImageFormat::PNG => image::codecs::png::PngDecoder::new(cursor)
+            .map(|d| d.color_type())
+            .ok(),
+        imageinfo::ImageFormat::TIFF => image::codecs::tiff::TiffDecoder::new(cursor)
+            .map(|d| d.color_type())
+            .ok(),
+        imageinfo::ImageFormat::HEIC => {
+            let context = libheif_rs::HeifContext::read_from_bytes(bytes).ok();
+            let handle = context
+                .as_ref()
+                .and_then(|context| context.primary_image_handle().ok());
+            return handle
+                .map(|handle| heic_bytes_per_pixel(&handle))
+                .unwrap_or(DECODED_BYTES_PER_PIXEL);
+        }
+        _ => None,
+    };
+
+    color_type
+        .map(|color_type| color_type.bytes_per_pixel() as u64)
+        .unwrap_or(DECODED_BYTES_PER_PIXEL)
+}
+
+/// The memory used by each pixel of a decoded HEIC image, matching [decode_heic_handle].
+fn heic_bytes_per_pixel(handle: &libheif_rs::ImageHandle) -> u64 {
+    let bytes_per_channel = (handle.luma_bits_per_pixel() as u64 + 7) / 8;
+    let channels = if handle.has_alpha_channel() { 4 } else { 3 };
+    bytes_per_channel * channels
+}
+
+fn check_decode_size(
+    width: u32,
+    height: u32,
+    bytes_per_pixel: u64,
+    max_bytes: Option<u64>,
+) -> Result<(), Error> {
+    let estimated_bytes = decoded_size(width, height, bytes_per_pixel);
+    match max_bytes {
+        Some(max_bytes) if estimated_bytes > max_bytes => Err(Error::TooLarge {
+            width,
+            height,
+            estimated_bytes,
+            max_bytes,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Decode a JPEG using DCT scaling at the largest of 1/2, 1/4, or 1/8 scale that fits in
+/// `max_bytes`. This never allocates the full resolution image.
+fn load_jpeg_scaled(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    bytes_per_pixel: u64,
+    max_bytes: u64,
+) -> Result<DynamicImage, Error> {
+    let read_error = |e: ImageError| Error::read_error(Some(imageinfo::ImageFormat::JPEG), e);
+
+    let scaled_size = [2, 4, 8]
+        .into_iter()
+        .map(|denom| ((width + denom - 1) / denom, (height + denom - 1) / denom))
+        .find(|&(w, h)| decoded_size(w, h, bytes_per_pixel) <= max_bytes);
+
+    let Some((scaled_width, scaled_height)) = scaled_size else {
+        return Err(Error::TooLarge {
+            width,
+            height,
+            estimated_bytes: decoded_size(width, height, bytes_per_pixel),
+            max_bytes,
+        });
+    };
+
+    let mut decoder =
+        image::codecs::jpeg::JpegDecoder::new(std::io::Cursor::new(bytes)).map_err(read_error)?;
+    decoder
+        .scale(scaled_width as u16, scaled_height as u16)
+        .map_err(read_error)?;
+    DynamicImage::from_decoder(decoder).map_err(read_error)
+}
+
+/// Decode the largest thumbnail embedded in a HEIC file that fits in `max_bytes`. HEVC has no
+/// equivalent of JPEG's DCT scaling, so libheif can only decode the primary image at full size,
+/// but phones and cameras usually embed a smaller preview that can be used instead.
+fn load_heic_scaled(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    bytes_per_pixel: u64,
+    max_bytes: u64,
+) -> Result<DynamicImage, Error> {
+    let read_error = |e: eyre::Report| Error::read_error(Some(imageinfo::ImageFormat::HEIC), e);
+
+    let context =
+        libheif_rs::HeifContext::read_from_bytes(bytes).map_err(|e| read_error(e.into()))?;
+    let primary = context
+        .primary_image_handle()
+        .map_err(|e| read_error(e.into()))?;
+
+    let mut thumbnail_ids = vec![0; primary.number_of_thumbnails()];
+    primary.thumbnail_ids(&mut thumbnail_ids);
+    let thumbnail = thumbnail_ids
+        .into_iter()
+        .filter_map(|id| primary.thumbnail(id).ok())
+        .filter(|t| decoded_size(t.width(), t.height(), heic_bytes_per_pixel(t)) <= max_bytes)
+        .max_by_key(|t| t.width() as u64 * t.height() as u64);
+
+    match thumbnail {
+        Some(thumbnail) => decode_heic_handle(&thumbnail).map_err(read_error),
+        None => Err(Error::TooLarge {
+            width,
+            height,
+            estimated_bytes: decoded_size(width, height, bytes_per_pixel),
+            max_bytes,
+        }),
+    }
+}
+
+/// Render an SVG at its intrinsic size, or smaller if that would exceed `max_bytes`.
+fn load_svg(bytes: &[u8], max_bytes: Option<u64>) -> eyre::Result<DynamicImage> {
+    let (width, height) = svg::svg_size(bytes)?;
+    let estimated_bytes = decoded_size(width, height, DECODED_BYTES_PER_PIXEL);
+    let (width, height) = match max_bytes {
+        Some(max_bytes) if estimated_bytes > max_bytes => {
+            let scale = (max_bytes as f64 / estimated_bytes as f64).sqrt();
+            (
+                ((width as f64 * scale).floor() as u32).max(1),
+                ((height as f64 * scale).floor() as u32).max(1),
+            )
+        }
+        _ => (width, height),
+    };
+
+    svg::rasterize(bytes, width, height)
+}
+
 pub fn image_from_bytes(bytes: &[u8]) -> Result<DynamicImage, Error> {
-    let format = imageinfo::ImageInfo::from_raw_data(bytes).map(|i| i.format);
+    image_from_bytes_with_limit(bytes, None)
+}
+
+/// Decode an image, keeping its estimated decoded size within `max_bytes`. JPEG and SVG images
+/// that are too large are decoded at a smaller size, and HEIC images use an embedded thumbnail if
+/// one fits, so callers should check the dimensions of the result. Other formats return
+/// [Error::TooLarge].
+pub fn image_from_bytes_with_limit(
+    bytes: &[u8],
+    max_bytes: Option<u64>,
+) -> Result<DynamicImage, Error> {
+    let info = imageinfo::ImageInfo::from_raw_data(bytes);
+    let format = info.as_ref().map(|i| i.format);
+
+    // RAW files are often TIFF containers, so check for them before trusting the detected format.
+    if raw::is_raw(bytes) {
+        let read_error = |error| Error::Read {
+            format: format.as_ref().ok().copied(),
+            error,
+        };
+        let raw_image = raw::decode_raw(bytes).map_err(read_error)?;
+        check_decode_size(
+            raw_image.width as u32,
+            raw_image.height as u32,
+            RAW_BYTES_PER_PIXEL,
+            max_bytes,
+        )?;
+        return raw::develop_raw(&raw_image).map_err(read_error);
+    }
+
+    // imageinfo doesn't know about SVG, so render it directly.
+    if svg::is_svg(bytes) {
+        return load_svg(bytes, max_bytes).map_err(|error| Error::Read {
+            format: None,
+            error,
+        });
+    }
+
+    if let (Ok(info), Some(max_bytes)) = (&info, max_bytes) {
+        let width = info.size.width as u32;
+        let height = info.size.height as u32;
+        let bytes_per_pixel = decoded_bytes_per_pixel(bytes, info.format);
+        let fits = check_decode_size(width, height, bytes_per_pixel, Some(max_bytes));
+        match (fits, info.format) {
+            (Ok(()), _) => {}
+            (Err(_), imageinfo::ImageFormat::JPEG) => {
+                return load_jpeg_scaled(bytes, width, height, bytes_per_pixel, max_bytes);
+            }
+            (Err(_), imageinfo::ImageFormat::HEIC) => {
+                return load_heic_scaled(bytes, width, height, bytes_per_pixel, max_bytes);
+            }
+            (Err(e), _) => return Err(e),
+        }
+    }
+
     let result = match format {
         // Some AVIF format files don't parse well using the image crate, so we
         // use libavif instead.
         Ok(imageinfo::ImageFormat::AVIF) => load_avif(bytes),
         Ok(imageinfo::ImageFormat::HEIC) => load_heic(bytes),
+        Ok(imageinfo::ImageFormat::JXL) => load_jxl(bytes),
         _ => image::load_from_memory(bytes).map_err(eyre::Report::from),
     };
 
@@ -122,27 +350,99 @@ pub struct ConvertResult {
     pub width: u32,
     pub height: u32,
     pub image: Vec<u8>,
+    /// The encoder quality used, if the format has one.
+    pub quality: Option<f32>,
 }
 
+/// Resize and encode an image. `source` is the original encoded file, which lets JPEG input be
+/// losslessly recompressed to JPEG XL when no resize is needed.
 pub fn convert(
     image: &DynamicImage,
-    format: image::ImageFormat,
-    quality: Option<f32>,
+    source: Option<&[u8]>,
+    format: &OutputFormat,
     size: &ImageSizeTransform,
 ) -> Result<ConvertResult, EncodeError> {
     let resized = resize_image(image, size);
-    let mut output = Vec::new();
+    encode_output(resized.as_ref(), source, format, image)
+}
 
-    let convert_input = resized.as_ref().unwrap_or(image);
+/// Encode an output that has already been resized. `resized` is `None` when the output is the
+/// same size as the original `image`.
+fn encode_output(
+    resized: Option<&DynamicImage>,
+    source: Option<&[u8]>,
+    format: &OutputFormat,
+    image: &DynamicImage,
+) -> Result<ConvertResult, EncodeError> {
+    let convert_input = resized.unwrap_or(image);
 
     let width = convert_input.width();
     let height = convert_input.height();
 
-    write_format::write_image(convert_input, format, quality, &mut output)?;
+    if let (None, Some(source), OutputFormat::Jxl(options)) = (resized, source, format) {
+        let is_jpeg = imageinfo::ImageInfo::from_raw_data(source)
+            .map(|info| info.format == imageinfo::ImageFormat::JPEG)
+            .unwrap_or(false);
+        if options.lossless_jpeg && is_jpeg {
+            let mut output = Vec::new();
+            write_format::recompress_jpeg_to_jxl(source, options, &mut output)?;
+            return Ok(ConvertResult {
+                width,
+                height,
+                image: output,
+                quality: None,
+            });
+        }
+    }
+
+    let (output, quality) = encode(convert_input, format)?;
     Ok(ConvertResult {
         width,
         height,
         image: output,
+        quality,
+    })
+}
+
+/// Encode an image, searching for the quality if the format has a target quality. Returns the
+/// encoded image and the quality used.
+fn encode(
+    image: &DynamicImage,
+    format: &OutputFormat,
+) -> Result<(Vec<u8>, Option<f32>), EncodeError> {
+    match format.target_quality() {
+        Some(target) => {
+            // This compares against the resized image so that the resize itself doesn't count
+            // against the target.
+            let result = target_quality::encode_to_target(image, format, target)?;
+            Ok((result.image, Some(result.quality)))
+        }
+        None => {
+            let mut output = Vec::new();
+            write_format::write_image(image, format, &mut output)?;
+            Ok((output, format.quality()))
+        }
+    }
+}
+
+/// Render an SVG directly at the requested size and encode it. Since SVGs are vectors, this
+/// always scales to the requested size, even if it is larger than the intrinsic size.
+pub fn convert_svg(
+    svg: &[u8],
+    format: &OutputFormat,
+    size: &ImageSizeTransform,
+) -> Result<ConvertResult, Error> {
+    let (svg_width, svg_height) = svg::svg_size(svg).map_err(|e| Error::read_error(None, e))?;
+    let (width, height) = resize::target_size(svg_width, svg_height, size);
+    let image =
+        svg::rasterize(svg, width.max(1), height.max(1)).map_err(|e| Error::read_error(None, e))?;
+
+    let (output, quality) = encode(&image, format)?;
+    Ok(ConvertResult {
+        width: image.width(),
+        height: image.height(),
+        image: output,
+        quality,
     })
 }
 
@@ -152,15 +452,20 @@ mod tests {
 
     use image::DynamicImage;
 
-    use crate::write_format::write_image;
+    use crate::write_format::{write_image, OutputFormat};
 
-    fn read_test_image(filename: &str) -> DynamicImage {
+    fn read_test_file(filename: &str) -> Vec<u8> {
         let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("../fixtures")
             .join(filename);
         let mut file = std::fs::File::open(&path).expect("opening file");
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer).expect("reading file");
+        buffer
+    }
+
+    fn read_test_image(filename: &str) -> DynamicImage {
+        let buffer = read_test_file(filename);
         super::image_from_bytes(buffer.as_slice()).expect("parsing file")
     }
 
@@ -186,7 +491,7 @@ mod tests {
         assert_eq!(image.height(), 1024);
 
         let writer = std::fs::File::create("test-output.jpeg").unwrap();
-        write_image(&image, image::ImageFormat::Jpeg, None, writer).unwrap();
+        write_image(&image, &OutputFormat::Jpeg(Default::default()), writer).unwrap();
     }
 
     #[test]
@@ -196,6 +501,30 @@ mod tests {
         assert_eq!(image.height(), 890);
     }
 
+    #[test]
+    fn read_jpeg_scaled_to_limit() {
+        let buffer = read_test_file("test-input.jpeg");
+        // Full size RGB needs about 3.6MB, so this should decode at half size.
+        let image = super::image_from_bytes_with_limit(&buffer, Some(2 * 1024 * 1024)).unwrap();
+        assert_eq!(image.width(), 667);
+        assert_eq!(image.height(), 445);
+    }
+
+    #[test]
+    fn read_over_limit() {
+        let buffer = read_test_file("test-input.png");
+        // 8-bit RGB needs about 890KB.
+        let result = super::image_from_bytes_with_limit(&buffer, Some(512 * 1024));
+        assert!(matches!(
+            result,
+            Err(super::Error::TooLarge {
+                width: 667,
+                height: 445,
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn read_png() {
         let image = read_test_image("test-input.png");
@@ -0,0 +1,216 @@
+//! Converting one image into many outputs.
+
+use std::collections::HashMap;
+
+use image::DynamicImage;
+use rayon::prelude::*;
+
+use crate::{
+    encode_output,
+    resize::{default_backend, output_size, resize_exact, ResizeBackend, ResizeOptions},
+    ConvertResult, EncodeError, Error, ImageSizeTransform, OutputFormat,
+};
+
+/// Only derive an output from an intermediate image that is at least this many times larger in
+/// each dimension, so that each resize still has plenty of source pixels to sample from.
+const MIN_CASCADE_SCALE: u32 = 2;
+
+pub struct ConversionRequest {
+    pub format: OutputFormat,
+    pub size: ImageSizeTransform,
+}
+
+/// Converts images on a dedicated thread pool, so that encoding doesn't take every CPU away from
+/// the rest of the process.
+pub struct Pipeline {
+    pool: rayon::ThreadPool,
+    resize_backend: &'static dyn ResizeBackend,
+}
+
+impl Pipeline {
+    /// Create a pipeline that encodes on `threads` threads. Zero uses one thread per CPU.
+    pub fn new(threads: usize) -> Result<Self, rayon::ThreadPoolBuildError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("convert-{i}"))
+            .build()?;
+        Ok(Self {
+            pool,
+            resize_backend: default_backend(),
+        })
+    }
+
+    /// Use a different resize implementation.
+    pub fn with_resize_backend(mut self, backend: &'static dyn ResizeBackend) -> Self {
+        self.resize_backend = backend;
+        self
+    }
+
+    /// Resize and encode an image into each of the requested outputs. The results are in the
+    /// same order as `requests`.
+    pub fn convert_all(
+        &self,
+        image: &DynamicImage,
+        source: Option<&[u8]>,
+        requests: &[ConversionRequest],
+    ) -> Vec<Result<ConvertResult, EncodeError>> {
+        let sizes = requests
+            .iter()
+            .map(|r| {
+                let (w, h) = output_size(image.width(), image.height(), &r.size);
+                (w, h, r.size.resize)
+            })
+            .collect::<Vec<_>>();
+        let resized = resize_cascade(self.resize_backend, image, &sizes);
+
+        self.pool.install(|| {
+            requests
+                .par_iter()
+                .zip(sizes.par_iter())
+                .map(|(request, size)| {
+                    encode_output(resized.get(size), source, &request.format, image)
+                })
+                .collect()
+        })
+    }
+
+    /// Render and encode an SVG into each of the requested outputs. The results are in the
+    /// same order as `requests`.
+    pub fn convert_svg_all(
+        &self,
+        svg: &[u8],
+        requests: &[ConversionRequest],
+    ) -> Vec<Result<ConvertResult, Error>> {
+        self.pool.install(|| {
+            requests
+                .par_iter()
+                .map(|request| crate::convert_svg(svg, &request.format, &request.size))
+                .collect()
+        })
+    }
+}
+
+/// Check if `width`x`height` is a scaled version of `original_width`x`original_height`, allowing
+/// for rounding.
+fn same_aspect_ratio(width: u32, height: u32, original_width: u32, original_height: u32) -> bool {
+    let expected_height = width as f64 * original_height as f64 / original_width as f64;
+    let expected_width = height as f64 * original_width as f64 / original_height as f64;
+    (expected_height - height as f64).abs() <= 1.0 && (expected_width - width as f64).abs() <= 1.0
+}
+
+/// An output size along with how to resample it.
+pub type ResizeTarget = (u32, u32, ResizeOptions);
+
+/// Resize `image` to each of `sizes`. Sizes are produced from largest to smallest, and each one
+/// is derived from the smallest previous result with the same options that is still
+/// [MIN_CASCADE_SCALE] times larger, instead of from the full image. Sizes that change the
+/// aspect ratio or enlarge the image are always resized from the original. The original size is
+/// not included in the result.
+pub fn resize_cascade(
+    backend: &dyn ResizeBackend,
+    image: &DynamicImage,
+    sizes: &[ResizeTarget],
+) -> HashMap<ResizeTarget, DynamicImage> {
+    let (iw, ih) = (image.width(), image.height());
+
+    let mut order = sizes.to_vec();
+    order.sort_unstable_by_key(|&(w, h, _)| std::cmp::Reverse(w as u64 * h as u64));
+
+    let mut resized = HashMap::new();
+    // Downscaled sizes with the original aspect ratio, from largest to smallest.
+    let mut cascade: Vec<ResizeTarget> = Vec::new();
+
+    for target in order {
+        if resized.contains_key(&target) {
+            continue;
+        }
+
+        let (w, h, options) = target;
+        let can_cascade = w <= iw && h <= ih && same_aspect_ratio(w, h, iw, ih);
+        let source = cascade
+            .iter()
+            .rev()
+            .filter(|_| can_cascade)
+            .find(|&&(cw, ch, co)| {
+                co == options && cw >= w * MIN_CASCADE_SCALE && ch >= h * MIN_CASCADE_SCALE
+            })
+            .and_then(|source| resized.get(source))
+            .unwrap_or(image);
+
+        let Some(output) = resize_exact(backend, source, w, h, &options) else {
+            continue;
+        };
+
+        if can_cascade {
+            cascade.push(target);
+        }
+        resized.insert(target, output);
+    }
+
+    resized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_format::JpegOptions;
+
+    fn request(width: u32) -> ConversionRequest {
+        ConversionRequest {
+            format: OutputFormat::Jpeg(JpegOptions::default()),
+            size: ImageSizeTransform {
+                width: Some(width),
+                height: None,
+                preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn cascade_sizes() {
+        let image = DynamicImage::new_rgb8(1000, 500);
+        let linear = ResizeOptions {
+            linear_light: true,
+            ..Default::default()
+        };
+        let sizes = [
+            (100, 50, ResizeOptions::default()),
+            (1000, 500, ResizeOptions::default()),
+            (500, 250, ResizeOptions::default()),
+            (200, 100, ResizeOptions::default()),
+            (300, 300, ResizeOptions::default()),
+            (200, 100, ResizeOptions::default()),
+            (200, 100, linear),
+        ];
+        let resized = resize_cascade(default_backend(), &image, &sizes);
+
+        assert_eq!(resized.len(), 5);
+        assert!(!resized.contains_key(&(1000, 500, ResizeOptions::default())));
+        for ((w, h, _), image) in &resized {
+            assert_eq!((*w, *h), (image.width(), image.height()));
+        }
+    }
+
+    #[test]
+    fn aspect_ratio() {
+        assert!(same_aspect_ratio(667, 445, 1334, 890));
+        assert!(same_aspect_ratio(100, 67, 150, 100));
+        assert!(!same_aspect_ratio(100, 100, 150, 100));
+    }
+
+    #[test]
+    fn convert_all_keeps_order() {
+        let image = DynamicImage::new_rgb8(800, 400);
+        let pipeline = Pipeline::new(2).unwrap();
+        let requests = [request(100), request(800), request(400), request(2000)];
+
+        let results = pipeline.convert_all(&image, None, &requests);
+        let widths = results
+            .into_iter()
+            .map(|r| r.unwrap().width)
+            .collect::<Vec<_>>();
+        assert_eq!(widths, vec![100, 800, 400, 800]);
+    }
+}
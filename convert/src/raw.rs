@@ -0,0 +1,270 @@
+//! Decoding for camera RAW files.
+
+use eyre::eyre;
+use image::{DynamicImage, ImageBuffer};
+
+const TIFF_TAG_NEW_SUBFILE_TYPE: u16 = 0x00fe;
+const TIFF_TAG_DNG_VERSION: u16 = 0xc612;
+
+fn read_u16(data: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+/// Check if a file header looks like a camera RAW file. Many RAW formats are TIFF containers,
+/// so this looks for the markers that distinguish them from a regular TIFF: the CR2 signature,
+/// a DNG version tag, or a first image that is only a thumbnail.
+pub fn is_raw(header: &[u8]) -> bool {
+    const SIGNATURES: [&[u8]; 5] = [b"FUJIFILMCCD-RAW", b"IIRO", b"IIRS", b"MMOR", b"IIU\0"];
+    if SIGNATURES.iter().any(|sig| header.starts_with(sig)) {
+        return true;
+    }
+
+    // Canon CR3 is an ISO media file with its own brand.
+    if header.get(4..12) == Some(&b"ftypcrx "[..]) {
+        return true;
+    }
+
+    let little_endian = match header.get(0..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => return false,
+    };
+
+    if header.get(8..10) == Some(&b"CR"[..]) {
+        return true;
+    }
+
+    let Some(ifd) = read_u32(header, 4, little_endian) else {
+        return false;
+    };
+    let ifd = ifd as usize;
+    let Some(count) = read_u16(header, ifd, little_endian) else {
+        return false;
+    };
+
+    (0..count as usize).map(|i| ifd + 2 + i * 12).any(|entry| {
+        match read_u16(header, entry, little_endian) {
+            Some(TIFF_TAG_DNG_VERSION) => true,
+            Some(TIFF_TAG_NEW_SUBFILE_TYPE) => {
+                // Bit 0 marks a reduced resolution version of another image.
+                let value = match read_u16(header, entry + 2, little_endian) {
+                    Some(3) => read_u16(header, entry + 8, little_endian).map(u32::from),
+                    _ => read_u32(header, entry + 8, little_endian),
+                };
+                value.map(|v| v & 1 == 1).unwrap_or(false)
+            }
+            _ => false,
+        }
+    })
+}
+
+/// A bilinear demosaic. Each output channel is the average of the pixels of that color in the
+/// surrounding 3x3 block. `color_at` returns 0, 1, or 2 for red, green, and blue.
+fn demosaic(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    color_at: impl Fn(usize, usize) -> usize,
+) -> Vec<f32> {
+    let mut output = vec![0.0; width * height * 3];
+    for row in 0..height {
+        for col in 0..width {
+            let mut sums = [0.0f32; 3];
+            let mut counts = [0u32; 3];
+            for r in row.saturating_sub(1)..(row + 2).min(height) {
+                for c in col.saturating_sub(1)..(col + 2).min(width) {
+                    let color = color_at(r, c);
+                    sums[color] += data[r * width + c];
+                    counts[color] += 1;
+                }
+            }
+
+            let out = &mut output[(row * width + col) * 3..][..3];
+            for channel in 0..3 {
+                if counts[channel] > 0 {
+                    out[channel] = sums[channel] / counts[channel] as f32;
+                }
+            }
+        }
+    }
+
+    output
+}
+
+fn srgb_gamma(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Normalize the white balance coefficients against green. Some cameras don't record them, in
+/// which case the image is left as-is.
+fn white_balance(coeffs: [f32; 4]) -> [f32; 3] {
+    let [r, g, b, _] = coeffs;
+    if [r, g, b].iter().all(|c| c.is_finite() && *c > 0.0) {
+        [r / g, 1.0, b / g]
+    } else {
+        [1.0, 1.0, 1.0]
+    }
+}
+
+/// Read the sensor data from a RAW file, without processing it into an image.
+pub fn decode_raw(bytes: &[u8]) -> eyre::Result<rawloader::RawImage> {
+    rawloader::decode(&mut std::io::Cursor::new(bytes))
+        .map_err(|e| eyre!("Failed to decode RAW image: {e:?}"))
+}
+
+pub fn load_raw(bytes: &[u8]) -> eyre::Result<DynamicImage> {
+    develop_raw(&decode_raw(bytes)?)
+}
+
+/// Process RAW sensor data into an sRGB image.
+pub fn develop_raw(raw: &rawloader::RawImage) -> eyre::Result<DynamicImage> {
+    let width = raw.width;
+    let height = raw.height;
+    let cpp = raw.cpp;
+
+    let values: Vec<f32> = match &raw.data {
+        rawloader::RawImageData::Integer(data) => data.iter().map(|&v| v as f32).collect(),
+        rawloader::RawImageData::Float(data) => data.clone(),
+    };
+
+    // Scale each sample to 0..1 using the black and white levels for its color.
+    let normalize = |value: f32, color: usize| {
+        let black = raw.blacklevels[color] as f32;
+        let white = raw.whitelevels[color] as f32;
+        ((value - black) / (white - black).max(1.0)).clamp(0.0, 1.0)
+    };
+
+    let rgb = if cpp == 3 {
+        // Already demosaiced, e.g. a linear DNG.
+        values
+            .chunks_exact(3)
+            .flat_map(|px| {
+                [
+                    normalize(px[0], 0),
+                    normalize(px[1], 1),
+                    normalize(px[2], 2),
+                ]
+            })
+            .collect::<Vec<_>>()
+    } else if cpp == 1 {
+        // Treat the second green (color 3 in four-color CFAs) as green.
+        let color_at = |row: usize, col: usize| raw.cfa.color_at(row, col).min(2);
+        let normalized = values
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| normalize(v, raw.cfa.color_at(i / width, i % width)))
+            .collect::<Vec<_>>();
+        demosaic(&normalized, width, height, color_at)
+    } else {
+        return Err(eyre!(
+            "Unsupported RAW layout with {cpp} components per pixel"
+        ));
+    };
+
+    let wb = white_balance(raw.wb_coeffs);
+
+    // Crops are top, right, bottom, left.
+    let [top, right, bottom, left] = raw.crops;
+    let out_width = width.saturating_sub(left + right);
+    let out_height = height.saturating_sub(top + bottom);
+    if out_width == 0 || out_height == 0 {
+        return Err(eyre!("RAW image has no pixels after cropping"));
+    }
+
+    let mut output = Vec::with_capacity(out_width * out_height * 3);
+    for row in top..top + out_height {
+        for col in left..left + out_width {
+            let px = &rgb[(row * width + col) * 3..][..3];
+            for channel in 0..3 {
+                let v = srgb_gamma((px[channel] * wb[channel]).clamp(0.0, 1.0));
+                output.push((v * u16::MAX as f32).round() as u16);
+            }
+        }
+    }
+
+    let image = ImageBuffer::from_raw(out_width as u32, out_height as u32, output)
+        .ok_or_else(|| eyre!("Not enough image data to match dimensions"))?;
+    Ok(DynamicImage::ImageRgb16(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_tiff_based_raw() {
+        assert!(is_raw(b"II*\0\x10\0\0\0CR\x02\0"));
+
+        // A little-endian TIFF with a DNGVersion tag.
+        let mut dng = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        dng.extend([0x12, 0xc6, 1, 0, 4, 0, 0, 0, 1, 4, 0, 0]);
+        assert!(is_raw(&dng));
+
+        // A big-endian TIFF whose first image is a thumbnail.
+        let mut nef = b"MM\0*\0\0\0\x08\0\x01".to_vec();
+        nef.extend([0, 0xfe, 0, 4, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert!(is_raw(&nef));
+    }
+
+    #[test]
+    fn detect_other_raw() {
+        assert!(is_raw(b"FUJIFILMCCD-RAW 0201"));
+        assert!(is_raw(b"\0\0\0\x18ftypcrx \0\0\0\x01"));
+    }
+
+    #[test]
+    fn plain_tiff_is_not_raw() {
+        // NewSubfileType of 0 is a full resolution image.
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        tiff.extend([0xfe, 0, 4, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(!is_raw(&tiff));
+
+        assert!(!is_raw(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[test]
+    fn demosaic_flat_color() {
+        // An RGGB pattern where red is 1.0, green is 0.5, and blue is 0.25 should demosaic to
+        // the same color everywhere.
+        let color_at = |row: usize, col: usize| match (row % 2, col % 2) {
+            (0, 0) => 0,
+            (1, 1) => 2,
+            _ => 1,
+        };
+        let width = 4;
+        let height = 4;
+        let data = (0..width * height)
+            .map(|i| [1.0, 0.5, 0.25][color_at(i / width, i % width)])
+            .collect::<Vec<_>>();
+
+        let output = demosaic(&data, width, height, color_at);
+        for px in output.chunks_exact(3) {
+            assert_eq!(px, [1.0, 0.5, 0.25]);
+        }
+    }
+
+    #[test]
+    fn white_balance_normalizes_to_green() {
+        assert_eq!(white_balance([2.0, 1.0, 1.5, f32::NAN]), [2.0, 1.0, 1.5]);
+        assert_eq!(white_balance([4.0, 2.0, 3.0, 0.0]), [2.0, 1.0, 1.5]);
+        assert_eq!(white_balance([f32::NAN; 4]), [1.0, 1.0, 1.0]);
+    }
+}
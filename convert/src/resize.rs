@@ -1,5 +1,40 @@
 use image::{imageops, DynamicImage};
 
+#[cfg(feature = "fast-resize")]
+mod fast;
+#[cfg(feature = "fast-resize")]
+pub use fast::FastResizeBackend;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    Lanczos3,
+}
+
+/// How to resample the image when resizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ResizeOptions {
+    /// The filter to use. When this is `None`, Lanczos3 is used to shrink images and Catmull-Rom
+    /// to enlarge them.
+    pub filter: Option<ResizeFilter>,
+    /// Resample in linear light instead of on the gamma-encoded sRGB values. This avoids
+    /// darkening fine bright details when downscaling, at some extra cost.
+    pub linear_light: bool,
+}
+
+impl ResizeOptions {
+    fn filter_for(&self, input_width: u32, output_width: u32) -> ResizeFilter {
+        self.filter.unwrap_or(if output_width > input_width {
+            ResizeFilter::CatmullRom
+        } else {
+            ResizeFilter::Lanczos3
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ImageSizeTransform {
     /// Desired width of the scaled object
     pub width: Option<u32>,
@@ -8,12 +43,113 @@ pub struct ImageSizeTransform {
 
     /// Preserve aspect ratio, only checked if both width and height are provided.
     pub preserve_aspect_ratio: bool,
+
+    /// Allow the output to be larger than the input. When this is false, a target size larger
+    /// than the input is scaled down to fit within the input's dimensions.
+    pub allow_upscale: bool,
+
+    pub resize: ResizeOptions,
 }
 
 pub struct ImageSpec {
     pub size: ImageSizeTransform,
 }
 
+/// An implementation of image resampling.
+pub trait ResizeBackend: Send + Sync {
+    /// Resize `input` to exactly `width`x`height`.
+    fn resize(
+        &self,
+        input: &DynamicImage,
+        width: u32,
+        height: u32,
+        options: &ResizeOptions,
+    ) -> DynamicImage;
+}
+
+/// Resizing with the `image` crate. This is portable but slow for large images.
+pub struct ImageCrateBackend;
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl ResizeBackend for ImageCrateBackend {
+    fn resize(
+        &self,
+        input: &DynamicImage,
+        width: u32,
+        height: u32,
+        options: &ResizeOptions,
+    ) -> DynamicImage {
+        let filter = match options.filter_for(input.width(), width) {
+            ResizeFilter::Nearest => imageops::FilterType::Nearest,
+            ResizeFilter::Bilinear => imageops::FilterType::Triangle,
+            ResizeFilter::CatmullRom => imageops::FilterType::CatmullRom,
+            ResizeFilter::Lanczos3 => imageops::FilterType::Lanczos3,
+        };
+
+        if !options.linear_light {
+            return input.resize_exact(width, height, filter);
+        }
+
+        let mut linear = input.to_rgba32f();
+        for px in linear.pixels_mut() {
+            for c in &mut px.0[..3] {
+                *c = srgb_to_linear(*c);
+            }
+        }
+
+        let mut output = imageops::resize(&linear, width, height, filter);
+        for px in output.pixels_mut() {
+            for c in &mut px.0[..3] {
+                *c = linear_to_srgb(c.clamp(0.0, 1.0));
+            }
+        }
+
+        // Return the same kind of image as the input, so that encoders see the expected depth
+        // and channels.
+        let output = DynamicImage::ImageRgba32F(output);
+        let color = input.color();
+        let grayscale = color.channel_count() <= 2;
+        let high_depth = color.bytes_per_pixel() / color.channel_count() > 1;
+        match (grayscale, color.has_alpha(), high_depth) {
+            (true, false, false) => DynamicImage::ImageLuma8(output.to_luma8()),
+            (true, true, false) => DynamicImage::ImageLumaA8(output.to_luma_alpha8()),
+            (true, false, true) => DynamicImage::ImageLuma16(output.to_luma16()),
+            (true, true, true) => DynamicImage::ImageLumaA16(output.to_luma_alpha16()),
+            (false, false, false) => DynamicImage::ImageRgb8(output.to_rgb8()),
+            (false, true, false) => DynamicImage::ImageRgba8(output.to_rgba8()),
+            (false, false, true) => DynamicImage::ImageRgb16(output.to_rgb16()),
+            (false, true, true) => DynamicImage::ImageRgba16(output.to_rgba16()),
+        }
+    }
+}
+
+/// The fastest backend available in this build.
+pub fn default_backend() -> &'static dyn ResizeBackend {
+    #[cfg(feature = "fast-resize")]
+    {
+        &FastResizeBackend
+    }
+    #[cfg(not(feature = "fast-resize"))]
+    {
+        &ImageCrateBackend
+    }
+}
+
 /// Calculate target dimensions to resize an image, using the desired width and height as maximum
 /// dimensions.
 fn calculate_size(width: u32, height: u32, desired: &ImageSizeTransform) -> (u32, u32) {
@@ -34,31 +170,61 @@ fn calculate_size(width: u32, height: u32, desired: &ImageSizeTransform) -> (u32
     (result.0.round() as u32, result.1.round() as u32)
 }
 
-pub fn resize_image(input: &DynamicImage, transform: &ImageSizeTransform) -> Option<DynamicImage> {
-    let tw = transform.width;
-    let th = transform.height;
-    let (iw, ih) = (input.width(), input.height());
-
-    let (w, h) = match (tw, th, transform.preserve_aspect_ratio) {
+/// Calculate the dimensions that `resize_image` will produce for an image of the given size.
+pub fn target_size(width: u32, height: u32, transform: &ImageSizeTransform) -> (u32, u32) {
+    match (
+        transform.width,
+        transform.height,
+        transform.preserve_aspect_ratio,
+    ) {
         (Some(w), Some(h), false) => (w, h),
         // No resize requested. Allow this so we don't have to check explicitly for it everywhere.
-        (None, None, _) => (iw, ih),
-        _ => calculate_size(iw, ih, transform),
-    };
+        (None, None, _) => (width, height),
+        _ => calculate_size(width, height, transform),
+    }
+}
 
-    if w == iw && h == ih {
-        None
+/// Scale the target dimensions down, preserving their aspect ratio, so that they fit within the
+/// input dimensions.
+fn fit_within(w: u32, h: u32, iw: u32, ih: u32) -> (u32, u32) {
+    let scale = (iw as f64 / w as f64).min(ih as f64 / h as f64);
+    (
+        ((w as f64 * scale).round() as u32).clamp(1, iw),
+        ((h as f64 * scale).round() as u32).clamp(1, ih),
+    )
+}
+
+/// Calculate the dimensions of the output for an input of the given size. Unlike `target_size`,
+/// this accounts for `allow_upscale`.
+pub fn output_size(width: u32, height: u32, transform: &ImageSizeTransform) -> (u32, u32) {
+    let (w, h) = target_size(width, height, transform);
+    if !transform.allow_upscale && (w > width || h > height) {
+        fit_within(w, h, width, height)
     } else {
-        let filter_type = if w > iw {
-            imageops::FilterType::CatmullRom
-        } else {
-            imageops::FilterType::Lanczos3
-        };
+        (w, h)
+    }
+}
 
-        Some(input.resize_exact(w, h, filter_type))
+/// Resize an image to exact dimensions, returning `None` if it is already that size.
+pub fn resize_exact(
+    backend: &dyn ResizeBackend,
+    input: &DynamicImage,
+    width: u32,
+    height: u32,
+    options: &ResizeOptions,
+) -> Option<DynamicImage> {
+    if width == input.width() && height == input.height() {
+        None
+    } else {
+        Some(backend.resize(input, width, height, options))
     }
 }
 
+pub fn resize_image(input: &DynamicImage, transform: &ImageSizeTransform) -> Option<DynamicImage> {
+    let (w, h) = output_size(input.width(), input.height(), transform);
+    resize_exact(default_backend(), input, w, h, &transform.resize)
+}
+
 #[cfg(test)]
 mod tests {
     use image::DynamicImage;
@@ -74,6 +240,8 @@ mod tests {
                 width: Some(200),
                 height: Some(125),
                 preserve_aspect_ratio: false,
+                allow_upscale: true,
+                ..Default::default()
             },
         )
         .unwrap();
@@ -82,6 +250,42 @@ mod tests {
         assert_eq!(output.height(), 125, "height");
     }
 
+    #[test]
+    fn no_upscale() {
+        let image = DynamicImage::new_rgb8(100, 100);
+        let output = resize_image(
+            &image,
+            &ImageSizeTransform {
+                width: Some(300),
+                height: None,
+                preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
+            },
+        );
+
+        assert!(output.is_none(), "Should return None");
+    }
+
+    #[test]
+    fn no_upscale_no_preserve_aspect() {
+        let image = DynamicImage::new_rgb8(100, 100);
+        let output = resize_image(
+            &image,
+            &ImageSizeTransform {
+                width: Some(200),
+                height: Some(125),
+                preserve_aspect_ratio: false,
+                allow_upscale: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(output.width(), 100, "width");
+        assert_eq!(output.height(), 63, "height");
+    }
+
     #[test]
     fn nothing_to_do() {
         let image = DynamicImage::new_rgb8(100, 100);
@@ -91,6 +295,8 @@ mod tests {
                 width: Some(100),
                 height: Some(100),
                 preserve_aspect_ratio: false,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -106,6 +312,8 @@ mod tests {
                 width: None,
                 height: None,
                 preserve_aspect_ratio: false,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -121,6 +329,8 @@ mod tests {
                 width: Some(150),
                 height: Some(200),
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -136,6 +346,8 @@ mod tests {
                 width: Some(400),
                 height: Some(100),
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -151,6 +363,8 @@ mod tests {
                 width: Some(400),
                 height: Some(1000),
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -166,6 +380,8 @@ mod tests {
                 width: Some(4000),
                 height: Some(200),
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -181,6 +397,8 @@ mod tests {
                 width: Some(300),
                 height: None,
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -196,6 +414,8 @@ mod tests {
                 width: None,
                 height: Some(400),
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -211,6 +431,8 @@ mod tests {
                 width: Some(100),
                 height: None,
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
@@ -226,9 +448,102 @@ mod tests {
                 width: None,
                 height: Some(50),
                 preserve_aspect_ratio: true,
+                allow_upscale: false,
+                ..Default::default()
             },
         );
 
         assert_eq!(output, (75, 50));
     }
+
+    fn backends() -> Vec<&'static dyn ResizeBackend> {
+        vec![
+            &ImageCrateBackend,
+            #[cfg(feature = "fast-resize")]
+            &FastResizeBackend,
+        ]
+    }
+
+    #[test]
+    fn all_backends() {
+        let mut image = image::RgbaImage::new(64, 48);
+        for (x, y, px) in image.enumerate_pixels_mut() {
+            *px = image::Rgba([(x * 4) as u8, (y * 5) as u8, 128, 255]);
+        }
+        let image = DynamicImage::ImageRgba8(image);
+
+        let filters = [
+            None,
+            Some(ResizeFilter::Nearest),
+            Some(ResizeFilter::Bilinear),
+            Some(ResizeFilter::CatmullRom),
+            Some(ResizeFilter::Lanczos3),
+        ];
+
+        for backend in backends() {
+            for filter in filters {
+                for linear_light in [false, true] {
+                    let options = ResizeOptions {
+                        filter,
+                        linear_light,
+                    };
+                    for (w, h) in [(32, 24), (100, 75), (10, 40)] {
+                        let output = resize_exact(backend, &image, w, h, &options).unwrap();
+                        assert_eq!((output.width(), output.height()), (w, h));
+                        assert_eq!(output.color(), image.color());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_grayscale_layout() {
+        let gray = image::GrayImage::from_fn(64, 48, |x, y| image::Luma([(x * 2 + y) as u8]));
+        let gray = DynamicImage::ImageLuma8(gray);
+        let images = [
+            gray.clone(),
+            DynamicImage::ImageLumaA8(gray.to_luma_alpha8()),
+            DynamicImage::ImageLuma16(gray.to_luma16()),
+            DynamicImage::ImageLumaA16(gray.to_luma_alpha16()),
+        ];
+
+        for backend in backends() {
+            for image in &images {
+                for linear_light in [false, true] {
+                    let options = ResizeOptions {
+                        filter: None,
+                        linear_light,
+                    };
+                    let output = backend.resize(image, 32, 24, &options);
+                    assert_eq!((output.width(), output.height()), (32, 24));
+                    assert_eq!(output.color(), image.color());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn linear_light_keeps_brightness() {
+        // Alternating black and white columns average to 50% linear light, which is about 188
+        // in sRGB. Averaging the sRGB values directly gives 128.
+        let image = image::RgbImage::from_fn(64, 64, |x, _| {
+            if x % 2 == 0 {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        });
+        let image = DynamicImage::ImageRgb8(image);
+        let options = ResizeOptions {
+            filter: Some(ResizeFilter::Bilinear),
+            linear_light: true,
+        };
+
+        for backend in backends() {
+            let output = backend.resize(&image, 8, 8, &options).to_rgb8();
+            let value = output.get_pixel(4, 4).0[0];
+            assert!((180..=195).contains(&value), "value {value}");
+        }
+    }
 }
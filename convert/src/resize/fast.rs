@@ -0,0 +1,200 @@
+//! A SIMD resize backend using `fast_image_resize`.
+
+use std::num::NonZeroU32;
+
+use fast_image_resize as fr;
+use image::{DynamicImage, ImageBuffer};
+
+use super::{ResizeBackend, ResizeFilter, ResizeOptions};
+
+pub struct FastResizeBackend;
+
+/// The pixel layouts this backend works in. Other images are converted to the closest one.
+#[derive(Clone, Copy)]
+enum Layout {
+    Luma8,
+    LumaA8,
+    Luma16,
+    LumaA16,
+    Rgb8,
+    Rgba8,
+    Rgb16,
+    Rgba16,
+}
+
+impl Layout {
+    fn for_image(image: &DynamicImage) -> Layout {
+        let color = image.color();
+        let grayscale = color.channel_count() <= 2;
+        let high_depth = color.bytes_per_pixel() / color.channel_count() > 1;
+        match (grayscale, color.has_alpha(), high_depth) {
+            (true, false, false) => Layout::Luma8,
+            (true, true, false) => Layout::LumaA8,
+            (true, false, true) => Layout::Luma16,
+            (true, true, true) => Layout::LumaA16,
+            (false, false, false) => Layout::Rgb8,
+            (false, true, false) => Layout::Rgba8,
+            (false, false, true) => Layout::Rgb16,
+            (false, true, true) => Layout::Rgba16,
+        }
+    }
+
+    fn pixel_type(&self) -> fr::PixelType {
+        match self {
+            Layout::Luma8 => fr::PixelType::U8,
+            Layout::LumaA8 => fr::PixelType::U8x2,
+            Layout::Luma16 => fr::PixelType::U16,
+            Layout::LumaA16 => fr::PixelType::U16x2,
+            Layout::Rgb8 => fr::PixelType::U8x3,
+            Layout::Rgba8 => fr::PixelType::U8x4,
+            Layout::Rgb16 => fr::PixelType::U16x3,
+            Layout::Rgba16 => fr::PixelType::U16x4,
+        }
+    }
+
+    /// The type used to hold linear light values. 8 bits isn't enough to hold dark colors
+    /// without banding, so this always uses 16 bits.
+    fn linear_pixel_type(&self) -> fr::PixelType {
+        match self {
+            Layout::Luma8 | Layout::Luma16 => fr::PixelType::U16,
+            Layout::LumaA8 | Layout::LumaA16 => fr::PixelType::U16x2,
+            Layout::Rgb8 | Layout::Rgb16 => fr::PixelType::U16x3,
+            Layout::Rgba8 | Layout::Rgba16 => fr::PixelType::U16x4,
+        }
+    }
+
+    fn has_alpha(&self) -> bool {
+        matches!(
+            self,
+            Layout::LumaA8 | Layout::LumaA16 | Layout::Rgba8 | Layout::Rgba16
+        )
+    }
+
+    fn image_bytes(&self, image: &DynamicImage) -> Vec<u8> {
+        match self {
+            Layout::Luma8 => image.to_luma8().into_raw(),
+            Layout::LumaA8 => image.to_luma_alpha8().into_raw(),
+            Layout::Luma16 => u16_to_bytes(image.to_luma16().into_raw()),
+            Layout::LumaA16 => u16_to_bytes(image.to_luma_alpha16().into_raw()),
+            Layout::Rgb8 => image.to_rgb8().into_raw(),
+            Layout::Rgba8 => image.to_rgba8().into_raw(),
+            Layout::Rgb16 => u16_to_bytes(image.to_rgb16().into_raw()),
+            Layout::Rgba16 => u16_to_bytes(image.to_rgba16().into_raw()),
+        }
+    }
+
+    fn build_image(&self, width: u32, height: u32, bytes: Vec<u8>) -> DynamicImage {
+        let image = match self {
+            Layout::Luma8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLuma8)
+            }
+            Layout::LumaA8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageLumaA8)
+            }
+            Layout::Luma16 => ImageBuffer::from_raw(width, height, bytes_to_u16(&bytes))
+                .map(DynamicImage::ImageLuma16),
+            Layout::LumaA16 => ImageBuffer::from_raw(width, height, bytes_to_u16(&bytes))
+                .map(DynamicImage::ImageLumaA16),
+            Layout::Rgb8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgb8)
+            }
+            Layout::Rgba8 => {
+                ImageBuffer::from_raw(width, height, bytes).map(DynamicImage::ImageRgba8)
+            }
+            Layout::Rgb16 => ImageBuffer::from_raw(width, height, bytes_to_u16(&bytes))
+                .map(DynamicImage::ImageRgb16),
+            Layout::Rgba16 => ImageBuffer::from_raw(width, height, bytes_to_u16(&bytes))
+                .map(DynamicImage::ImageRgba16),
+        };
+
+        image.expect("resized buffer matches dimensions")
+    }
+}
+
+fn u16_to_bytes(data: Vec<u16>) -> Vec<u8> {
+    data.into_iter().flat_map(u16::to_ne_bytes).collect()
+}
+
+fn bytes_to_u16(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+        .collect()
+}
+
+fn algorithm(filter: ResizeFilter) -> fr::ResizeAlg {
+    match filter {
+        ResizeFilter::Nearest => fr::ResizeAlg::Nearest,
+        ResizeFilter::Bilinear => fr::ResizeAlg::Convolution(fr::FilterType::Bilinear),
+        ResizeFilter::CatmullRom => fr::ResizeAlg::Convolution(fr::FilterType::CatmullRom),
+        ResizeFilter::Lanczos3 => fr::ResizeAlg::Convolution(fr::FilterType::Lanczos3),
+    }
+}
+
+fn non_zero(value: u32) -> NonZeroU32 {
+    NonZeroU32::new(value.max(1)).unwrap()
+}
+
+impl ResizeBackend for FastResizeBackend {
+    fn resize(
+        &self,
+        input: &DynamicImage,
+        width: u32,
+        height: u32,
+        options: &ResizeOptions,
+    ) -> DynamicImage {
+        let layout = Layout::for_image(input);
+        let src_width = non_zero(input.width());
+        let src_height = non_zero(input.height());
+        let dst_width = non_zero(width);
+        let dst_height = non_zero(height);
+
+        let mut src = fr::Image::from_vec_u8(
+            src_width,
+            src_height,
+            layout.image_bytes(input),
+            layout.pixel_type(),
+        )
+        .expect("source buffer matches dimensions");
+
+        // Work in linear light if requested. The alpha channel is left as-is by the mapper.
+        let mapper = fr::create_srgb_mapper();
+        if options.linear_light {
+            let mut linear = fr::Image::new(src_width, src_height, layout.linear_pixel_type());
+            mapper
+                .forward_map(&src.view(), &mut linear.view_mut())
+                .expect("mapping to linear light");
+            src = linear;
+        }
+
+        // Resample with premultiplied alpha, so that transparent pixels don't bleed their color
+        // into their neighbors.
+        let mul_div = fr::MulDiv::default();
+        if layout.has_alpha() {
+            mul_div
+                .multiply_alpha_inplace(&mut src.view_mut())
+                .expect("premultiplying alpha");
+        }
+
+        let mut dst = fr::Image::new(dst_width, dst_height, src.pixel_type());
+        let mut resizer = fr::Resizer::new(algorithm(options.filter_for(input.width(), width)));
+        resizer
+            .resize(&src.view(), &mut dst.view_mut())
+            .expect("source and destination pixel types match");
+
+        if layout.has_alpha() {
+            mul_div
+                .divide_alpha_inplace(&mut dst.view_mut())
+                .expect("dividing alpha");
+        }
+
+        if options.linear_light {
+            let mut encoded = fr::Image::new(dst_width, dst_height, layout.pixel_type());
+            mapper
+                .backward_map(&dst.view(), &mut encoded.view_mut())
+                .expect("mapping from linear light");
+            dst = encoded;
+        }
+
+        layout.build_image(width.max(1), height.max(1), dst.into_vec())
+    }
+}
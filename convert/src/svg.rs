@@ -0,0 +1,377 @@
+//! SVG sanitizing and rasterization.
+
+use std::borrow::Cow;
+
+use eyre::eyre;
+use image::{DynamicImage, RgbaImage};
+use resvg::{tiny_skia, usvg, usvg::TreeParsing};
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+/// Elements that are kept, in lowercase. Anything else, including scripts, foreign content, and
+/// animations that could change attributes after sanitizing, is removed along with everything
+/// inside it.
+const ALLOWED_ELEMENTS: [&str; 56] = [
+    "svg",
+    "g",
+    "defs",
+    "symbol",
+    "use",
+    "title",
+    "desc",
+    "metadata",
+    "switch",
+    "view",
+    "a",
+    "path",
+    "rect",
+    "circle",
+    "ellipse",
+    "line",
+    "polyline",
+    "polygon",
+    "text",
+    "tspan",
+    "textpath",
+    "image",
+    "style",
+    "lineargradient",
+    "radialgradient",
+    "stop",
+    "pattern",
+    "clippath",
+    "mask",
+    "marker",
+    "filter",
+    "feblend",
+    "fecolormatrix",
+    "fecomponenttransfer",
+    "fecomposite",
+    "feconvolvematrix",
+    "fediffuselighting",
+    "fedisplacementmap",
+    "fedistantlight",
+    "fedropshadow",
+    "feflood",
+    "fefunca",
+    "fefuncb",
+    "fefuncg",
+    "fefuncr",
+    "fegaussianblur",
+    "feimage",
+    "femerge",
+    "femergenode",
+    "femorphology",
+    "feoffset",
+    "fepointlight",
+    "fespecularlighting",
+    "fespotlight",
+    "fetile",
+    "feturbulence",
+];
+
+/// Check if a file header looks like an SVG document.
+pub fn is_svg(header: &[u8]) -> bool {
+    let header = header.strip_prefix(b"\xef\xbb\xbf").unwrap_or(header);
+    let Some(start) = header.iter().position(|b| !b.is_ascii_whitespace()) else {
+        return false;
+    };
+
+    let header = String::from_utf8_lossy(&header[start..]).to_ascii_lowercase();
+    let xml_start = ["<?xml", "<svg", "<!--", "<!doctype svg"]
+        .iter()
+        .any(|prefix| header.starts_with(prefix));
+
+    xml_start && header.contains("<svg")
+}
+
+/// Decode the character references and predefined entities in an attribute value or text, so
+/// that checks see what the browser will. Returns `None` if it refers to any other entity, since
+/// the DTD that would define it is removed.
+fn unescape(text: &str) -> Option<Cow<'_, str>> {
+    if !text.contains('&') {
+        return Some(Cow::Borrowed(text));
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let end = rest.find(';')?;
+        let c = match &rest[..end] {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            reference => {
+                let code = reference.strip_prefix('#')?;
+                let code = match code.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => code.parse().ok()?,
+                };
+                char::from_u32(code)?
+            }
+        };
+        output.push(c);
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+
+    Some(Cow::Owned(output))
+}
+
+/// Check if a CSS fragment loads anything from outside the document.
+fn has_external_css_reference(css: &str) -> bool {
+    let css = css.to_ascii_lowercase();
+    // CSS escapes could spell out anything below, so they aren't allowed at all.
+    if css.contains("@import") || css.contains('\\') {
+        return true;
+    }
+
+    css.match_indices("url(").any(|(i, m)| {
+        let target = css[i + m.len()..]
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '"' || c == '\'');
+        !target.starts_with('#') && !target.starts_with("data:image/")
+    })
+}
+
+/// Check if a value contains a `javascript:` URL. Browsers ignore tabs and newlines inside URLs,
+/// so those are ignored here too.
+fn has_javascript_url(value: &str) -> bool {
+    value
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control())
+        .collect::<String>()
+        .to_ascii_lowercase()
+        .contains("javascript:")
+}
+
+fn is_allowed_href(value: &str) -> bool {
+    let value = value.trim().to_ascii_lowercase();
+    value.starts_with('#')
+        || (value.starts_with("data:image/") && !value.starts_with("data:image/svg"))
+}
+
+/// Remove scripts, animations, event handlers, and references to external resources from an SVG,
+/// so that it is safe to serve from our own domain. Only known static elements are kept, and
+/// values are checked after decoding their entities. Comments, DTDs and processing instructions
+/// are removed as well.
+pub fn sanitize(svg: &[u8]) -> eyre::Result<Vec<u8>> {
+    let text = std::str::from_utf8(svg).map_err(|_| eyre!("SVG is not valid UTF-8"))?;
+
+    let mut output = String::with_capacity(text.len());
+    // How many levels deep we are inside a blocked element.
+    let mut skip_depth = 0usize;
+    let mut in_style = false;
+
+    for token in Tokenizer::from(text) {
+        let token = token?;
+        if skip_depth > 0 {
+            match token {
+                Token::ElementStart { .. } => skip_depth += 1,
+                Token::ElementEnd {
+                    end: ElementEnd::Empty | ElementEnd::Close(..),
+                    ..
+                } => skip_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+
+        match token {
+            Token::Declaration { span, .. } => output.push_str(span.as_str()),
+            Token::ElementStart { local, span, .. } => {
+                let name = local.as_str().to_ascii_lowercase();
+                if !ALLOWED_ELEMENTS.contains(&name.as_str()) {
+                    skip_depth = 1;
+                } else {
+                    in_style = name == "style";
+                    output.push_str(span.as_str());
+                }
+            }
+            Token::Attribute {
+                local, value, span, ..
+            } => {
+                let name = local.as_str().to_ascii_lowercase();
+                let blocked = match unescape(value.as_str()) {
+                    Some(value) => {
+                        name.starts_with("on")
+                            || (name == "href" && !is_allowed_href(&value))
+                            || (name == "style" && has_external_css_reference(&value))
+                            || has_javascript_url(&value)
+                    }
+                    None => true,
+                };
+
+                if !blocked {
+                    output.push(' ');
+                    output.push_str(span.as_str());
+                }
+            }
+            Token::ElementEnd { end, span } => {
+                if matches!(end, ElementEnd::Close(..) | ElementEnd::Empty) {
+                    in_style = false;
+                }
+                output.push_str(span.as_str());
+            }
+            Token::Text { text } => {
+                let blocked = in_style
+                    && match unescape(text.as_str()) {
+                        Some(css) => has_external_css_reference(&css),
+                        None => true,
+                    };
+                if !blocked {
+                    output.push_str(text.as_str());
+                }
+            }
+            Token::Cdata { text, span } => {
+                // CDATA isn't unescaped.
+                if !(in_style && has_external_css_reference(text.as_str())) {
+                    output.push_str(span.as_str());
+                }
+            }
+            Token::Comment { .. }
+            | Token::ProcessingInstruction { .. }
+            | Token::DtdStart { .. }
+            | Token::EmptyDtd { .. }
+            | Token::EntityDeclaration { .. }
+            | Token::DtdEnd { .. } => {}
+        }
+    }
+
+    Ok(output.into_bytes())
+}
+
+fn parse(svg: &[u8]) -> eyre::Result<usvg::Tree> {
+    usvg::Tree::from_data(svg, &usvg::Options::default())
+        .map_err(|e| eyre!("Failed to parse SVG: {e}"))
+}
+
+/// The intrinsic size of an SVG, rounded up to whole pixels.
+pub fn svg_size(svg: &[u8]) -> eyre::Result<(u32, u32)> {
+    let tree = parse(svg)?;
+    Ok((
+        tree.size.width().ceil() as u32,
+        tree.size.height().ceil() as u32,
+    ))
+}
+
+/// Render an SVG at the given size. The image is stretched to fill the size, so callers should
+/// preserve the aspect ratio themselves if needed. Text is not rendered since no fonts are
+/// loaded.
+pub fn rasterize(svg: &[u8], width: u32, height: u32) -> eyre::Result<DynamicImage> {
+    let tree = parse(svg)?;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| eyre!("Invalid SVG output size {width}x{height}"))?;
+
+    let transform = tiny_skia::Transform::from_scale(
+        width as f32 / tree.size.width(),
+        height as f32 / tree.size.height(),
+    );
+    resvg::Tree::from_usvg(&tree).render(transform, &mut pixmap.as_mut());
+
+    // tiny-skia uses premultiplied alpha.
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect::<Vec<_>>();
+
+    let image = RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| eyre!("Not enough image data to match dimensions"))?;
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGO: &str = r##"<?xml version="1.0"?>
+<!DOCTYPE svg [<!ENTITY x "y">]>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="40" height="20" onload="alert(1)">
+  <!-- a comment -->
+  <script>alert(1)</script>
+  <style>@import url(https://evil.example/x.css);</style>
+  <defs><linearGradient id="g"><stop offset="0" stop-color="red"/></linearGradient></defs>
+  <rect width="40" height="20" fill="url(#g)" style="fill: url(https://evil.example/a)"/>
+  <use xlink:href="#g"/>
+  <image href="https://evil.example/track.png" width="1" height="1"/>
+  <a href="javascript:alert(1)"><circle r="5" fill="blue"/></a>
+  <foreignObject><div xmlns="http://www.w3.org/1999/xhtml"><p>hi</p></div></foreignObject>
+</svg>"##;
+
+    #[test]
+    fn detect_svg() {
+        assert!(is_svg(LOGO.as_bytes()));
+        assert!(is_svg(
+            b"\xef\xbb\xbf  <svg xmlns=\"http://www.w3.org/2000/svg\"/>"
+        ));
+        assert!(!is_svg(b"<?xml version=\"1.0\"?><rss></rss>"));
+        assert!(!is_svg(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[test]
+    fn sanitize_removes_active_content() {
+        let output = String::from_utf8(sanitize(LOGO.as_bytes()).unwrap()).unwrap();
+
+        for blocked in [
+            "script",
+            "alert",
+            "onload",
+            "evil.example",
+            "foreignObject",
+            "ENTITY",
+            "comment",
+        ] {
+            assert!(!output.contains(blocked), "{blocked} in {output}");
+        }
+
+        assert!(output.contains(r##"fill="url(#g)""##));
+        assert!(output.contains(r##"xlink:href="#g""##));
+        assert!(output.contains("<circle"));
+
+        // The result should still be a usable image.
+        assert_eq!(svg_size(output.as_bytes()).unwrap(), (40, 20));
+    }
+
+    #[test]
+    fn sanitize_checks_decoded_values() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="10" height="10">
+  <a><set attributeName="href" to="javascript&#58;alert(1)"/><circle r="5"/></a>
+  <a xlink:href="#x"><animate attributeName="xlink:href" values="javascript:alert(2)"/></a>
+  <a href="java&#x09;script&#x3A;alert(3)"><rect width="1" height="1"/></a>
+  <image href="&#104;ttps://evil.example/track.png"/>
+  <style>&#64;import url(https://evil.example/x.css);</style>
+  <rect style="fill: u\72 l(https://evil.example/a)" width="1" height="1"/>
+  <rect fill="&amp;" width="2" height="2"/>
+</svg>"##;
+        let output = String::from_utf8(sanitize(svg.as_bytes()).unwrap()).unwrap();
+
+        for blocked in ["<set", "<animate", "alert", "evil.example", "import"] {
+            assert!(!output.contains(blocked), "{blocked} in {output}");
+        }
+
+        assert!(output.contains("<circle"));
+        assert!(output.contains(r##"xlink:href="#x""##));
+        assert!(output.contains(r#"fill="&amp;""#));
+    }
+
+    #[test]
+    fn unescape_references() {
+        assert_eq!(unescape("a&lt;b&#58;&#x3A;&amp;").unwrap(), "a<b::&");
+        assert_eq!(unescape("plain").unwrap(), "plain");
+        assert!(unescape("&custom;").is_none());
+        assert!(unescape("&#xZZ;").is_none());
+    }
+
+    #[test]
+    fn rasterize_svg() {
+        let image = rasterize(LOGO.as_bytes(), 80, 40).unwrap();
+        assert_eq!(image.width(), 80);
+        assert_eq!(image.height(), 40);
+    }
+}
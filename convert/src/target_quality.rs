@@ -0,0 +1,152 @@
+use image::DynamicImage;
+use rgb::FromSlice;
+
+use crate::write_format::{write_image, EncodeError, OutputFormat, TargetQuality};
+
+pub struct TargetQualityResult {
+    pub image: Vec<u8>,
+    pub quality: f32,
+    pub dssim: f64,
+}
+
+fn dssim_image(
+    dssim: &dssim_core::Dssim,
+    image: &DynamicImage,
+) -> Option<dssim_core::DssimImage<f32>> {
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+    if image.color().has_alpha() {
+        let pixels = image.to_rgba8();
+        dssim.create_image_rgba(pixels.as_raw().as_rgba(), width, height)
+    } else {
+        let pixels = image.to_rgb8();
+        dssim.create_image_rgb(pixels.as_raw().as_rgb(), width, height)
+    }
+}
+
+/// Binary search the encoder quality for the smallest value whose output stays within the
+/// target DSSIM of `image`. If no quality in the range meets the target, the output at the
+/// maximum quality is returned.
+pub fn encode_to_target(
+    image: &DynamicImage,
+    format: &OutputFormat,
+    target: &TargetQuality,
+) -> Result<TargetQualityResult, EncodeError> {
+    let dssim = dssim_core::Dssim::new();
+    let source = dssim_image(&dssim, image)
+        .ok_or_else(|| EncodeError::StringError("Image too small to compare".to_string()))?;
+
+    let encode = |quality: f32| -> Result<TargetQualityResult, EncodeError> {
+        let mut output = Vec::new();
+        write_image(image, &format.with_quality(quality), &mut output)?;
+
+        let decoded = crate::image_from_bytes(&output)
+            .map_err(|e| EncodeError::StringError(format!("Decoding candidate: {e}")))?;
+        let candidate = dssim_image(&dssim, &decoded).ok_or_else(|| {
+            EncodeError::StringError("Candidate image too small to compare".to_string())
+        })?;
+        let (score, _) = dssim.compare(&source, candidate);
+
+        Ok(TargetQualityResult {
+            image: output,
+            quality,
+            dssim: score.into(),
+        })
+    };
+
+    let mut low = target.min_quality.clamp(0.0, 100.0).round() as u32;
+    let mut high = target.max_quality.clamp(0.0, 100.0).round() as u32;
+    if low > high {
+        std::mem::swap(&mut low, &mut high);
+    }
+
+    let mut best: Option<TargetQualityResult> = None;
+    let mut last_rejected: Option<TargetQualityResult> = None;
+    while low <= high {
+        let mid = low + (high - low) / 2;
+        let result = encode(mid as f32)?;
+        if result.dssim <= target.dssim {
+            best = Some(result);
+            if mid == 0 {
+                break;
+            }
+            high = mid - 1;
+        } else {
+            low = mid + 1;
+            last_rejected = Some(result);
+        }
+    }
+
+    match (best, last_rejected) {
+        (Some(best), _) => Ok(best),
+        // The search only gets here when every candidate failed, and the last one tried was
+        // the highest quality.
+        (None, Some(rejected)) => Ok(rejected),
+        (None, None) => encode(target.max_quality),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::write_format::JpegOptions;
+
+    fn read_test_image(filename: &str) -> DynamicImage {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures")
+            .join(filename);
+        image::open(path).expect("reading image")
+    }
+
+    fn jpeg() -> OutputFormat {
+        OutputFormat::Jpeg(JpegOptions::default())
+    }
+
+    #[test]
+    fn meets_target() {
+        let image = read_test_image("test-input.png");
+        let target = TargetQuality {
+            dssim: 0.005,
+            min_quality: 10.0,
+            max_quality: 95.0,
+        };
+
+        let result = encode_to_target(&image, &jpeg(), &target).unwrap();
+        assert!(result.dssim <= target.dssim, "dssim {}", result.dssim);
+        assert!(result.quality >= 10.0 && result.quality <= 95.0);
+    }
+
+    #[test]
+    fn stricter_target_uses_higher_quality() {
+        let image = read_test_image("test-input.png");
+        let loose = TargetQuality {
+            dssim: 0.01,
+            min_quality: 10.0,
+            max_quality: 95.0,
+        };
+        let strict = TargetQuality {
+            dssim: 0.001,
+            ..loose
+        };
+
+        let loose = encode_to_target(&image, &jpeg(), &loose).unwrap();
+        let strict = encode_to_target(&image, &jpeg(), &strict).unwrap();
+        assert!(strict.quality >= loose.quality);
+        assert!(strict.image.len() >= loose.image.len());
+    }
+
+    #[test]
+    fn unreachable_target_uses_max_quality() {
+        let image = read_test_image("test-input.png");
+        let target = TargetQuality {
+            dssim: 0.0,
+            min_quality: 50.0,
+            max_quality: 60.0,
+        };
+
+        let result = encode_to_target(&image, &jpeg(), &target).unwrap();
+        assert_eq!(result.quality, 60.0);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{borrow::Cow, io::Write};
 
-use image::{DynamicImage, GenericImageView, ImageEncoder, ImageFormat};
+use image::{DynamicImage, GenericImageView, ImageEncoder};
 use rgb::FromSlice;
 use thiserror::Error;
 
@@ -13,23 +13,217 @@ fn to_8bit(image: &'_ DynamicImage) -> Cow<'_, DynamicImage> {
     }
 }
 
-fn write_png(image: &DynamicImage, writer: impl Write) -> Result<(), image::ImageError> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    Yuv444,
+    Yuv422,
+    Yuv420,
+}
+
+/// Search for the lowest quality whose output is within `dssim` of the source image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TargetQuality {
+    pub dssim: f64,
+    pub min_quality: f32,
+    pub max_quality: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    Fast,
+    Default,
+    #[default]
+    Best,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PngOptions {
+    pub compression: PngCompression,
+    /// Quantize to a palette with at most this many colors.
+    pub palette_colors: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JpegOptions {
+    pub quality: Option<f32>,
+    pub target_quality: Option<TargetQuality>,
+    pub progressive: bool,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WebpOptions {
+    pub quality: Option<f32>,
+    pub target_quality: Option<TargetQuality>,
+    pub method: Option<u8>,
+    pub lossless: bool,
+    pub near_lossless: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AvifOptions {
+    pub quality: Option<f32>,
+    pub target_quality: Option<TargetQuality>,
+    pub speed: Option<u8>,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub bit_depth: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JxlOptions {
+    pub quality: Option<f32>,
+    pub target_quality: Option<TargetQuality>,
+    /// Encoder effort from 1 to 9.
+    pub effort: Option<u8>,
+    pub lossless: bool,
+    /// Losslessly recompress JPEG input when possible. See [crate::convert].
+    pub lossless_jpeg: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeicOptions {
+    pub quality: Option<f32>,
+    pub target_quality: Option<TargetQuality>,
+    pub lossless: bool,
+}
+
+/// An output format along with the encoder settings to use for it.
+#[derive(Debug, Clone)]
+pub enum OutputFormat {
+    Png(PngOptions),
+    Jpeg(JpegOptions),
+    Webp(WebpOptions),
+    Avif(AvifOptions),
+    Jxl(JxlOptions),
+    Heic(HeicOptions),
+}
+
+impl OutputFormat {
+    pub fn quality(&self) -> Option<f32> {
+        match self {
+            Self::Png(_) => None,
+            Self::Jpeg(o) => o.quality,
+            Self::Webp(o) => o.quality,
+            Self::Avif(o) => o.quality,
+            Self::Jxl(o) => o.quality,
+            Self::Heic(o) => o.quality,
+        }
+    }
+
+    pub fn target_quality(&self) -> Option<&TargetQuality> {
+        match self {
+            Self::Png(_) => None,
+            Self::Jpeg(o) => o.target_quality.as_ref(),
+            // Quality only controls effort for lossless WebP, so there is nothing to search.
+            Self::Webp(o) if o.lossless => None,
+            Self::Webp(o) => o.target_quality.as_ref(),
+            Self::Avif(o) => o.target_quality.as_ref(),
+            Self::Jxl(o) if o.lossless => None,
+            Self::Jxl(o) => o.target_quality.as_ref(),
+            Self::Heic(o) if o.lossless => None,
+            Self::Heic(o) => o.target_quality.as_ref(),
+        }
+    }
+
+    /// Return a copy of this format with a fixed quality setting.
+    pub fn with_quality(&self, quality: f32) -> OutputFormat {
+        let mut format = self.clone();
+        match &mut format {
+            Self::Png(_) => {}
+            Self::Jpeg(o) => o.quality = Some(quality),
+            Self::Webp(o) => o.quality = Some(quality),
+            Self::Avif(o) => o.quality = Some(quality),
+            Self::Jxl(o) => o.quality = Some(quality),
+            Self::Heic(o) => o.quality = Some(quality),
+        }
+        format
+    }
+}
+
+fn write_png(
+    image: &DynamicImage,
+    options: &PngOptions,
+    writer: impl Write,
+) -> Result<(), EncodeError> {
+    if let Some(colors) = options.palette_colors {
+        return write_png_palette(image, options, colors, writer);
+    }
+
+    let compression = match options.compression {
+        PngCompression::Fast => image::codecs::png::CompressionType::Fast,
+        PngCompression::Default => image::codecs::png::CompressionType::Default,
+        PngCompression::Best => image::codecs::png::CompressionType::Best,
+    };
+
     let encoder = image::codecs::png::PngEncoder::new_with_quality(
         writer,
-        image::codecs::png::CompressionType::Best,
+        compression,
         image::codecs::png::FilterType::Adaptive,
     );
 
     let image = to_8bit(image);
     let (width, height) = image.dimensions();
-    encoder.write_image(image.as_bytes(), width, height, image.color())
+    encoder.write_image(image.as_bytes(), width, height, image.color())?;
+    Ok(())
+}
+
+fn write_png_palette(
+    image: &DynamicImage,
+    options: &PngOptions,
+    colors: u16,
+    writer: impl Write,
+) -> Result<(), EncodeError> {
+    if !(2..=256).contains(&colors) {
+        return Err(EncodeError::InvalidOption(format!(
+            "PNG palette must have between 2 and 256 colors, got {colors}"
+        )));
+    }
+
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let pixels = rgba.as_raw();
+
+    // Sample factor 10 is the recommended tradeoff between speed and quality.
+    let quantizer = color_quant::NeuQuant::new(10, colors as usize, pixels);
+    let indexes = pixels
+        .chunks_exact(4)
+        .map(|pixel| quantizer.index_of(pixel) as u8)
+        .collect::<Vec<_>>();
+
+    let color_map = quantizer.color_map_rgba();
+    let palette = color_map
+        .chunks_exact(4)
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect::<Vec<_>>();
+
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match options.compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    encoder.set_palette(palette);
+    if image.color().has_alpha() {
+        let trns = color_map.chunks_exact(4).map(|c| c[3]).collect::<Vec<_>>();
+        encoder.set_trns(trns);
+    }
+
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| EncodeError::StringError(e.to_string()))?;
+    writer
+        .write_image_data(&indexes)
+        .map_err(|e| EncodeError::StringError(e.to_string()))?;
+    Ok(())
 }
 
 fn write_webp(
     image: &DynamicImage,
-    quality: Option<f32>,
+    options: &WebpOptions,
     mut writer: impl Write,
-) -> Result<(), std::io::Error> {
+) -> Result<(), EncodeError> {
     let format = if image.color().has_alpha() {
         webp::PixelLayout::Rgba
     } else {
@@ -38,45 +232,110 @@ fn write_webp(
 
     let image = to_8bit(image);
     let (width, height) = image.dimensions();
-    let quality = quality.unwrap_or(70.0);
+    let quality = options.quality.unwrap_or(70.0);
+
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| EncodeError::StringError("Failed to initialize WebP config".to_string()))?;
+    // Quality 100 has always meant lossless.
+    let lossless = options.lossless || quality >= 100.0;
+    config.lossless = i32::from(lossless);
+    config.quality = quality.min(100.0);
+    if let Some(method) = options.method {
+        config.method = i32::from(method.min(6));
+    }
+    if let Some(near_lossless) = options.near_lossless {
+        config.near_lossless = i32::from(near_lossless.min(100));
+    }
+
     let encoder = webp::Encoder::new(image.as_bytes(), format, width, height);
-    let output = if quality < 100.0 {
-        encoder.encode(quality)
-    } else {
-        encoder.encode_lossless()
-    };
+    let output = encoder
+        .encode_advanced(&config)
+        .map_err(|e| EncodeError::StringError(format!("WebP encoding failed: {e:?}")))?;
 
-    writer.write_all(&output)
+    writer.write_all(&output)?;
+    Ok(())
 }
 
 fn write_jpeg(
     image: &DynamicImage,
-    quality: Option<f32>,
-    mut writer: impl Write,
-) -> Result<(), image::ImageError> {
-    let quality = quality.unwrap_or(70.0) as u8;
-    let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut writer, quality);
-    let image = to_8bit(image);
+    options: &JpegOptions,
+    writer: impl Write,
+) -> Result<(), EncodeError> {
+    let quality = options.quality.unwrap_or(70.0) as u8;
     let (width, height) = image.dimensions();
+    let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => {
+            return Err(EncodeError::InvalidOption(format!(
+                "{width}x{height} is too large for JPEG"
+            )))
+        }
+    };
+
+    let mut encoder = jpeg_encoder::Encoder::new(writer, quality);
+    encoder.set_progressive(options.progressive);
+    if let Some(subsampling) = options.chroma_subsampling {
+        encoder.set_sampling_factor(match subsampling {
+            ChromaSubsampling::Yuv444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Yuv422 => jpeg_encoder::SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+        });
+    }
+
+    // JPEG has no alpha channel, so flatten to RGB or grayscale.
+    let result = if image.color().has_color() {
+        let rgb = image.to_rgb8();
+        encoder.encode(rgb.as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
+    } else {
+        let luma = image.to_luma8();
+        encoder.encode(luma.as_raw(), width, height, jpeg_encoder::ColorType::Luma)
+    };
 
-    encoder.write_image(image.as_bytes(), width, height, image.color())
+    result.map_err(|e| match e {
+        jpeg_encoder::EncodingError::IoError(e) => EncodeError::IoError(e),
+        e => EncodeError::StringError(e.to_string()),
+    })
 }
 
 fn write_avif(
     image: &DynamicImage,
-    quality: Option<f32>,
-    mut writer: impl Write,
+    options: &AvifOptions,
+    writer: impl Write,
 ) -> Result<(), EncodeError> {
-    let quality = quality.unwrap_or(60.0);
+    match options.bit_depth {
+        None | Some(8) | Some(10) => {}
+        Some(depth) => {
+            return Err(EncodeError::InvalidOption(format!(
+                "AVIF bit depth must be 8 or 10, got {depth}"
+            )))
+        }
+    }
+
+    match options.chroma_subsampling {
+        None | Some(ChromaSubsampling::Yuv444) => write_avif_ravif(image, options, writer),
+        Some(subsampling) => write_avif_subsampled(image, options, subsampling, writer),
+    }
+}
+
+fn avif_alpha_quality(quality: f32) -> f32 {
     // From https://github.com/kornelski/cavif-rs/blob/main/src/main.rs
-    let alpha_quality = ((quality + 100.0_f32) / 2.).min(quality + quality / 4. + 2.);
+    ((quality + 100.0_f32) / 2.).min(quality + quality / 4. + 2.)
+}
 
+/// Encode with ravif, which always uses 4:4:4 chroma.
+fn write_avif_ravif(
+    image: &DynamicImage,
+    options: &AvifOptions,
+    mut writer: impl Write,
+) -> Result<(), EncodeError> {
+    let quality = options.quality.unwrap_or(60.0);
     let (width, height) = image.dimensions();
 
     let encoder = ravif::Encoder::new()
         .with_quality(quality)
-        .with_alpha_quality(alpha_quality)
-        .with_speed(4);
+        .with_alpha_quality(avif_alpha_quality(quality))
+        .with_speed(options.speed.unwrap_or(4).clamp(1, 10))
+        .with_depth(options.bit_depth);
 
     let image = to_8bit(image);
 
@@ -95,23 +354,236 @@ fn write_avif(
     Ok(())
 }
 
-pub fn write_image(
+/// ravif doesn't support chroma subsampling, so subsampled output goes through libavif, which
+/// needs an AV1 encoder to be compiled in.
+#[cfg(feature = "codec-aom")]
+fn write_avif_subsampled(
     image: &DynamicImage,
-    output_format: ImageFormat,
-    quality: Option<f32>,
-    writer: impl Write,
+    options: &AvifOptions,
+    subsampling: ChromaSubsampling,
+    mut writer: impl Write,
 ) -> Result<(), EncodeError> {
-    match output_format {
-        ImageFormat::Png => write_png(image, writer)?,
-        ImageFormat::WebP => write_webp(image, quality, writer)?,
-        ImageFormat::Avif => write_avif(image, quality, writer)?,
-        ImageFormat::Jpeg => write_jpeg(image, quality, writer)?,
-        _ => Err(EncodeError::UnsupportedFormat(output_format))?,
+    if options.bit_depth == Some(10) {
+        return Err(EncodeError::InvalidOption(
+            "10-bit AVIF output requires 4:4:4 chroma".to_string(),
+        ));
+    }
+
+    let quality = options.quality.unwrap_or(60.0);
+    // libavif quantizers run from 0 (lossless) to 63 (worst).
+    let quantizer = |q: f32| ((100.0 - q.clamp(0.0, 100.0)) * 63.0 / 100.0).round() as u8;
+
+    let (width, height) = image.dimensions();
+    let pixels = if image.color().has_alpha() {
+        image.to_rgba8().into_raw()
+    } else {
+        image.to_rgb8().into_raw()
+    };
+
+    let yuv_format = match subsampling {
+        ChromaSubsampling::Yuv444 => libavif::YuvFormat::Yuv444,
+        ChromaSubsampling::Yuv422 => libavif::YuvFormat::Yuv422,
+        ChromaSubsampling::Yuv420 => libavif::YuvFormat::Yuv420,
     };
 
+    let avif_image = libavif::RgbPixels::new(width, height, &pixels)
+        .map_err(|e| EncodeError::StringError(e.to_string()))?
+        .to_image(yuv_format);
+
+    let mut encoder = libavif::Encoder::new();
+    encoder
+        .set_quantizer(quantizer(quality))
+        .set_quantizer_alpha(quantizer(avif_alpha_quality(quality)))
+        .set_speed(options.speed.unwrap_or(4).clamp(1, 10));
+
+    let output = encoder
+        .encode(&avif_image)
+        .map_err(|e| EncodeError::StringError(e.to_string()))?;
+
+    writer.write_all(&output)?;
     Ok(())
 }
 
+#[cfg(not(feature = "codec-aom"))]
+fn write_avif_subsampled(
+    _image: &DynamicImage,
+    _options: &AvifOptions,
+    subsampling: ChromaSubsampling,
+    _writer: impl Write,
+) -> Result<(), EncodeError> {
+    Err(EncodeError::InvalidOption(format!(
+        "AVIF chroma subsampling {subsampling:?} requires the codec-aom feature"
+    )))
+}
+
+fn jxl_encoder(
+    options: &JxlOptions,
+    has_alpha: bool,
+) -> Result<jpegxl_rs::encode::JxlEncoder<'static, 'static>, EncodeError> {
+    use jpegxl_rs::encode::EncoderSpeed;
+
+    let speed = match options.effort.unwrap_or(7) {
+        0 | 1 => EncoderSpeed::Lightning,
+        2 => EncoderSpeed::Thunder,
+        3 => EncoderSpeed::Falcon,
+        4 => EncoderSpeed::Cheetah,
+        5 => EncoderSpeed::Hare,
+        6 => EncoderSpeed::Wombat,
+        7 => EncoderSpeed::Squirrel,
+        8 => EncoderSpeed::Kitten,
+        _ => EncoderSpeed::Tortoise,
+    };
+
+    jpegxl_rs::encoder_builder()
+        .has_alpha(has_alpha)
+        .lossless(options.lossless)
+        .speed(speed)
+        .quality(jxl_distance(options.quality.unwrap_or(75.0)))
+        .build()
+        .map_err(|e| EncodeError::StringError(e.to_string()))
+}
+
+/// Convert a 0-100 quality into a JPEG XL Butteraugli distance, using the same mapping as
+/// libjxl's `cjxl -q`.
+fn jxl_distance(quality: f32) -> f32 {
+    let quality = quality.clamp(0.0, 100.0);
+    if quality >= 100.0 {
+        0.0
+    } else if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        53.0 / 3000.0 * quality * quality - 23.0 / 20.0 * quality + 25.0
+    }
+}
+
+fn write_jxl(
+    image: &DynamicImage,
+    options: &JxlOptions,
+    mut writer: impl Write,
+) -> Result<(), EncodeError> {
+    let has_alpha = image.color().has_alpha();
+    let mut encoder = jxl_encoder(options, has_alpha)?;
+
+    let (width, height) = image.dimensions();
+    let pixels = if has_alpha {
+        image.to_rgba8().into_raw()
+    } else {
+        image.to_rgb8().into_raw()
+    };
+
+    let output: jpegxl_rs::encode::EncoderResult<u8> = encoder
+        .encode::<u8, u8>(&pixels, width, height)
+        .map_err(|e| EncodeError::StringError(e.to_string()))?;
+
+    writer.write_all(&output.data)?;
+    Ok(())
+}
+
+/// Losslessly recompress a JPEG file into JPEG XL. This keeps the original DCT coefficients,
+/// so the JPEG can be reconstructed bit-for-bit from the output.
+pub fn recompress_jpeg_to_jxl(
+    jpeg: &[u8],
+    options: &JxlOptions,
+    mut writer: impl Write,
+) -> Result<(), EncodeError> {
+    let mut encoder = jxl_encoder(options, false)?;
+    let output: jpegxl_rs::encode::EncoderResult<u8> = encoder
+        .encode_jpeg(jpeg)
+        .map_err(|e| EncodeError::StringError(e.to_string()))?;
+
+    writer.write_all(&output.data)?;
+    Ok(())
+}
+
+/// Check if the installed libheif has an HEVC encoder. libheif can be built without one, in
+/// which case HEIC output is not available.
+pub fn heic_encoder_available() -> bool {
+    let lib_heif = libheif_rs::LibHeif::new();
+    lib_heif
+        .encoder_for_format(libheif_rs::CompressionFormat::Hevc)
+        .is_ok()
+}
+
+/// Check if AVIF output supports chroma subsampling other than 4:4:4.
+pub const fn avif_subsampling_available() -> bool {
+    cfg!(feature = "codec-aom")
+}
+
+fn write_heic(
+    image: &DynamicImage,
+    options: &HeicOptions,
+    mut writer: impl Write,
+) -> Result<(), EncodeError> {
+    use libheif_rs::{
+        Channel, ColorSpace, CompressionFormat, EncoderQuality, HeifContext, Image, LibHeif,
+        RgbChroma,
+    };
+
+    let heif_err = |e: libheif_rs::HeifError| EncodeError::StringError(e.to_string());
+
+    let has_alpha = image.color().has_alpha();
+    let (chroma, pixels) = if has_alpha {
+        (RgbChroma::Rgba, image.to_rgba8().into_raw())
+    } else {
+        (RgbChroma::Rgb, image.to_rgb8().into_raw())
+    };
+    let channels = if has_alpha { 4 } else { 3 };
+
+    let (width, height) = image.dimensions();
+    let mut heif_image = Image::new(width, height, ColorSpace::Rgb(chroma)).map_err(heif_err)?;
+    heif_image
+        .create_plane(Channel::Interleaved, width, height, 8)
+        .map_err(heif_err)?;
+
+    let plane = heif_image
+        .planes_mut()
+        .interleaved
+        .ok_or_else(|| EncodeError::StringError("No interleaved plane in HEIC image".into()))?;
+
+    // libheif may pad each row, so copy row by row.
+    let row_len = width as usize * channels;
+    for (row, src) in pixels.chunks_exact(row_len).enumerate() {
+        let start = row * plane.stride;
+        plane.data[start..start + row_len].copy_from_slice(src);
+    }
+
+    let lib_heif = LibHeif::new();
+    let mut encoder = lib_heif
+        .encoder_for_format(CompressionFormat::Hevc)
+        .map_err(|e| EncodeError::StringError(format!("No HEIC encoder available: {e}")))?;
+
+    let quality = if options.lossless {
+        EncoderQuality::LossLess
+    } else {
+        EncoderQuality::Lossy(options.quality.unwrap_or(75.0).clamp(0.0, 100.0).round() as u8)
+    };
+    encoder.set_quality(quality).map_err(heif_err)?;
+
+    let mut context = HeifContext::new().map_err(heif_err)?;
+    context
+        .encode_image(&heif_image, &mut encoder, None)
+        .map_err(heif_err)?;
+
+    let output = context.write_to_bytes().map_err(heif_err)?;
+    writer.write_all(&output)?;
+    Ok(())
+}
+
+pub fn write_image(
+    image: &DynamicImage,
+    output_format: &OutputFormat,
+    writer: impl Write,
+) -> Result<(), EncodeError> {
+    match output_format {
+        OutputFormat::Png(options) => write_png(image, options, writer),
+        OutputFormat::Webp(options) => write_webp(image, options, writer),
+        OutputFormat::Avif(options) => write_avif(image, options, writer),
+        OutputFormat::Jpeg(options) => write_jpeg(image, options, writer),
+        OutputFormat::Jxl(options) => write_jxl(image, options, writer),
+        OutputFormat::Heic(options) => write_heic(image, options, writer),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum EncodeError {
     #[error(transparent)]
@@ -120,6 +592,8 @@ pub enum EncodeError {
     IoError(#[from] std::io::Error),
     #[error("Unsupported output format {0:?}")]
     UnsupportedFormat(image::ImageFormat),
+    #[error("Invalid encoder option: {0}")]
+    InvalidOption(String),
     #[error("{0}")]
     StringError(String),
 }
@@ -139,6 +613,8 @@ mod tests {
 
     use image::DynamicImage;
 
+    use super::*;
+
     fn read_test_image(filename: &str) -> DynamicImage {
         let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("../fixtures")
@@ -151,7 +627,7 @@ mod tests {
     fn write_avif() {
         let image = read_test_image("test-input.png");
         let mut output = Vec::new();
-        super::write_image(&image, image::ImageFormat::Avif, None, &mut output).unwrap();
+        super::write_image(&image, &OutputFormat::Avif(Default::default()), &mut output).unwrap();
 
         let info = imageinfo::ImageInfo::from_raw_data(&output).expect("Reading image");
         assert_eq!(info.format, imageinfo::ImageFormat::AVIF);
@@ -164,7 +640,7 @@ mod tests {
     fn write_avif_with_alpha() {
         let image = read_test_image("test-with-alpha.png");
         let mut output = Vec::new();
-        super::write_image(&image, image::ImageFormat::Avif, None, &mut output).unwrap();
+        super::write_image(&image, &OutputFormat::Avif(Default::default()), &mut output).unwrap();
 
         let info = imageinfo::ImageInfo::from_raw_data(&output).expect("Reading image");
         assert_eq!(info.format, imageinfo::ImageFormat::AVIF);
@@ -179,7 +655,7 @@ mod tests {
     fn write_png() {
         let image = read_test_image("test-input.png");
         let mut output = Vec::new();
-        super::write_image(&image, image::ImageFormat::Png, None, &mut output).unwrap();
+        super::write_image(&image, &OutputFormat::Png(Default::default()), &mut output).unwrap();
 
         let info = imageinfo::ImageInfo::from_raw_data(&output).expect("Reading image");
         assert_eq!(info.format, imageinfo::ImageFormat::PNG);
@@ -191,7 +667,7 @@ mod tests {
     fn write_webp() {
         let image = read_test_image("test-input.png");
         let mut output = Vec::new();
-        super::write_image(&image, image::ImageFormat::WebP, None, &mut output).unwrap();
+        super::write_image(&image, &OutputFormat::Webp(Default::default()), &mut output).unwrap();
 
         let info = imageinfo::ImageInfo::from_raw_data(&output).expect("Reading image");
         assert_eq!(info.format, imageinfo::ImageFormat::WEBP);
@@ -203,11 +679,135 @@ mod tests {
     fn write_jpeg() {
         let image = read_test_image("test-input.png");
         let mut output = Vec::new();
-        super::write_image(&image, image::ImageFormat::Jpeg, None, &mut output).unwrap();
+        super::write_image(&image, &OutputFormat::Jpeg(Default::default()), &mut output).unwrap();
+
+        let info = imageinfo::ImageInfo::from_raw_data(&output).expect("Reading image");
+        assert_eq!(info.format, imageinfo::ImageFormat::JPEG);
+        assert_eq!(info.size.width as u32, image.width());
+        assert_eq!(info.size.height as u32, image.height());
+    }
+
+    #[test]
+    #[cfg(feature = "test-slow")]
+    fn write_heic() {
+        if !heic_encoder_available() {
+            return;
+        }
+
+        let image = read_test_image("test-with-alpha.png");
+        let mut output = Vec::new();
+        super::write_image(&image, &OutputFormat::Heic(Default::default()), &mut output).unwrap();
+
+        let decoded = crate::image_from_bytes(&output).expect("Reading image");
+        assert_eq!(decoded.width(), image.width());
+        assert_eq!(decoded.height(), image.height());
+        assert!(decoded.color().has_alpha());
+    }
+
+    #[test]
+    fn write_png_palette() {
+        let image = read_test_image("test-with-alpha.png");
+        let mut output = Vec::new();
+        let format = OutputFormat::Png(PngOptions {
+            compression: PngCompression::Fast,
+            palette_colors: Some(64),
+        });
+        super::write_image(&image, &format, &mut output).unwrap();
+
+        let decoder = png::Decoder::new(output.as_slice());
+        let reader = decoder.read_info().expect("Reading image");
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Indexed);
+        assert_eq!(info.palette.as_ref().map(|p| p.len()), Some(64 * 3));
+        assert_eq!(info.width, image.width());
+        assert_eq!(info.height, image.height());
+    }
+
+    #[test]
+    fn write_png_palette_invalid_colors() {
+        let image = read_test_image("test-input.png");
+        let format = OutputFormat::Png(PngOptions {
+            palette_colors: Some(1000),
+            ..Default::default()
+        });
+        let result = super::write_image(&image, &format, &mut Vec::new());
+        assert!(matches!(result, Err(EncodeError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn write_webp_lossless() {
+        let image = read_test_image("test-input.png");
+        let mut output = Vec::new();
+        let format = OutputFormat::Webp(WebpOptions {
+            lossless: true,
+            method: Some(0),
+            near_lossless: Some(60),
+            ..Default::default()
+        });
+        super::write_image(&image, &format, &mut output).unwrap();
+
+        let info = imageinfo::ImageInfo::from_raw_data(&output).expect("Reading image");
+        assert_eq!(info.format, imageinfo::ImageFormat::WEBP);
+        assert_eq!(info.size.width as u32, image.width());
+    }
+
+    #[test]
+    fn write_jpeg_progressive() {
+        let image = read_test_image("test-with-alpha.png");
+        let mut output = Vec::new();
+        let format = OutputFormat::Jpeg(JpegOptions {
+            quality: Some(80.0),
+            progressive: true,
+            chroma_subsampling: Some(ChromaSubsampling::Yuv420),
+            ..Default::default()
+        });
+        super::write_image(&image, &format, &mut output).unwrap();
 
         let info = imageinfo::ImageInfo::from_raw_data(&output).expect("Reading image");
         assert_eq!(info.format, imageinfo::ImageFormat::JPEG);
         assert_eq!(info.size.width as u32, image.width());
         assert_eq!(info.size.height as u32, image.height());
     }
+
+    #[test]
+    fn write_avif_invalid_depth() {
+        let image = read_test_image("test-input.png");
+        let format = OutputFormat::Avif(AvifOptions {
+            bit_depth: Some(12),
+            ..Default::default()
+        });
+        let result = super::write_image(&image, &format, &mut Vec::new());
+        assert!(matches!(result, Err(EncodeError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn write_jxl() {
+        let image = read_test_image("test-with-alpha.png");
+        let mut output = Vec::new();
+        super::write_image(&image, &OutputFormat::Jxl(Default::default()), &mut output).unwrap();
+
+        let decoded = crate::image_from_bytes(&output).expect("Reading image");
+        assert_eq!(decoded.width(), image.width());
+        assert_eq!(decoded.height(), image.height());
+    }
+
+    #[test]
+    fn recompress_jpeg_to_jxl() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../fixtures/test-input.jpeg");
+        let jpeg = std::fs::read(path).unwrap();
+        let mut output = Vec::new();
+        super::recompress_jpeg_to_jxl(&jpeg, &Default::default(), &mut output).unwrap();
+        assert!(output.len() < jpeg.len());
+
+        let decoded = crate::image_from_bytes(&output).expect("Reading image");
+        assert_eq!(decoded.width(), 1334);
+        assert_eq!(decoded.height(), 890);
+    }
+
+    #[test]
+    fn jxl_distance() {
+        assert_eq!(super::jxl_distance(100.0), 0.0);
+        assert!((super::jxl_distance(90.0) - 1.0).abs() < 0.001);
+        assert!(super::jxl_distance(20.0) > super::jxl_distance(50.0));
+    }
 }
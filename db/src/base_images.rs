@@ -34,6 +34,14 @@ pub struct BaseImage {
 
     pub updated: chrono::DateTime<chrono::Utc>,
     pub deleted: Option<chrono::DateTime<chrono::Utc>>,
+    /// The job that is creating the output images.
+    pub conversion_job_id: Option<uuid::Uuid>,
+    /// The number of times the conversion job has run.
+    pub conversion_attempts: i32,
+    /// The most recent error from the conversion job.
+    pub conversion_error: Option<String>,
+    /// The multipart upload in progress, if any.
+    pub upload_id: Option<String>,
 }
 
 #[derive(Debug, Insertable)]
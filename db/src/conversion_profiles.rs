@@ -16,11 +16,62 @@ pub struct ConversionSize {
     pub height: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub preserve_aspect_ratio: Option<bool>,
+    /// Pixel densities to generate for a fixed-size element. When this is set, `width` and
+    /// `height` are CSS pixels and one output is created for each density.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub densities: Option<Vec<f32>>,
+    /// The pixel density of a single output that was generated from `densities`. This is only
+    /// set by the server, and profiles that set it are rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub density: Option<f32>,
+    /// The resampling filter. Defaults to Lanczos3 when shrinking and Catmull-Rom when
+    /// enlarging.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<ResizeFilter>,
+    /// Resize in linear light instead of on the sRGB values. This is slower, but keeps small
+    /// bright details from darkening when downscaling.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub linear_light: Option<bool>,
 }
 
 diesel_jsonb!(ConversionSize);
 
-// This will eventually contain more details such as format-specific quality settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    Lanczos3,
+}
+
+impl ConversionSize {
+    /// Expand a density-based size into one size per density, with the dimensions scaled to
+    /// device pixels. Sizes without densities are returned unchanged.
+    pub fn expand_densities(&self) -> Vec<ConversionSize> {
+        let densities = match &self.densities {
+            Some(d) if !d.is_empty() && (self.width.is_some() || self.height.is_some()) => d,
+            _ => return vec![self.clone()],
+        };
+
+        let scale =
+            |value: Option<u32>, density: f32| value.map(|v| (v as f32 * density).round() as u32);
+
+        densities
+            .iter()
+            .map(|&density| ConversionSize {
+                width: scale(self.width, density),
+                height: scale(self.height, density),
+                preserve_aspect_ratio: self.preserve_aspect_ratio,
+                densities: None,
+                density: Some(density),
+                filter: self.filter,
+                linear_light: self.linear_light,
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, AsExpression, FromSqlRow)]
 #[diesel(sql_type = sql_types::Jsonb)]
 #[serde(tag = "format", rename_all = "lowercase")]
@@ -28,22 +79,88 @@ pub enum ConversionFormat {
     Png {
         #[serde(skip_serializing_if = "Option::is_none")]
         condition: Option<FormatConversionCondition>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        compression: Option<PngCompression>,
+        /// Quantize the image to a palette with at most this many colors, from 2 to 256.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        palette_colors: Option<u16>,
     },
     Jpg {
         #[serde(skip_serializing_if = "Option::is_none")]
         quality: Option<f32>,
         #[serde(skip_serializing_if = "Option::is_none")]
+        target_quality: Option<TargetQuality>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         condition: Option<FormatConversionCondition>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        progressive: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chroma_subsampling: Option<ChromaSubsampling>,
     },
     Avif {
         #[serde(skip_serializing_if = "Option::is_none")]
         quality: Option<f32>,
         #[serde(skip_serializing_if = "Option::is_none")]
+        target_quality: Option<TargetQuality>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         condition: Option<FormatConversionCondition>,
+        /// Encoder speed from 1 (slowest, smallest) to 10 (fastest).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        speed: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chroma_subsampling: Option<ChromaSubsampling>,
+        /// 8 or 10 bits per channel.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bit_depth: Option<u8>,
     },
     Webp {
         #[serde(skip_serializing_if = "Option::is_none")]
         quality: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target_quality: Option<TargetQuality>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        condition: Option<FormatConversionCondition>,
+        /// Compression method from 0 (fastest) to 6 (slowest, smallest).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        method: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lossless: Option<bool>,
+        /// Near-lossless preprocessing level from 0 (most lossy) to 100 (off). Only used with
+        /// lossless encoding.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        near_lossless: Option<u8>,
+    },
+    Jxl {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quality: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target_quality: Option<TargetQuality>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        condition: Option<FormatConversionCondition>,
+        /// Encoder effort from 1 (fastest) to 9 (slowest, smallest).
+        #[serde(skip_serializing_if = "Option::is_none")]
+        effort: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lossless: Option<bool>,
+        /// When the input is a JPEG and no resize is needed, losslessly recompress the original
+        /// JPEG data instead of encoding from pixels. The original file can be reconstructed
+        /// exactly from the output.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lossless_jpeg: Option<bool>,
+    },
+    Heic {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quality: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target_quality: Option<TargetQuality>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        condition: Option<FormatConversionCondition>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lossless: Option<bool>,
+    },
+    /// A sanitized copy of an SVG input. This is only generated for SVG inputs, and ignores
+    /// the output sizes.
+    Svg {
         #[serde(skip_serializing_if = "Option::is_none")]
         condition: Option<FormatConversionCondition>,
     },
@@ -58,6 +175,37 @@ pub enum FormatConversionCondition {
     MustNot { formats: Vec<ImageFormat> },
 }
 
+/// Choose the lowest encoder quality that keeps the output within a perceptual distance of the
+/// source image, instead of using a fixed quality.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TargetQuality {
+    /// The maximum allowed DSSIM between the source and the output. Lower is closer to the
+    /// source; 0.001 is hard to tell apart and 0.01 has noticeable artifacts.
+    pub dssim: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_quality: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_quality: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "444")]
+    Yuv444,
+    #[serde(rename = "422")]
+    Yuv422,
+    #[serde(rename = "420")]
+    Yuv420,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
 impl FormatConversionCondition {
     pub fn matches(&self, input_format: ImageFormat) -> bool {
         match self {
@@ -74,6 +222,9 @@ impl ConversionFormat {
             Self::Jpg { .. } => "jpg",
             Self::Avif { .. } => "avif",
             Self::Webp { .. } => "webp",
+            Self::Jxl { .. } => "jxl",
+            Self::Heic { .. } => "heic",
+            Self::Svg { .. } => "svg",
         }
     }
 
@@ -83,15 +234,33 @@ impl ConversionFormat {
             Self::Jpg { quality, .. } => *quality,
             Self::Avif { quality, .. } => *quality,
             Self::Webp { quality, .. } => *quality,
+            Self::Jxl { quality, .. } => *quality,
+            Self::Heic { quality, .. } => *quality,
+            Self::Svg { .. } => None,
+        }
+    }
+
+    pub fn target_quality(&self) -> Option<&TargetQuality> {
+        match self {
+            Self::Png { .. } => None,
+            Self::Jpg { target_quality, .. } => target_quality.as_ref(),
+            Self::Avif { target_quality, .. } => target_quality.as_ref(),
+            Self::Webp { target_quality, .. } => target_quality.as_ref(),
+            Self::Jxl { target_quality, .. } => target_quality.as_ref(),
+            Self::Heic { target_quality, .. } => target_quality.as_ref(),
+            Self::Svg { .. } => None,
         }
     }
 
     pub fn matches_condition(&self, input_format: ImageFormat) -> bool {
         let condition = match self {
-            Self::Png { condition } => condition.as_ref(),
+            Self::Png { condition, .. } => condition.as_ref(),
             Self::Jpg { condition, .. } => condition.as_ref(),
             Self::Avif { condition, .. } => condition.as_ref(),
             Self::Webp { condition, .. } => condition.as_ref(),
+            Self::Jxl { condition, .. } => condition.as_ref(),
+            Self::Heic { condition, .. } => condition.as_ref(),
+            Self::Svg { condition } => condition.as_ref(),
         };
 
         condition.map(|c| c.matches(input_format)).unwrap_or(true)
@@ -103,6 +272,9 @@ impl ConversionFormat {
             ConversionFormat::Jpg { .. } => crate::ImageFormat::Jpg,
             ConversionFormat::Webp { .. } => crate::ImageFormat::Webp,
             ConversionFormat::Avif { .. } => crate::ImageFormat::Avif,
+            ConversionFormat::Jxl { .. } => crate::ImageFormat::Jxl,
+            ConversionFormat::Heic { .. } => crate::ImageFormat::Heic,
+            ConversionFormat::Svg { .. } => crate::ImageFormat::Svg,
         }
     }
 }
@@ -113,17 +285,6 @@ impl From<&ConversionFormat> for crate::ImageFormat {
     }
 }
 
-impl From<&ConversionFormat> for image::ImageFormat {
-    fn from(format: &ConversionFormat) -> Self {
-        match format {
-            ConversionFormat::Png { .. } => image::ImageFormat::Png,
-            ConversionFormat::Jpg { .. } => image::ImageFormat::Jpeg,
-            ConversionFormat::Webp { .. } => image::ImageFormat::WebP,
-            ConversionFormat::Avif { .. } => image::ImageFormat::Avif,
-        }
-    }
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize, AsExpression, FromSqlRow)]
 #[diesel(sql_type = sql_types::Jsonb)]
 #[serde(tag = "type", rename_all = "lowercase")]
@@ -131,11 +292,27 @@ pub enum ConversionOutput {
     Cross {
         formats: Vec<ConversionFormat>,
         sizes: Vec<ConversionSize>,
+        /// Allow outputs to be larger than the original image. When this is false, sizes larger
+        /// than the original are replaced by a single output at the original size.
+        #[serde(default)]
+        allow_upscale: bool,
+        /// Render SVG inputs into the other formats at each size. When this is false, SVG
+        /// inputs only produce `svg` outputs.
+        #[serde(default)]
+        rasterize_svg: bool,
     },
 }
 
 diesel_jsonb!(ConversionOutput);
 
+impl ConversionOutput {
+    pub fn allow_upscale(&self) -> bool {
+        match self {
+            Self::Cross { allow_upscale, .. } => *allow_upscale,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Queryable, Identifiable)]
 pub struct ConversionProfile {
     pub id: ConversionProfileId,
@@ -1,5 +1,6 @@
 use diesel_derive_enum::DbEnum;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 #[derive(Debug, Clone, Copy, DbEnum, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -10,16 +11,32 @@ pub enum ImageFormat {
     Avif,
     Webp,
     Heic,
+    Jxl,
+    Tiff,
+    Bmp,
+    /// A camera RAW file, such as DNG, CR2, or NEF.
+    Raw,
+    Svg,
 }
 
-impl From<ImageFormat> for image::ImageFormat {
-    fn from(f: ImageFormat) -> Self {
+#[derive(Debug, Error)]
+#[error("{0:?} is not supported by the image crate")]
+pub struct UnsupportedImageFormat(pub ImageFormat);
+
+impl TryFrom<ImageFormat> for image::ImageFormat {
+    type Error = UnsupportedImageFormat;
+
+    fn try_from(f: ImageFormat) -> Result<Self, Self::Error> {
         match f {
-            ImageFormat::Png => image::ImageFormat::Png,
-            ImageFormat::Jpg => image::ImageFormat::Jpeg,
-            ImageFormat::Avif => image::ImageFormat::Avif,
-            ImageFormat::Webp => image::ImageFormat::WebP,
-            ImageFormat::Heic => panic!("Heic output not supported"),
+            ImageFormat::Png => Ok(image::ImageFormat::Png),
+            ImageFormat::Jpg => Ok(image::ImageFormat::Jpeg),
+            ImageFormat::Avif => Ok(image::ImageFormat::Avif),
+            ImageFormat::Webp => Ok(image::ImageFormat::WebP),
+            ImageFormat::Tiff => Ok(image::ImageFormat::Tiff),
+            ImageFormat::Bmp => Ok(image::ImageFormat::Bmp),
+            ImageFormat::Heic | ImageFormat::Jxl | ImageFormat::Raw | ImageFormat::Svg => {
+                Err(UnsupportedImageFormat(f))
+            }
         }
     }
 }
@@ -34,6 +51,8 @@ pub enum BaseImageStatus {
     QueuedForDelete,
     Deleting,
     Deleted,
+    /// The conversion job failed. Some outputs may still be ready.
+    Failed,
 }
 
 impl Default for BaseImageStatus {
@@ -51,6 +70,10 @@ pub enum OutputImageStatus {
     Ready,
     QueuedForDelete,
     Deleted,
+    /// The output was larger than a more widely supported alternative, so it was not kept.
+    Skipped,
+    /// The most recent attempt to create the output failed.
+    Failed,
 }
 
 impl Default for OutputImageStatus {
@@ -59,6 +82,27 @@ impl Default for OutputImageStatus {
     }
 }
 
+/// What an upload profile hashes to make unique output filenames.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, DbEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[ExistingTypePath = "crate::schema::sql_types::OutputFilenameHash"]
+pub enum OutputFilenameHash {
+    /// The encoded bytes of the output, which are only known after it is converted.
+    Content,
+    /// The output format and size settings, and the hash of the base image.
+    Parameters,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, DbEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[ExistingTypePath = "crate::schema::sql_types::JobStatus"]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
 #[derive(Copy, Clone, Debug, DbEnum, Deserialize)]
 #[ExistingTypePath = "crate::schema::sql_types::Permission"]
 pub enum Permission {
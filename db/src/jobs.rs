@@ -0,0 +1,45 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+pub use crate::schema::jobs::*;
+use crate::{enums::JobStatus, schema::*};
+
+#[derive(Clone, Debug, Queryable, Identifiable)]
+pub struct Job {
+    pub id: Uuid,
+    pub job_type: String,
+    pub status: JobStatus,
+    pub priority: i32,
+    pub payload: serde_json::Value,
+    /// The number of times the job has been started.
+    pub current_try: i32,
+    pub max_retries: i32,
+    pub backoff_initial_interval_ms: i32,
+    pub backoff_multiplier: f32,
+    pub backoff_randomization: f32,
+    pub timeout_ms: i32,
+    pub run_at: DateTime<Utc>,
+    /// When a running job will be considered lost and run again.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The error from the most recent failed try.
+    pub error: Option<String>,
+    pub added_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = jobs)]
+pub struct NewJob {
+    pub id: Uuid,
+    pub job_type: String,
+    pub priority: i32,
+    pub payload: serde_json::Value,
+    pub max_retries: i32,
+    pub backoff_initial_interval_ms: i32,
+    pub backoff_multiplier: f32,
+    pub backoff_randomization: f32,
+    pub timeout_ms: i32,
+    pub run_at: DateTime<Utc>,
+}
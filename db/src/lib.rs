@@ -8,12 +8,14 @@ mod schema;
 pub mod api_keys;
 pub mod base_images;
 pub mod conversion_profiles;
+pub mod jobs;
 pub mod object_id;
 pub mod output_images;
 pub mod permissions;
 pub mod projects;
 pub mod role_permissions;
 pub mod roles;
+pub mod secrets;
 pub mod sessions;
 pub mod storage_locations;
 pub mod teams;
@@ -24,6 +24,12 @@ pub struct OutputImage {
 
     pub updated: chrono::DateTime<chrono::Utc>,
     pub deleted: Option<chrono::DateTime<chrono::Utc>>,
+    /// The encoder quality that was used, which may have been chosen by a target quality search.
+    pub quality: Option<f32>,
+    /// The most recent error from creating the image.
+    pub error: Option<String>,
+    /// The number of times creating the image has been attempted.
+    pub attempts: i32,
 }
 
 #[derive(Debug, Insertable)]
@@ -40,3 +46,32 @@ pub struct NewOutputImage {
 
     pub status: OutputImageStatus,
 }
+
+/// Insert a short hash of `data` into `location`, just before the extension.
+pub fn hashed_location(location: &str, data: &[u8]) -> String {
+    let hash = blake3::hash(data).to_hex();
+    let hash = &hash[..10];
+    match location.rsplit_once('.') {
+        Some((stem, extension)) => format!("{stem}-{hash}.{extension}"),
+        None => format!("{location}-{hash}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_goes_before_extension() {
+        let location = hashed_location("a/photo-w200-abc.avif", b"data");
+        let (stem, hash) = location
+            .strip_suffix(".avif")
+            .unwrap()
+            .rsplit_once('-')
+            .unwrap();
+        assert_eq!(stem, "a/photo-w200-abc");
+        assert_eq!(hash.len(), 10);
+
+        assert_ne!(location, hashed_location("a/photo-w200-abc.avif", b"other"));
+    }
+}
@@ -9,6 +9,14 @@ pub mod sql_types {
     #[diesel(postgres_type(name = "image_format"))]
     pub struct ImageFormat;
 
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "job_status"))]
+    pub struct JobStatus;
+
+    #[derive(diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "output_filename_hash"))]
+    pub struct OutputFilenameHash;
+
     #[derive(diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "output_image_status"))]
     pub struct OutputImageStatus;
@@ -73,6 +81,10 @@ diesel::table! {
         updated -> Timestamptz,
         deleted -> Nullable<Timestamptz>,
         file_size -> Int4,
+        conversion_job_id -> Nullable<Uuid>,
+        conversion_attempts -> Int4,
+        conversion_error -> Nullable<Text>,
+        upload_id -> Nullable<Text>,
     }
 }
 
@@ -91,6 +103,32 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::enums::*;
+    use super::sql_types::JobStatus;
+
+    jobs (id) {
+        id -> Uuid,
+        job_type -> Text,
+        status -> JobStatus,
+        priority -> Int4,
+        payload -> Jsonb,
+        current_try -> Int4,
+        max_retries -> Int4,
+        backoff_initial_interval_ms -> Int4,
+        backoff_multiplier -> Float4,
+        backoff_randomization -> Float4,
+        timeout_ms -> Int4,
+        run_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        error -> Nullable<Text>,
+        added_at -> Timestamptz,
+        started_at -> Nullable<Timestamptz>,
+        finished_at -> Nullable<Timestamptz>,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use crate::enums::*;
@@ -109,6 +147,9 @@ diesel::table! {
         updated -> Timestamptz,
         deleted -> Nullable<Timestamptz>,
         file_size -> Int4,
+        quality -> Nullable<Float4>,
+        error -> Nullable<Text>,
+        attempts -> Int4,
     }
 }
 
@@ -177,6 +218,7 @@ diesel::table! {
         public_url_base -> Text,
         updated -> Timestamptz,
         deleted -> Nullable<Timestamptz>,
+        cache_control -> Nullable<Text>,
     }
 }
 
@@ -194,6 +236,7 @@ diesel::table! {
 diesel::table! {
     use diesel::sql_types::*;
     use crate::enums::*;
+    use super::sql_types::OutputFilenameHash;
 
     upload_profiles (id) {
         id -> Uuid,
@@ -208,6 +251,7 @@ diesel::table! {
         deleted -> Nullable<Timestamptz>,
         base_storage_location_path -> Nullable<Text>,
         output_storage_location_path -> Nullable<Text>,
+        output_filename_hash -> Nullable<OutputFilenameHash>,
     }
 }
 
@@ -271,6 +315,7 @@ diesel::allow_tables_to_appear_in_same_query!(
     api_keys,
     base_images,
     conversion_profiles,
+    jobs,
     output_images,
     projects,
     role_permissions,
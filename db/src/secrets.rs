@@ -0,0 +1,291 @@
+//! Encryption for secrets, such as storage credentials, that are saved in the database.
+
+use base64::Engine;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The value returned in place of a secret when it is sent to a client.
+pub const REDACTED: &str = "********";
+
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("Master key must be 32 bytes, encoded as base64")]
+    InvalidKey,
+
+    #[error("Secret was encrypted with unknown master key {0}")]
+    UnknownKey(String),
+
+    #[error("No master key is set, so secrets can't be saved")]
+    NoCurrentKey,
+
+    #[error("Failed to encrypt secret")]
+    Encrypt,
+
+    #[error("Failed to decrypt secret")]
+    Decrypt,
+}
+
+/// A secret value as it is saved in the database.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    /// Encrypted with a master key. The ciphertext is the base64 of the nonce followed by the
+    /// encrypted value.
+    Encrypted { key_id: String, ciphertext: String },
+    /// Not encrypted, as sent by a client or saved before encryption was added.
+    Plain(String),
+}
+
+impl Secret {
+    /// Returns true if this is the placeholder sent to clients instead of the real value.
+    pub fn is_redacted(&self) -> bool {
+        matches!(self, Secret::Plain(s) if s == REDACTED)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Encrypted { key_id, .. } => f
+                .debug_struct("Encrypted")
+                .field("key_id", key_id)
+                .finish_non_exhaustive(),
+            Secret::Plain(_) => f.write_str("Plain(..)"),
+        }
+    }
+}
+
+struct MasterKey {
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl MasterKey {
+    fn from_base64(key: &str) -> Result<Self, SecretError> {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key.trim())
+            .map_err(|_| SecretError::InvalidKey)?;
+        let cipher =
+            XChaCha20Poly1305::new_from_slice(&key).map_err(|_| SecretError::InvalidKey)?;
+
+        // The ID lets us find the right key for a secret without revealing anything about it.
+        let id = blake3::hash(&key).to_hex()[..16].to_string();
+        Ok(MasterKey { id, cipher })
+    }
+}
+
+/// The master keys for encrypting and decrypting secrets. New secrets are always encrypted with
+/// the current key, and the previous keys are only used to decrypt secrets that haven't been
+/// rotated to the current key yet. Without a current key, existing secrets can still be read but
+/// new ones can't be saved.
+pub struct MasterKeys {
+    current: Option<MasterKey>,
+    previous: Vec<MasterKey>,
+}
+
+impl MasterKeys {
+    /// Create the keys from base64-encoded 32 byte values.
+    pub fn new(current: &str, previous: &[String]) -> Result<Self, SecretError> {
+        Ok(MasterKeys {
+            current: Some(MasterKey::from_base64(current)?),
+            previous: parse_keys(previous)?,
+        })
+    }
+
+    /// Create keys that only read existing secrets, for deployments that haven't set a key yet.
+    /// Unencrypted secrets and those encrypted with the previous keys can still be decrypted.
+    pub fn decrypt_only(previous: &[String]) -> Result<Self, SecretError> {
+        Ok(MasterKeys {
+            current: None,
+            previous: parse_keys(previous)?,
+        })
+    }
+
+    /// Generate a random key, encoded as base64.
+    pub fn generate_key() -> String {
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        base64::engine::general_purpose::STANDARD.encode(key)
+    }
+
+    /// The ID of the key used to encrypt new secrets, if there is one.
+    pub fn current_key_id(&self) -> Option<&str> {
+        self.current.as_ref().map(|key| key.id.as_str())
+    }
+
+    /// Encrypt a secret with the current key. Fails if there is no current key, so that secrets
+    /// are never saved unencrypted.
+    pub fn encrypt(&self, plaintext: &str) -> Result<Secret, SecretError> {
+        let current = self.current.as_ref().ok_or(SecretError::NoCurrentKey)?;
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = current
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| SecretError::Encrypt)?;
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&encrypted);
+
+        Ok(Secret::Encrypted {
+            key_id: current.id.clone(),
+            ciphertext: base64::engine::general_purpose::STANDARD.encode(data),
+        })
+    }
+
+    pub fn decrypt(&self, secret: &Secret) -> Result<String, SecretError> {
+        let (key_id, ciphertext) = match secret {
+            Secret::Plain(value) => return Ok(value.clone()),
+            Secret::Encrypted { key_id, ciphertext } => (key_id, ciphertext),
+        };
+
+        let key = self
+            .current
+            .iter()
+            .chain(self.previous.iter())
+            .find(|key| &key.id == key_id)
+            .ok_or_else(|| SecretError::UnknownKey(key_id.clone()))?;
+
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(ciphertext)
+            .map_err(|_| SecretError::Decrypt)?;
+        if data.len() < NONCE_LEN {
+            return Err(SecretError::Decrypt);
+        }
+
+        let (nonce, encrypted) = data.split_at(NONCE_LEN);
+        let plaintext = key
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), encrypted)
+            .map_err(|_| SecretError::Decrypt)?;
+
+        String::from_utf8(plaintext).map_err(|_| SecretError::Decrypt)
+    }
+
+    /// Encrypt the secret with the current key, unless it already is. Returns `None` if nothing
+    /// needed to change. Like [MasterKeys::encrypt], this fails without a current key.
+    pub fn reencrypt(&self, secret: &Secret) -> Result<Option<Secret>, SecretError> {
+        let current = self.current.as_ref().ok_or(SecretError::NoCurrentKey)?;
+
+        match secret {
+            Secret::Encrypted { key_id, .. } if key_id == &current.id => Ok(None),
+            _ => {
+                let plaintext = self.decrypt(secret)?;
+                self.encrypt(&plaintext).map(Some)
+            }
+        }
+    }
+}
+
+fn parse_keys(keys: &[String]) -> Result<Vec<MasterKey>, SecretError> {
+    keys.iter().map(|key| MasterKey::from_base64(key)).collect()
+}
+
+impl std::fmt::Debug for MasterKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MasterKeys")
+            .field("current", &self.current_key_id())
+            .field(
+                "previous",
+                &self.previous.iter().map(|k| &k.id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let keys = MasterKeys::new(&MasterKeys::generate_key(), &[]).unwrap();
+        let secret = keys.encrypt("hunter2").unwrap();
+
+        let json = serde_json::to_string(&secret).unwrap();
+        assert!(!json.contains("hunter2"));
+        let secret: Secret = serde_json::from_str(&json).unwrap();
+        assert_eq!(keys.decrypt(&secret).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn plain_secrets_pass_through() {
+        let keys = MasterKeys::new(&MasterKeys::generate_key(), &[]).unwrap();
+        let secret: Secret = serde_json::from_str(r#""hunter2""#).unwrap();
+        assert_eq!(secret, Secret::Plain("hunter2".to_string()));
+        assert_eq!(keys.decrypt(&secret).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn rotate() {
+        let old_key = MasterKeys::generate_key();
+        let old = MasterKeys::new(&old_key, &[]).unwrap();
+        let secret = old.encrypt("hunter2").unwrap();
+
+        let new = MasterKeys::new(&MasterKeys::generate_key(), &[]).unwrap();
+        assert!(matches!(
+            new.decrypt(&secret),
+            Err(SecretError::UnknownKey(_))
+        ));
+
+        let new = MasterKeys::new(&MasterKeys::generate_key(), &[old_key]).unwrap();
+        let rotated = new
+            .reencrypt(&secret)
+            .unwrap()
+            .expect("secret should change");
+        assert!(
+            matches!(&rotated, Secret::Encrypted { key_id, .. } if Some(key_id.as_str()) == new.current_key_id())
+        );
+        assert_eq!(new.decrypt(&rotated).unwrap(), "hunter2");
+        assert!(new.reencrypt(&rotated).unwrap().is_none());
+    }
+
+    #[test]
+    fn no_current_key() {
+        let old_key = MasterKeys::generate_key();
+        let encrypted = MasterKeys::new(&old_key, &[])
+            .unwrap()
+            .encrypt("hunter2")
+            .unwrap();
+
+        let keys = MasterKeys::decrypt_only(&[old_key]).unwrap();
+        assert_eq!(keys.current_key_id(), None);
+        assert!(matches!(
+            keys.encrypt("hunter2"),
+            Err(SecretError::NoCurrentKey)
+        ));
+        let plain = Secret::Plain("hunter2".to_string());
+        assert!(matches!(
+            keys.reencrypt(&plain),
+            Err(SecretError::NoCurrentKey)
+        ));
+
+        // Existing secrets are still readable.
+        assert_eq!(keys.decrypt(&encrypted).unwrap(), "hunter2");
+        assert_eq!(keys.decrypt(&plain).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails() {
+        let keys = MasterKeys::new(&MasterKeys::generate_key(), &[]).unwrap();
+        let Secret::Encrypted { key_id, ciphertext } = keys.encrypt("hunter2").unwrap() else {
+            panic!("expected an encrypted secret");
+        };
+
+        let mut data = base64::engine::general_purpose::STANDARD
+            .decode(ciphertext)
+            .unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        let tampered = Secret::Encrypted {
+            key_id,
+            ciphertext: base64::engine::general_purpose::STANDARD.encode(data),
+        };
+
+        assert!(matches!(keys.decrypt(&tampered), Err(SecretError::Decrypt)));
+    }
+}
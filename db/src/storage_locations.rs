@@ -6,6 +6,7 @@ use crate::{
     diesel_jsonb,
     object_id::{ProjectId, StorageLocationId, TeamId},
     schema::*,
+    secrets::{MasterKeys, Secret, SecretError, REDACTED},
 };
 
 pub use crate::schema::storage_locations::*;
@@ -16,23 +17,112 @@ pub use crate::schema::storage_locations::*;
 pub enum Provider {
     /// Local filesystem
     Local,
+    /// Kept in memory and lost when the process exits, for tests and ephemeral environments.
+    /// Locations with the same bucket share their objects within a process.
+    Memory,
     /// S3 or compatible storage
     S3 {
         endpoint: Option<String>,
         region: Option<String>,
-        access_key_id: Option<String>,
-        secret_key: Option<String>,
+        access_key_id: Option<Secret>,
+        secret_key: Option<Secret>,
         virtual_host_style: Option<bool>,
     },
+    /// Google Cloud Storage
+    Gcs {
+        /// The JSON contents of a service account key. If not set, the key is read from the
+        /// environment.
+        service_account_key: Option<Secret>,
+    },
+    /// Azure Blob Storage
+    Azure {
+        account: Option<String>,
+        access_key: Option<Secret>,
+        /// Connect to the Azurite emulator instead of Azure.
+        use_emulator: Option<bool>,
+    },
 }
 
 diesel_jsonb!(Provider);
 
+impl Provider {
+    /// The fields of the provider that hold secrets.
+    pub fn secrets(&self) -> Vec<&Option<Secret>> {
+        match self {
+            Self::Local | Self::Memory => Vec::new(),
+            Self::S3 {
+                access_key_id,
+                secret_key,
+                ..
+            } => vec![access_key_id, secret_key],
+            Self::Gcs {
+                service_account_key,
+            } => vec![service_account_key],
+            Self::Azure { access_key, .. } => vec![access_key],
+        }
+    }
+
+    fn secrets_mut(&mut self) -> Vec<&mut Option<Secret>> {
+        match self {
+            Self::Local | Self::Memory => Vec::new(),
+            Self::S3 {
+                access_key_id,
+                secret_key,
+                ..
+            } => vec![access_key_id, secret_key],
+            Self::Gcs {
+                service_account_key,
+            } => vec![service_account_key],
+            Self::Azure { access_key, .. } => vec![access_key],
+        }
+    }
+
+    /// Encrypt the secrets with the current master key. Secrets encrypted with an older key are
+    /// re-encrypted. Returns true if any secret changed.
+    pub fn encrypt_secrets(&mut self, keys: &MasterKeys) -> Result<bool, SecretError> {
+        let mut changed = false;
+        for secret in self.secrets_mut().into_iter().flatten() {
+            if let Some(encrypted) = keys.reencrypt(secret)? {
+                *secret = encrypted;
+                changed = true;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Return a copy of the provider with the secrets replaced by a placeholder, to send to
+    /// clients.
+    pub fn redacted(&self) -> Provider {
+        let mut provider = self.clone();
+        for secret in provider.secrets_mut().into_iter().flatten() {
+            *secret = Secret::Plain(REDACTED.to_string());
+        }
+
+        provider
+    }
+
+    /// Replace secrets that a client sent back redacted with the values from `existing`, so that
+    /// other fields can be updated without resending the secrets.
+    pub fn restore_redacted(&mut self, existing: &Provider) {
+        let same_type = std::mem::discriminant(self) == std::mem::discriminant(existing);
+        let existing = existing.secrets();
+        for (i, secret) in self.secrets_mut().into_iter().enumerate() {
+            if secret.as_ref().map(|s| s.is_redacted()).unwrap_or(false) {
+                *secret = if same_type { existing[i].clone() } else { None };
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for Provider {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let desc = match self {
             Self::Local => "local",
+            Self::Memory => "memory",
             Self::S3 { .. } => "s3",
+            Self::Gcs { .. } => "gcs",
+            Self::Azure { .. } => "azure",
         };
 
         f.write_str(desc)
@@ -54,6 +144,10 @@ pub struct StorageLocation {
 
     pub updated: chrono::DateTime<chrono::Utc>,
     pub deleted: Option<chrono::DateTime<chrono::Utc>>,
+
+    /// The Cache-Control header to save with objects written here. If not set, objects with
+    /// content-hashed names are cached forever and others use the provider's default.
+    pub cache_control: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Insertable)]
@@ -69,4 +163,7 @@ pub struct NewStorageLocation {
 
     /// The base URL at which images in this StorageLocation can be accessed on the web.
     pub public_url_base: String,
+
+    #[serde(default)]
+    pub cache_control: Option<String>,
 }
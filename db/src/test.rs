@@ -215,10 +215,18 @@ fn populate_database(conn: &mut PgConnection) -> Result<DatabaseInfo, eyre::Repo
                     ConversionFormat::Avif {
                         condition: None,
                         quality: None,
+                        target_quality: None,
+                        speed: None,
+                        chroma_subsampling: None,
+                        bit_depth: None,
                     },
                     ConversionFormat::Webp {
                         condition: None,
                         quality: None,
+                        target_quality: None,
+                        method: None,
+                        lossless: None,
+                        near_lossless: None,
                     },
                 ],
                 sizes: vec![
@@ -231,6 +239,8 @@ fn populate_database(conn: &mut PgConnection) -> Result<DatabaseInfo, eyre::Repo
                         ..Default::default()
                     },
                 ],
+                allow_upscale: false,
+                rasterize_svg: false,
             },
         })
         .execute(conn)?;
@@ -242,20 +252,22 @@ fn populate_database(conn: &mut PgConnection) -> Result<DatabaseInfo, eyre::Repo
             NewStorageLocation {
                 id: base_storage_location_id,
                 team_id,
-                name: "Local Base Images".to_string(),
+                name: "Memory Base Images".to_string(),
                 project_id: None,
-                provider: crate::storage_locations::Provider::Local,
-                base_location: "TODO".to_string(),
+                provider: crate::storage_locations::Provider::Memory,
+                base_location: "test-images/base".to_string(),
                 public_url_base: "https://my.images/orig_image/".to_string(),
+                cache_control: None,
             },
             NewStorageLocation {
                 id: output_storage_location_id,
                 team_id,
-                name: "Local Output Images".to_string(),
+                name: "Memory Output Images".to_string(),
                 project_id: None,
-                provider: crate::storage_locations::Provider::Local,
-                base_location: "TODO".to_string(),
+                provider: crate::storage_locations::Provider::Memory,
+                base_location: "test-images/output".to_string(),
                 public_url_base: "https://my.images/image/".to_string(),
+                cache_control: None,
             },
         ])
         .execute(conn)?;
@@ -274,6 +286,7 @@ fn populate_database(conn: &mut PgConnection) -> Result<DatabaseInfo, eyre::Repo
             base_storage_location_path: None,
             output_storage_location_id,
             output_storage_location_path: None,
+            output_filename_hash: None,
         })
         .execute(conn)?;
 
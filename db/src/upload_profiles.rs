@@ -3,6 +3,7 @@ use serde::Deserialize;
 
 pub use crate::schema::upload_profiles::*;
 use crate::{
+    enums::OutputFilenameHash,
     object_id::{ConversionProfileId, ProjectId, StorageLocationId, TeamId, UploadProfileId},
     schema::*,
 };
@@ -29,6 +30,8 @@ pub struct UploadProfile {
 
     pub updated: chrono::DateTime<chrono::Utc>,
     pub deleted: Option<chrono::DateTime<chrono::Utc>>,
+    /// Add a hash to output filenames, so that they can be cached forever.
+    pub output_filename_hash: Option<OutputFilenameHash>,
 }
 
 #[derive(Debug, Deserialize, Insertable)]
@@ -51,4 +54,7 @@ pub struct NewUploadProfile {
     /// A path within the output storage location where the output images will be stored.
     pub output_storage_location_path: Option<String>,
     pub conversion_profile_id: ConversionProfileId,
+    /// Add a hash to output filenames, so that they can be cached forever.
+    #[serde(default)]
+    pub output_filename_hash: Option<OutputFilenameHash>,
 }
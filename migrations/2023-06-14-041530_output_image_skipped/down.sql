@@ -0,0 +1,16 @@
+-- Postgres can't remove a value from an enum, so recreate the type without it.
+UPDATE output_images SET status = 'deleted' WHERE status = 'skipped';
+
+ALTER TYPE output_image_status RENAME TO output_image_status_old;
+CREATE TYPE output_image_status AS ENUM (
+  'queued',
+  'converting',
+  'ready',
+  'queued_for_delete',
+  'deleted'
+);
+
+ALTER TABLE output_images
+  ALTER COLUMN status TYPE output_image_status USING status::text::output_image_status;
+
+DROP TYPE output_image_status_old;
@@ -0,0 +1 @@
+ALTER TYPE output_image_status ADD VALUE 'skipped';
@@ -0,0 +1,15 @@
+-- Postgres can't remove a value from an enum, so recreate the type without it.
+UPDATE base_images SET format = NULL WHERE format = 'jxl';
+
+ALTER TYPE image_format RENAME TO image_format_old;
+CREATE TYPE image_format AS ENUM (
+  'png',
+  'jpg',
+  'avif',
+  'webp'
+);
+
+ALTER TABLE base_images
+  ALTER COLUMN format TYPE image_format USING format::text::image_format;
+
+DROP TYPE image_format_old;
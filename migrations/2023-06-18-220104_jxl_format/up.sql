@@ -0,0 +1 @@
+ALTER TYPE image_format ADD VALUE 'jxl';
@@ -0,0 +1,4 @@
+ALTER TYPE image_format ADD VALUE IF NOT EXISTS 'heic';
+ALTER TYPE image_format ADD VALUE 'tiff';
+ALTER TYPE image_format ADD VALUE 'bmp';
+ALTER TYPE image_format ADD VALUE 'raw';
@@ -0,0 +1,32 @@
+CREATE TYPE job_status AS ENUM (
+  'pending',
+  'running',
+  'succeeded',
+  'failed'
+);
+
+-- A job queue for running background jobs from multiple processes.
+CREATE TABLE jobs (
+  id uuid primary key,
+  job_type text not null,
+  status job_status not null default 'pending',
+  priority int not null default 0,
+  payload jsonb not null,
+  -- The number of times the job has been started.
+  current_try int not null default 0,
+  max_retries int not null,
+  backoff_initial_interval_ms int not null,
+  backoff_multiplier real not null,
+  backoff_randomization real not null,
+  timeout_ms int not null,
+  run_at timestamptz not null default now(),
+  -- When a running job will be considered lost and run again.
+  expires_at timestamptz,
+  error text,
+  added_at timestamptz not null default now(),
+  started_at timestamptz,
+  finished_at timestamptz
+);
+
+CREATE INDEX jobs_pending_idx ON jobs (priority DESC, run_at) WHERE status = 'pending';
+CREATE INDEX jobs_running_idx ON jobs (expires_at) WHERE status = 'running';
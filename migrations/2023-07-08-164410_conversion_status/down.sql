@@ -0,0 +1,2 @@
+ALTER TABLE output_images DROP COLUMN error;
+ALTER TABLE base_images DROP COLUMN conversion_job_id;
@@ -0,0 +1,4 @@
+-- The most recent error from creating the output image.
+ALTER TABLE output_images ADD COLUMN error text;
+-- The job that is creating the output images.
+ALTER TABLE base_images ADD COLUMN conversion_job_id uuid;
@@ -0,0 +1,38 @@
+ALTER TABLE output_images DROP COLUMN attempts;
+ALTER TABLE base_images DROP COLUMN conversion_attempts;
+ALTER TABLE base_images DROP COLUMN conversion_error;
+
+-- Postgres can't remove a value from an enum, so recreate the types without it.
+UPDATE output_images SET status = 'queued' WHERE status = 'failed';
+
+ALTER TYPE output_image_status RENAME TO output_image_status_old;
+CREATE TYPE output_image_status AS ENUM (
+  'queued',
+  'converting',
+  'ready',
+  'queued_for_delete',
+  'deleted',
+  'skipped'
+);
+
+ALTER TABLE output_images
+  ALTER COLUMN status TYPE output_image_status USING status::text::output_image_status;
+
+DROP TYPE output_image_status_old;
+
+UPDATE base_images SET status = 'converting' WHERE status = 'failed';
+
+ALTER TYPE base_image_status RENAME TO base_image_status_old;
+CREATE TYPE base_image_status AS ENUM (
+  'awaiting_upload',
+  'converting',
+  'ready',
+  'queued_for_delete',
+  'deleting',
+  'deleted'
+);
+
+ALTER TABLE base_images
+  ALTER COLUMN status TYPE base_image_status USING status::text::base_image_status;
+
+DROP TYPE base_image_status_old;
@@ -0,0 +1,8 @@
+ALTER TYPE output_image_status ADD VALUE 'failed';
+ALTER TYPE base_image_status ADD VALUE 'failed';
+
+-- The number of times conversion has been attempted.
+ALTER TABLE output_images ADD COLUMN attempts int not null default 0;
+ALTER TABLE base_images ADD COLUMN conversion_attempts int not null default 0;
+-- The most recent error from the conversion job.
+ALTER TABLE base_images ADD COLUMN conversion_error text;
@@ -0,0 +1,2 @@
+DROP INDEX base_images_status_updated;
+ALTER TABLE base_images DROP COLUMN upload_id;
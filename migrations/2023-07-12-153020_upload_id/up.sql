@@ -0,0 +1,6 @@
+-- The multipart upload in progress for the base image, so that it can be cleaned up if the
+-- upload never finishes.
+ALTER TABLE base_images ADD COLUMN upload_id text;
+
+CREATE INDEX base_images_status_updated ON base_images(status, updated)
+  WHERE status IN ('awaiting_upload', 'converting');
@@ -0,0 +1 @@
+ALTER TABLE storage_locations DROP COLUMN cache_control;
@@ -0,0 +1,2 @@
+-- The Cache-Control header to save with objects written to the storage location.
+ALTER TABLE storage_locations ADD COLUMN cache_control text;
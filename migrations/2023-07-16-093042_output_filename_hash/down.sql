@@ -0,0 +1,2 @@
+ALTER TABLE upload_profiles DROP COLUMN output_filename_hash;
+DROP TYPE output_filename_hash;
@@ -0,0 +1,8 @@
+CREATE TYPE output_filename_hash AS ENUM (
+  'content',
+  'parameters'
+);
+
+-- Adds a hash to output image filenames, so that reconverting an image never reuses the name of
+-- an output that may be cached.
+ALTER TABLE upload_profiles ADD COLUMN output_filename_hash output_filename_hash;
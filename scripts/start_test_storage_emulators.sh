@@ -0,0 +1,31 @@
+#!/bin/bash
+set -euo pipefail
+set -x
+
+# Start emulators for the storage provider tests. Afterward, run the tests with
+#   TEST_GCS_EMULATOR_URL=http://localhost:4443 TEST_AZURITE_CONTAINER=pic-store-test cargo test -p pic-store-storage
+
+TEST_BUCKET=${TEST_BUCKET:-pic-store-test}
+FAKE_GCS_PORT=${FAKE_GCS_PORT:-4443}
+
+docker run -d \
+  -p ${FAKE_GCS_PORT}:4443 \
+  --name fake-gcs-pic-store-test \
+  fsouza/fake-gcs-server -scheme http -public-host localhost:${FAKE_GCS_PORT}
+
+docker run -d \
+  -p 10000:10000 \
+  --name azurite-pic-store-test \
+  mcr.microsoft.com/azure-storage/azurite azurite-blob --blobHost 0.0.0.0
+
+sleep 2
+
+curl -f -X POST \
+  -H "Content-Type: application/json" \
+  --data-binary "{\"name\":\"${TEST_BUCKET}\"}" \
+  "http://localhost:${FAKE_GCS_PORT}/storage/v1/b"
+
+# The well-known Azurite development account.
+AZURITE_CONNECTION_STRING="DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==;BlobEndpoint=http://host.docker.internal:10000/devstoreaccount1;"
+docker run --rm --add-host=host.docker.internal:host-gateway mcr.microsoft.com/azure-cli \
+  az storage container create -n "${TEST_BUCKET}" --connection-string "${AZURITE_CONNECTION_STRING}"
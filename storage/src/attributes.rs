@@ -0,0 +1,176 @@
+use http::{
+    header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE},
+    HeaderMap, HeaderValue,
+};
+use object_store::{Attribute, Attributes};
+
+/// The Cache-Control for objects whose names change whenever their contents do, so they can be
+/// cached forever.
+pub const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Metadata saved along with an object, which the provider returns as headers when the object
+/// is served.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectAttributes {
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+}
+
+impl ObjectAttributes {
+    pub(crate) fn to_object_store(&self) -> Attributes {
+        [
+            (Attribute::ContentType, &self.content_type),
+            (Attribute::CacheControl, &self.cache_control),
+            (Attribute::ContentDisposition, &self.content_disposition),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.clone()?)))
+        .collect()
+    }
+
+    /// The attributes as the headers that providers read them from, for requests that object_store
+    /// can't attach attributes to.
+    pub(crate) fn to_headers(&self) -> Result<HeaderMap, eyre::Report> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (CONTENT_TYPE, &self.content_type),
+            (CACHE_CONTROL, &self.cache_control),
+            (CONTENT_DISPOSITION, &self.content_disposition),
+        ] {
+            if let Some(value) = value {
+                headers.insert(name, HeaderValue::from_str(value)?);
+            }
+        }
+
+        Ok(headers)
+    }
+
+    /// Read the attributes from an object that was fetched from storage.
+    pub fn from_object_store(attributes: &Attributes) -> Self {
+        let get = |key: &Attribute| attributes.get(key).map(|value| value.to_string());
+        ObjectAttributes {
+            content_type: get(&Attribute::ContentType),
+            cache_control: get(&Attribute::CacheControl),
+            content_disposition: get(&Attribute::ContentDisposition),
+        }
+    }
+}
+
+/// The MIME type for a file extension, for the formats that images are stored as.
+pub fn content_type_for_extension(extension: &str) -> Option<&'static str> {
+    let content_type = match extension.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "avif" => "image/avif",
+        "webp" => "image/webp",
+        "jxl" => "image/jxl",
+        "heic" => "image/heic",
+        "svg" => "image/svg+xml",
+        "gif" => "image/gif",
+        "tif" | "tiff" => "image/tiff",
+        "bmp" => "image/bmp",
+        _ => return None,
+    };
+
+    Some(content_type)
+}
+
+/// The MIME type for an object, based on the extension of its location.
+pub fn content_type_for_location(location: &str) -> Option<&'static str> {
+    let (_, extension) = location.rsplit_once('.')?;
+    content_type_for_extension(extension)
+}
+
+/// The Cache-Control to save with an object. A storage location's configured policy always
+/// wins, and otherwise objects with content-hashed names are marked immutable.
+pub fn cache_control(configured: Option<&str>, content_hashed: bool) -> Option<String> {
+    match configured {
+        Some(configured) => Some(configured.to_string()),
+        None if content_hashed => Some(IMMUTABLE_CACHE_CONTROL.to_string()),
+        None => None,
+    }
+}
+
+/// A Content-Disposition that displays the object in the browser, and suggests `filename` if
+/// it is downloaded.
+pub fn inline_content_disposition(filename: &str) -> String {
+    format!("inline; filename=\"{}\"", header_filename(filename))
+}
+
+/// A Content-Disposition that makes the browser download the object as `filename` instead of
+/// displaying it.
+pub fn attachment_content_disposition(filename: &str) -> String {
+    format!("attachment; filename=\"{}\"", header_filename(filename))
+}
+
+/// Keep the filename to plain ASCII so that the header value is always valid.
+fn header_filename(filename: &str) -> String {
+    filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_from_location() {
+        assert_eq!(
+            content_type_for_location("a/b/image-w200.avif"),
+            Some("image/avif")
+        );
+        assert_eq!(content_type_for_location("IMAGE.JPG"), Some("image/jpeg"));
+        assert_eq!(content_type_for_location("no-extension"), None);
+    }
+
+    #[test]
+    fn configured_cache_control_wins() {
+        assert_eq!(cache_control(None, false), None);
+        assert_eq!(
+            cache_control(None, true).as_deref(),
+            Some(IMMUTABLE_CACHE_CONTROL)
+        );
+        assert_eq!(
+            cache_control(Some("no-cache"), true).as_deref(),
+            Some("no-cache")
+        );
+    }
+
+    #[test]
+    fn headers_from_attributes() {
+        let attributes = ObjectAttributes {
+            content_type: Some("image/png".to_string()),
+            cache_control: None,
+            content_disposition: Some(inline_content_disposition("a.png")),
+        };
+        let headers = attributes.to_headers().unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[CONTENT_TYPE], "image/png");
+        assert_eq!(headers[CONTENT_DISPOSITION], "inline; filename=\"a.png\"");
+
+        let invalid = ObjectAttributes {
+            cache_control: Some("no-cache\n".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.to_headers().is_err());
+    }
+
+    #[test]
+    fn content_disposition_is_ascii() {
+        assert_eq!(
+            inline_content_disposition("my \"photo\" é.jpg"),
+            "inline; filename=\"my _photo_ _.jpg\""
+        );
+        assert_eq!(
+            attachment_content_disposition("logo\\é.svg"),
+            "attachment; filename=\"logo__.svg\""
+        );
+    }
+}
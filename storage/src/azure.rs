@@ -0,0 +1,33 @@
+use object_store::azure::{MicrosoftAzure, MicrosoftAzureBuilder};
+
+#[derive(Debug, Clone)]
+pub struct AzureProviderConfig {
+    pub account: Option<String>,
+    pub access_key: Option<String>,
+    /// Connect to the Azurite emulator. The emulator's account and key are used unless others
+    /// are given.
+    pub use_emulator: bool,
+}
+
+pub(crate) fn create_store<'a>(
+    config: &AzureProviderConfig,
+    base_location: &'a str,
+) -> Result<(MicrosoftAzure, &'a str), eyre::Report> {
+    let (container, base_path) = crate::provider::split_bucket(base_location)?;
+
+    // Only the location's own credentials are used, never the server's environment.
+    let mut builder = MicrosoftAzureBuilder::new()
+        .with_container_name(container)
+        .with_use_emulator(config.use_emulator);
+
+    if let Some(account) = config.account.as_ref() {
+        builder = builder.with_account(account.as_str());
+    }
+
+    if let Some(access_key) = config.access_key.as_ref() {
+        builder = builder.with_access_key(access_key.as_str());
+    }
+
+    let store = builder.build()?;
+    Ok((store, base_path))
+}
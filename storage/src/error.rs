@@ -23,6 +23,19 @@ pub enum Error {
     #[error("Missing field {0}")]
     MissingField(&'static str),
 
+    #[error("Failed to read storage credentials: {0}")]
+    Secret(#[from] pic_store_db::secrets::SecretError),
+
     #[error("Operator error {0}")]
     OperatorError(#[from] object_store::Error),
 }
+
+impl Error {
+    /// Returns true if the error is because the object doesn't exist.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            Error::OperatorError(object_store::Error::NotFound { .. })
+        )
+    }
+}
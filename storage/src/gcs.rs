@@ -0,0 +1,30 @@
+use object_store::{
+    gcp::{GoogleCloudStorage, GoogleCloudStorageBuilder},
+    ClientOptions,
+};
+
+#[derive(Debug, Clone)]
+pub struct GcsProviderConfig {
+    /// The JSON contents of a service account key. Emulators such as fake-gcs-server can be used
+    /// by setting `gcs_base_url` and `disable_oauth` in the key.
+    pub service_account_key: Option<String>,
+}
+
+pub(crate) fn create_store<'a>(
+    config: &GcsProviderConfig,
+    base_location: &'a str,
+    client_options: ClientOptions,
+) -> Result<(GoogleCloudStorage, &'a str), eyre::Report> {
+    let (bucket, base_path) = crate::provider::split_bucket(base_location)?;
+
+    // Only the location's own credentials are used, never the server's environment.
+    let mut builder = GoogleCloudStorageBuilder::new()
+        .with_bucket_name(bucket)
+        .with_client_options(client_options);
+    if let Some(key) = config.service_account_key.as_ref() {
+        builder = builder.with_service_account_key(key.as_str());
+    }
+
+    let store = builder.build()?;
+    Ok((store, base_path))
+}
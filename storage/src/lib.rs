@@ -1,8 +1,15 @@
+mod attributes;
+mod azure;
 mod error;
+mod gcs;
+mod memory;
 mod operator;
 mod provider;
 mod s3;
+mod upload;
 
+pub use attributes::*;
 pub use error::*;
 pub use operator::*;
 pub use provider::*;
+pub use upload::*;
@@ -0,0 +1,20 @@
+use std::{collections::HashMap, sync::Arc};
+
+use object_store::{memory::InMemory, ObjectStore};
+use once_cell::sync::Lazy;
+
+/// In-memory stores by bucket name, so that every operator for a bucket sees the same objects.
+static STORES: Lazy<std::sync::Mutex<HashMap<String, Arc<InMemory>>>> = Lazy::new(Default::default);
+
+pub(crate) fn get_store(base_location: &str) -> Result<(Arc<dyn ObjectStore>, &str), eyre::Report> {
+    let (bucket, base_path) = crate::provider::split_bucket(base_location)?;
+
+    let store: Arc<dyn ObjectStore> = STORES
+        .lock()
+        .unwrap()
+        .entry(bucket.to_string())
+        .or_insert_with(|| Arc::new(InMemory::new()))
+        .clone();
+
+    Ok((store, base_path))
+}
@@ -1,15 +1,32 @@
+use std::sync::Arc;
+
 use bytes::Bytes;
-use object_store::{path::Path, GetResult, MultipartId, ObjectStore};
-use tokio::io::AsyncWrite;
+use futures::{stream::BoxStream, StreamExt};
+use object_store::{
+    multipart::MultipartStore, path::Path, GetResult, MultipartId, ObjectMeta, ObjectStore,
+    PutOptions,
+};
 use tracing::instrument;
 
-use crate::error::{Error, Result};
+use crate::{
+    attributes::ObjectAttributes,
+    error::{Error, Result},
+    upload::Upload,
+    Provider,
+};
 
 pub struct Operator {
-    pub operator: Box<dyn ObjectStore>,
+    pub operator: Arc<dyn ObjectStore>,
     pub base_location: String,
     pub supports_multipart: bool,
+    /// The same store, for providers that keep the parts of an unfinished multipart upload
+    /// until it is aborted by its ID.
+    pub multipart_store: Option<Arc<dyn MultipartStore>>,
+    /// False for providers that can't save attributes with objects. Attributes passed to this
+    /// operator are dropped.
+    pub supports_attributes: bool,
     pub path_prefix: Option<Path>,
+    pub(crate) provider: Provider,
 }
 
 impl Operator {
@@ -20,6 +37,14 @@ impl Operator {
         }
     }
 
+    fn object_attributes(&self, attributes: &ObjectAttributes) -> object_store::Attributes {
+        if self.supports_attributes {
+            attributes.to_object_store()
+        } else {
+            object_store::Attributes::new()
+        }
+    }
+
     #[instrument(skip(self), fields(base=%self.base_location, path_prefix=?self.path_prefix))]
     pub async fn get(&self, location: &str) -> Result<GetResult> {
         let p = self.make_full_path(location);
@@ -27,27 +52,273 @@ impl Operator {
     }
 
     #[instrument(skip(self, bytes), fields(base=%self.base_location, path_prefix=?self.path_prefix))]
-    pub async fn put(&self, location: &str, bytes: Bytes) -> Result<()> {
+    pub async fn put(
+        &self,
+        location: &str,
+        bytes: Bytes,
+        attributes: &ObjectAttributes,
+    ) -> Result<()> {
         let p = self.make_full_path(location);
-        self.operator.put(&p, bytes).await.map_err(Error::from)?;
+        let options = PutOptions {
+            attributes: self.object_attributes(attributes),
+            ..Default::default()
+        };
+        self.operator
+            .put_opts(&p, bytes.into(), options)
+            .await
+            .map_err(Error::from)?;
         Ok(())
     }
 
     #[instrument(skip(self), fields(base=%self.base_location, path_prefix=?self.path_prefix))]
-    pub async fn put_multipart(
+    pub async fn delete(&self, location: &str) -> Result<()> {
+        let p = self.make_full_path(location);
+        self.operator.delete(&p).await.map_err(Error::from)
+    }
+
+    /// List the objects under `prefix`, or all objects if it is `None`. The returned locations
+    /// are relative to the operator's base location, like the ones passed to its other methods.
+    #[instrument(skip(self), fields(base=%self.base_location, path_prefix=?self.path_prefix))]
+    pub async fn list(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        let full_prefix = match prefix {
+            Some(prefix) => Some(self.make_full_path(prefix)),
+            None => self.path_prefix.clone(),
+        };
+
+        let stream = self.operator.list(full_prefix.as_ref()).map(move |meta| {
+            let mut meta = meta.map_err(Error::from)?;
+            let relative = self
+                .path_prefix
+                .as_ref()
+                .and_then(|prefix| meta.location.prefix_match(prefix))
+                .map(Path::from_iter);
+            if let Some(relative) = relative {
+                meta.location = relative;
+            }
+
+            Ok(meta)
+        });
+
+        Ok(stream.boxed())
+    }
+
+    /// Start writing an object in pieces. See [Upload] for how the object is sent.
+    #[instrument(skip(self), fields(base=%self.base_location, path_prefix=?self.path_prefix))]
+    pub fn put_multipart(
         &self,
         location: &str,
-    ) -> Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        attributes: &ObjectAttributes,
+    ) -> Result<Upload, eyre::Report> {
         let p = self.make_full_path(location);
-        self.operator.put_multipart(&p).await.map_err(Error::from)
+        let object_attributes = self.object_attributes(attributes);
+        let create_store = if self.multipart_store.is_some() && !object_attributes.is_empty() {
+            self.provider
+                .create_multipart_store_with_attributes(&self.base_location, attributes)?
+        } else {
+            None
+        };
+
+        Ok(Upload::new(
+            self.operator.clone(),
+            self.multipart_store.clone(),
+            create_store,
+            p,
+            object_attributes,
+        ))
     }
 
     #[instrument(skip(self))]
     pub async fn abort_multipart(&self, location: &str, id: &MultipartId) -> Result<()> {
         let p = self.make_full_path(location);
-        self.operator
-            .abort_multipart(&p, id)
+        match &self.multipart_store {
+            Some(store) => store.abort_multipart(&p, id).await.map_err(Error::from),
+            // Uploads on these providers never have an ID to abort.
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use crate::{ObjectAttributes, Provider, ProviderConfig};
+
+    #[tokio::test]
+    async fn list_local() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let operator = Provider::new(ProviderConfig::Local)
+            .create_operator(dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+
+        for location in ["images/a.jpg", "images/b/c.png", "other.txt"] {
+            operator
+                .put(
+                    location,
+                    bytes::Bytes::from_static(b"data"),
+                    &ObjectAttributes::default(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut all = operator
+            .list(None)
+            .await
+            .unwrap()
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        all.sort();
+        assert_eq!(all, vec!["images/a.jpg", "images/b/c.png", "other.txt"]);
+
+        let mut images = operator
+            .list(Some("images"))
             .await
-            .map_err(Error::from)
+            .unwrap()
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        images.sort();
+        assert_eq!(images, vec!["images/a.jpg", "images/b/c.png"]);
+    }
+
+    #[tokio::test]
+    async fn memory_buckets_are_shared() {
+        let provider = Provider::new(ProviderConfig::Memory);
+        let writer = provider
+            .create_operator("memory-test-bucket/base")
+            .await
+            .unwrap();
+        writer
+            .put(
+                "image.jpg",
+                bytes::Bytes::from_static(b"data"),
+                &ObjectAttributes::default(),
+            )
+            .await
+            .unwrap();
+
+        let reader = provider
+            .create_operator("memory-test-bucket/base")
+            .await
+            .unwrap();
+        let data = reader
+            .get("image.jpg")
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(data.as_ref(), b"data");
+
+        let other = provider
+            .create_operator("memory-other-bucket/base")
+            .await
+            .unwrap();
+        let err = other.get("image.jpg").await.err().unwrap();
+        assert!(err.is_not_found());
+    }
+
+    #[tokio::test]
+    async fn memory_saves_attributes() {
+        let operator = Provider::new(ProviderConfig::Memory)
+            .create_operator("memory-attributes-bucket/base")
+            .await
+            .unwrap();
+        let attributes = ObjectAttributes {
+            content_type: Some("image/avif".to_string()),
+            cache_control: Some("public, max-age=60".to_string()),
+            content_disposition: None,
+        };
+        operator
+            .put(
+                "image.avif",
+                bytes::Bytes::from_static(b"data"),
+                &attributes,
+            )
+            .await
+            .unwrap();
+
+        let result = operator.get("image.avif").await.unwrap();
+        assert_eq!(
+            ObjectAttributes::from_object_store(&result.attributes),
+            attributes
+        );
+    }
+
+    #[tokio::test]
+    async fn local_ignores_attributes() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let operator = Provider::new(ProviderConfig::Local)
+            .create_operator(dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        let attributes = ObjectAttributes {
+            content_type: Some("image/png".to_string()),
+            ..Default::default()
+        };
+
+        operator
+            .put("image.png", bytes::Bytes::from_static(b"data"), &attributes)
+            .await
+            .unwrap();
+
+        let mut writer = operator
+            .put_multipart("multipart.png", &attributes)
+            .unwrap();
+        writer.write(b"data").await.unwrap();
+        writer.finish().await.unwrap();
+
+        let data = operator
+            .get("multipart.png")
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(data.as_ref(), b"data");
+    }
+
+    #[tokio::test]
+    async fn memory_multipart_upload() {
+        let operator = Provider::new(ProviderConfig::Memory)
+            .create_operator("memory-multipart-bucket/base")
+            .await
+            .unwrap();
+        let data = vec![7u8; crate::upload::PART_SIZE * 2 + 100];
+        let attributes = ObjectAttributes {
+            content_type: Some("image/png".to_string()),
+            ..Default::default()
+        };
+
+        let mut writer = operator.put_multipart("small.png", &attributes).unwrap();
+        writer.write(b"data").await.unwrap();
+        writer.finish().await.unwrap();
+
+        let mut writer = operator.put_multipart("large.png", &attributes).unwrap();
+        for chunk in data.chunks(1024 * 1024) {
+            writer.write(chunk).await.unwrap();
+        }
+        // Memory uploads end with the process, so there is no ID to save.
+        assert!(writer.upload_id().is_none());
+        writer.finish().await.unwrap();
+
+        let small = operator.get("small.png").await.unwrap();
+        assert_eq!(
+            ObjectAttributes::from_object_store(&small.attributes),
+            attributes
+        );
+        assert_eq!(small.bytes().await.unwrap().as_ref(), b"data");
+
+        let result = operator.get("large.png").await.unwrap();
+        assert_eq!(
+            ObjectAttributes::from_object_store(&result.attributes),
+            attributes
+        );
+        assert_eq!(result.bytes().await.unwrap().as_ref(), data.as_slice());
     }
 }
@@ -1,19 +1,37 @@
 use std::sync::Arc;
 
-use object_store::{local::LocalFileSystem, ObjectStore};
+use db::secrets::{MasterKeys, Secret};
+use object_store::{local::LocalFileSystem, multipart::MultipartStore, ClientOptions, ObjectStore};
 use pic_store_db as db;
 
-use crate::{error::Error, s3::S3ProviderConfig, Operator};
+use crate::{
+    azure::AzureProviderConfig, error::Error, gcs::GcsProviderConfig, s3::S3ProviderConfig,
+    ObjectAttributes, Operator,
+};
 
 #[derive(Debug, Clone)]
 pub enum ProviderConfig {
     S3(S3ProviderConfig),
+    Gcs(GcsProviderConfig),
+    Azure(AzureProviderConfig),
     Local,
+    Memory,
+}
+
+/// Decrypt an optional secret from the database.
+fn decrypt(keys: &MasterKeys, secret: Option<Secret>) -> Result<Option<String>, Error> {
+    secret
+        .map(|secret| keys.decrypt(&secret))
+        .transpose()
+        .map_err(Error::from)
 }
 
 impl ProviderConfig {
+    /// Create the configuration from a provider saved in the database, decrypting its secrets
+    /// with `keys`.
     pub fn from_db(
         provider_type: db::storage_locations::Provider,
+        keys: &MasterKeys,
     ) -> Result<ProviderConfig, Error> {
         match provider_type {
             db::storage_locations::Provider::S3 {
@@ -28,41 +46,86 @@ impl ProviderConfig {
                 Ok(ProviderConfig::S3(S3ProviderConfig {
                     endpoint: uri,
                     region,
-                    access_key_id,
-                    secret_key,
+                    access_key_id: decrypt(keys, access_key_id)?,
+                    secret_key: decrypt(keys, secret_key)?,
                     virtual_host_style,
                 }))
             }
+            db::storage_locations::Provider::Gcs {
+                service_account_key,
+            } => Ok(ProviderConfig::Gcs(GcsProviderConfig {
+                service_account_key: decrypt(keys, service_account_key)?,
+            })),
+            db::storage_locations::Provider::Azure {
+                account,
+                access_key,
+                use_emulator,
+            } => Ok(ProviderConfig::Azure(AzureProviderConfig {
+                account,
+                access_key: decrypt(keys, access_key)?,
+                use_emulator: use_emulator.unwrap_or(false),
+            })),
             db::storage_locations::Provider::Local => Ok(Self::Local),
+            db::storage_locations::Provider::Memory => Ok(Self::Memory),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Provider {
     S3 { config: S3ProviderConfig },
+    Gcs { config: GcsProviderConfig },
+    Azure { config: AzureProviderConfig },
     Local,
+    Memory,
 }
 
 impl Provider {
     pub fn new(config: ProviderConfig) -> Self {
         match config {
             ProviderConfig::S3(config) => Provider::S3 { config },
+            ProviderConfig::Gcs(config) => Provider::Gcs { config },
+            ProviderConfig::Azure(config) => Provider::Azure { config },
             ProviderConfig::Local => Provider::Local,
+            ProviderConfig::Memory => Provider::Memory,
         }
     }
 
-    pub fn from_db(provider_type: db::storage_locations::Provider) -> Result<Self, Error> {
-        let config = ProviderConfig::from_db(provider_type)?;
+    pub fn from_db(
+        provider_type: db::storage_locations::Provider,
+        keys: &MasterKeys,
+    ) -> Result<Self, Error> {
+        let config = ProviderConfig::from_db(provider_type, keys)?;
         Ok(Provider::new(config))
     }
 
     pub async fn create_operator(&self, base_location: &str) -> Result<Operator, eyre::Report> {
-        let (operator, supports_multipart, manual_prefix): (Box<dyn ObjectStore>, bool, &str) =
+        // Only S3 and GCS keep the parts of an unfinished upload until it is aborted. Azure drops
+        // uncommitted blocks on its own, and the other providers' uploads end with the process.
+        let mut multipart_store: Option<Arc<dyn MultipartStore>> = None;
+        let (operator, supports_multipart, manual_prefix): (Arc<dyn ObjectStore>, bool, &str) =
             match self {
                 Self::S3 { config, .. } => {
-                    let (store, base_path) = crate::s3::create_store(config, base_location)?;
-                    (Box::new(store), true, base_path)
+                    let (store, base_path) =
+                        crate::s3::create_store(config, base_location, ClientOptions::new())?;
+                    let store = Arc::new(store);
+                    multipart_store = Some(store.clone());
+                    (store, true, base_path)
+                }
+                Self::Gcs { config } => {
+                    let (store, base_path) =
+                        crate::gcs::create_store(config, base_location, ClientOptions::new())?;
+                    let store = Arc::new(store);
+                    multipart_store = Some(store.clone());
+                    (store, true, base_path)
+                }
+                Self::Azure { config } => {
+                    let (store, base_path) = crate::azure::create_store(config, base_location)?;
+                    (Arc::new(store), true, base_path)
+                }
+                Self::Memory => {
+                    let (store, base_path) = crate::memory::get_store(base_location)?;
+                    (store, true, base_path)
                 }
                 Self::Local => {
                     let store = if !base_location.is_empty() {
@@ -77,7 +140,7 @@ impl Provider {
                         LocalFileSystem::new()
                     };
 
-                    (Box::new(store), false, "")
+                    (Arc::new(store), false, "")
                 }
             };
 
@@ -90,8 +153,56 @@ impl Provider {
         Ok(Operator {
             operator,
             supports_multipart,
+            multipart_store,
+            provider: self.clone(),
+            // The local filesystem has nowhere to keep attributes. Whatever serves the files
+            // decides the headers.
+            supports_attributes: !matches!(self, Self::Local),
             base_location: base_location.to_string(),
             path_prefix,
         })
     }
+
+    /// A store that sends `attributes` as headers with every request, for starting multipart
+    /// uploads. object_store's [MultipartStore] has no other way to set them, and the provider
+    /// saves the finished object with the headers from the request that started it. Returns
+    /// `None` for providers without multipart stores.
+    pub(crate) fn create_multipart_store_with_attributes(
+        &self,
+        base_location: &str,
+        attributes: &ObjectAttributes,
+    ) -> Result<Option<Arc<dyn MultipartStore>>, eyre::Report> {
+        let mut options = ClientOptions::new().with_default_headers(attributes.to_headers()?);
+        if let Some(content_type) = attributes.content_type.as_ref() {
+            // The providers set Content-Type on the request themselves, using this default when
+            // the request has no attributes.
+            options = options.with_default_content_type(content_type);
+        }
+
+        let store: Arc<dyn MultipartStore> = match self {
+            Self::S3 { config } => {
+                Arc::new(crate::s3::create_store(config, base_location, options)?.0)
+            }
+            Self::Gcs { config } => {
+                Arc::new(crate::gcs::create_store(config, base_location, options)?.0)
+            }
+            Self::Azure { .. } | Self::Local | Self::Memory => return Ok(None),
+        };
+
+        Ok(Some(store))
+    }
+}
+
+/// Split a base location into the bucket name and the path within the bucket.
+pub(crate) fn split_bucket(base_location: &str) -> Result<(&str, &str), eyre::Report> {
+    if base_location.is_empty() {
+        return Err(eyre::eyre!("base_location is required"));
+    }
+
+    let split = match base_location.find('/') {
+        Some(slash_pos) => base_location.split_at(slash_pos),
+        None => (base_location, ""),
+    };
+
+    Ok(split)
 }
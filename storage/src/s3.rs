@@ -2,7 +2,7 @@ use http::{
     uri::{Authority, Scheme},
     Uri,
 };
-use object_store::aws::AmazonS3;
+use object_store::{aws::AmazonS3, ClientOptions};
 use tracing::{event, Level};
 
 #[derive(Debug, Clone)]
@@ -17,21 +17,16 @@ pub struct S3ProviderConfig {
 pub(crate) fn create_store<'a>(
     config: &S3ProviderConfig,
     base_location: &'a str,
+    client_options: ClientOptions,
 ) -> Result<(AmazonS3, &'a str), eyre::Report> {
     let virtual_host_style = config.virtual_host_style.unwrap_or(false);
 
-    if base_location.is_empty() {
-        return Err(eyre::eyre!("base_location is required"));
-    }
-
-    let (bucket, base_path) = match base_location.find('/') {
-        Some(slash_pos) => base_location.split_at(slash_pos),
-        None => (base_location, ""),
-    };
+    let (bucket, base_path) = crate::provider::split_bucket(base_location)?;
 
     let mut builder = object_store::aws::AmazonS3Builder::new()
         .with_virtual_hosted_style_request(virtual_host_style)
-        .with_bucket_name(bucket);
+        .with_bucket_name(bucket)
+        .with_client_options(client_options);
 
     match (config.access_key_id.as_ref(), config.secret_key.as_ref()) {
         (Some(access_key_id), Some(secret_key)) => {
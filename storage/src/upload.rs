@@ -0,0 +1,242 @@
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use object_store::{
+    multipart::{MultipartStore, PartId},
+    path::Path,
+    Attributes, MultipartId, MultipartUpload, ObjectStore, PutMultipartOpts, PutOptions,
+};
+
+use crate::error::Result;
+
+/// The size of each part of a multipart upload. Providers require parts other than the last to
+/// be at least 5MiB.
+pub(crate) const PART_SIZE: usize = 10 * 1024 * 1024;
+
+/// An object being written in pieces. Objects smaller than a part are written in a single
+/// request by [Upload::finish]. Larger ones switch to a multipart upload once the first part is
+/// full, and [Upload::upload_id] returns its ID from then on.
+pub struct Upload {
+    store: Arc<dyn ObjectStore>,
+    multipart_store: Option<Arc<dyn MultipartStore>>,
+    /// Starts the upload in place of `multipart_store`, so that the provider saves the object
+    /// with its attributes.
+    create_store: Option<Arc<dyn MultipartStore>>,
+    path: Path,
+    attributes: Attributes,
+    buffer: BytesMut,
+    multipart: Option<Multipart>,
+}
+
+enum Multipart {
+    /// An upload that any process can abort using its ID.
+    WithId {
+        store: Arc<dyn MultipartStore>,
+        id: MultipartId,
+        parts: Vec<PartId>,
+    },
+    /// An upload on a provider without upload IDs, such as the local filesystem. Only this
+    /// process can abort it.
+    Anonymous(Box<dyn MultipartUpload>),
+}
+
+impl Upload {
+    pub(crate) fn new(
+        store: Arc<dyn ObjectStore>,
+        multipart_store: Option<Arc<dyn MultipartStore>>,
+        create_store: Option<Arc<dyn MultipartStore>>,
+        path: Path,
+        attributes: Attributes,
+    ) -> Self {
+        Upload {
+            store,
+            multipart_store,
+            create_store,
+            path,
+            attributes,
+            buffer: BytesMut::new(),
+            multipart: None,
+        }
+    }
+
+    /// The ID of the multipart upload, once one has started. Save it so that the upload can be
+    /// aborted with [crate::Operator::abort_multipart] if this process dies before finishing.
+    pub fn upload_id(&self) -> Option<&MultipartId> {
+        match &self.multipart {
+            Some(Multipart::WithId { id, .. }) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() >= PART_SIZE {
+            let part = self.buffer.split_to(PART_SIZE).freeze();
+            self.put_part(part).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let multipart = match &self.multipart_store {
+            Some(store) => {
+                let id = self
+                    .create_store
+                    .as_ref()
+                    .unwrap_or(store)
+                    .create_multipart(&self.path)
+                    .await?;
+                Multipart::WithId {
+                    store: store.clone(),
+                    id,
+                    parts: Vec::new(),
+                }
+            }
+            None => {
+                let options = PutMultipartOpts {
+                    attributes: self.attributes.clone(),
+                    ..Default::default()
+                };
+                let upload = self.store.put_multipart_opts(&self.path, options).await?;
+                Multipart::Anonymous(upload)
+            }
+        };
+
+        self.multipart = Some(multipart);
+        Ok(())
+    }
+
+    async fn put_part(&mut self, part: Bytes) -> Result<()> {
+        if self.multipart.is_none() {
+            self.start().await?;
+        }
+
+        match self.multipart.as_mut().expect("upload was started") {
+            Multipart::WithId { store, id, parts } => {
+                let part_id = store
+                    .put_part(&self.path, id, parts.len(), part.into())
+                    .await?;
+                parts.push(part_id);
+            }
+            Multipart::Anonymous(upload) => upload.put_part(part.into()).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Write whatever is left and finish the object.
+    pub async fn finish(mut self) -> Result<()> {
+        let rest = std::mem::take(&mut self.buffer).freeze();
+        match self.multipart.take() {
+            None => {
+                let options = PutOptions {
+                    attributes: self.attributes,
+                    ..Default::default()
+                };
+                self.store
+                    .put_opts(&self.path, rest.into(), options)
+                    .await?;
+            }
+            Some(Multipart::WithId {
+                store,
+                id,
+                mut parts,
+            }) => {
+                if !rest.is_empty() {
+                    let part_id = store
+                        .put_part(&self.path, &id, parts.len(), rest.into())
+                        .await?;
+                    parts.push(part_id);
+                }
+                store.complete_multipart(&self.path, &id, parts).await?;
+            }
+            Some(Multipart::Anonymous(mut upload)) => {
+                if !rest.is_empty() {
+                    upload.put_part(rest.into()).await?;
+                }
+                upload.complete().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clean up the parts that were already sent.
+    pub async fn abort(mut self) -> Result<()> {
+        match self.multipart.take() {
+            None => {}
+            Some(Multipart::WithId { store, id, .. }) => {
+                store.abort_multipart(&self.path, &id).await?
+            }
+            Some(Multipart::Anonymous(mut upload)) => upload.abort().await?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+
+    use super::{Upload, PART_SIZE};
+
+    fn upload_with_id(store: &Arc<InMemory>, path: &str) -> Upload {
+        Upload::new(
+            store.clone(),
+            Some(store.clone()),
+            None,
+            Path::from(path),
+            Default::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn large_upload_has_id() {
+        let store = Arc::new(InMemory::new());
+        let data = vec![3u8; PART_SIZE + 100];
+
+        let mut upload = upload_with_id(&store, "small.png");
+        upload.write(b"data").await.unwrap();
+        assert!(upload.upload_id().is_none());
+        upload.finish().await.unwrap();
+
+        let mut upload = upload_with_id(&store, "large.png");
+        upload.write(&data).await.unwrap();
+        assert!(upload.upload_id().is_some());
+        upload.finish().await.unwrap();
+
+        let result = store
+            .get(&Path::from("large.png"))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(result.as_ref(), data.as_slice());
+    }
+
+    #[tokio::test]
+    async fn abort_by_id() {
+        let store = Arc::new(InMemory::new());
+        let path = Path::from("image.png");
+
+        let mut upload = upload_with_id(&store, "image.png");
+        upload.write(&vec![0u8; PART_SIZE]).await.unwrap();
+        let id = upload.upload_id().unwrap().clone();
+
+        // Another process, like the reaper, can abort the upload with its ID.
+        object_store::multipart::MultipartStore::abort_multipart(store.as_ref(), &path, &id)
+            .await
+            .unwrap();
+
+        assert!(upload.finish().await.is_err());
+        assert!(matches!(
+            store.get(&path).await,
+            Err(object_store::Error::NotFound { .. })
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+//! Tests against local storage emulators. These are skipped unless the emulators are running,
+//! which `scripts/start_test_storage_emulators.sh` takes care of.
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use pic_store_db::{secrets::MasterKeys, storage_locations::Provider as DbProvider};
+use pic_store_storage::{ObjectAttributes, Operator, Provider};
+
+/// Write, list, read, and delete an object.
+async fn round_trip(operator: &Operator) {
+    let location = format!("round-trip/{}.txt", uuid_like());
+    operator
+        .put(
+            &location,
+            Bytes::from_static(b"pic-store"),
+            &ObjectAttributes::default(),
+        )
+        .await
+        .expect("put");
+
+    let listed = operator
+        .list(Some("round-trip"))
+        .await
+        .expect("list")
+        .map_ok(|meta| meta.location.to_string())
+        .try_collect::<Vec<_>>()
+        .await
+        .expect("listing objects");
+    assert!(listed.contains(&location), "{location} in {listed:?}");
+
+    let data = operator
+        .get(&location)
+        .await
+        .expect("get")
+        .bytes()
+        .await
+        .expect("reading object");
+    assert_eq!(data.as_ref(), b"pic-store");
+
+    operator.delete(&location).await.expect("delete");
+    let err = operator.get(&location).await.expect_err("object deleted");
+    assert!(err.is_not_found());
+}
+
+/// A unique enough name so that test runs don't collide.
+fn uuid_like() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{nanos:x}")
+}
+
+#[tokio::test]
+async fn gcs_emulator() {
+    let Ok(url) = std::env::var("TEST_GCS_EMULATOR_URL") else {
+        eprintln!("TEST_GCS_EMULATOR_URL not set, skipping");
+        return;
+    };
+    let bucket = std::env::var("TEST_GCS_BUCKET").unwrap_or_else(|_| "pic-store-test".to_string());
+
+    // fake-gcs-server doesn't check credentials, but the key still has to parse.
+    let key = serde_json::json!({
+        "gcs_base_url": url,
+        "disable_oauth": true,
+        "client_email": "",
+        "private_key": "",
+    });
+
+    let keys = MasterKeys::new(&MasterKeys::generate_key(), &[]).unwrap();
+    let provider = Provider::from_db(
+        DbProvider::Gcs {
+            service_account_key: Some(keys.encrypt(&key.to_string()).unwrap()),
+        },
+        &keys,
+    )
+    .unwrap();
+    let operator = provider
+        .create_operator(&format!("{bucket}/base"))
+        .await
+        .unwrap();
+
+    round_trip(&operator).await;
+}
+
+#[tokio::test]
+async fn azure_emulator() {
+    let Ok(container) = std::env::var("TEST_AZURITE_CONTAINER") else {
+        eprintln!("TEST_AZURITE_CONTAINER not set, skipping");
+        return;
+    };
+
+    let keys = MasterKeys::new(&MasterKeys::generate_key(), &[]).unwrap();
+    let provider = Provider::from_db(
+        DbProvider::Azure {
+            account: None,
+            access_key: None,
+            use_emulator: Some(true),
+        },
+        &keys,
+    )
+    .unwrap();
+    let operator = provider
+        .create_operator(&format!("{container}/base"))
+        .await
+        .unwrap();
+
+    round_trip(&operator).await;
+}